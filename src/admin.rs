@@ -0,0 +1,158 @@
+//! The `betting-admin` command line tool, to inspect and repair a bets database
+//! without writing SQL by hand. Every command prints text, or JSON with `--json`.
+use std::{fs::File, io::BufReader};
+use clap::{Parser, Subcommand};
+use itertools::Itertools;
+use rusqlite::{Connection, OpenFlags};
+use serde_json::json;
+use crate::{render::Style, AccountUpdate, BetError, Bets, ExportFormat};
+
+#[derive(Parser, Debug)]
+#[command(name = "betting-admin", about = "Inspect and repair a bets database")]
+pub struct Cli {
+    /// Path of the database
+    #[arg(long, default_value = "bets.db")]
+    pub db: String,
+    /// Print JSON instead of text
+    #[arg(long)]
+    pub json: bool,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// List the servers that have accounts or bets
+    Servers,
+    /// List the accounts of a server
+    Accounts { server: u64 },
+    /// List the bets of a server that are not resolved or aborted
+    Bets { server: u64 },
+    /// Show a bet and its pools
+    Bet { bet: u64 },
+    /// Lock a bet
+    Lock { bet: u64 },
+    /// Resolve a bet with the winning outcome
+    Resolve { bet: u64, outcome: usize },
+    /// Abort a bet, refunding the wagers
+    Abort { bet: u64 },
+    /// Give coins to a user, or take them with a negative amount
+    Adjust {
+        server: u64,
+        user: u64,
+        #[arg(allow_hyphen_values = true)]
+        diff: i64,
+        /// Why the balance is adjusted, kept in the database
+        #[arg(long)]
+        reason: String,
+    },
+    /// Look for inconsistencies in the database
+    Check {
+        /// Refund the wagers on missing outcomes or deleted bets
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Print the config, accounts, open bets and adjustments of a server, `--json` has no effect
+    Export {
+        server: u64,
+        #[arg(long, value_enum, default_value_t = ExportFormat::Json)]
+        format: ExportFormat,
+    },
+    /// Copy the database to a file, safely while it's in use
+    Backup { path: String },
+    /// Replace the database with a file written by `backup`
+    Restore { path: String },
+    /// Restore a server from the file written by `export`
+    Import {
+        file: String,
+        #[arg(long, value_enum, default_value_t = ExportFormat::Json)]
+        format: ExportFormat,
+        /// Import into this server instead of the exported one
+        #[arg(long)]
+        into: Option<u64>,
+    },
+}
+
+fn updates_text(style: &Style, updates: &[AccountUpdate]) -> String {
+    if updates.is_empty() {
+        return "No balance changed".to_string();
+    }
+    updates
+        .iter()
+        .map(|update| style.account_update(update))
+        .join("\n")
+}
+
+/// Runs a command, returns what it prints. Fails if the database doesn't exist rather than creating it.
+pub fn run(cli: &Cli) -> Result<String, BetError> {
+    Connection::open_with_flags(&cli.db, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+    let bets = Bets::new(&cli.db)?;
+    let style = Style::default();
+    let (json, text) = match &cli.command {
+        Command::Servers => {
+            let servers = bets.servers()?;
+            (json!(servers), servers.iter().join("\n"))
+        }
+        Command::Accounts { server } => {
+            let mut accounts = bets.accounts(*server)?;
+            accounts.sort_by_key(|account| account.user);
+            let text = accounts.iter().map(|account| style.account(account)).join("\n");
+            (json!(accounts), text)
+        }
+        Command::Bets { server } => {
+            let server_bets = bets.bets_of_server(*server)?;
+            let text = server_bets.iter().map(|bet| style.bet(bet)).join("\n");
+            (json!(server_bets), text)
+        }
+        Command::Bet { bet } => {
+            let bet = bets.bet(*bet)?;
+            (json!(bet), style.bet(&bet))
+        }
+        Command::Lock { bet } => {
+            bets.lock_bet(*bet)?;
+            let bet = bets.bet(*bet)?;
+            (json!(bet), style.bet(&bet))
+        }
+        Command::Resolve { bet, outcome } => {
+            let updates = bets.resolve(*bet, *outcome)?;
+            (json!(updates), updates_text(&style, &updates))
+        }
+        Command::Abort { bet } => {
+            let updates = bets.abort_bet(*bet)?;
+            (json!(updates), updates_text(&style, &updates))
+        }
+        Command::Adjust { server, user, diff, reason } => {
+            let update = bets.adjust_balance(*server, *user, *diff, reason)?;
+            (json!(update), updates_text(&style, &[update]))
+        }
+        Command::Check { repair } => {
+            let report = if *repair { bets.repair_integrity()? } else { bets.check_integrity()? };
+            let text = if report.is_ok() && report.repaired.is_empty() {
+                "No problems found".to_string()
+            } else {
+                let repaired = report.repaired.iter().map(|violation| format!("repaired: {}", violation));
+                repaired.chain(report.violations.iter().map(|violation| violation.to_string())).join("\n")
+            };
+            (json!(report), text)
+        }
+        Command::Backup { path } => {
+            bets.backup_to(path)?;
+            (json!({ "path": path }), format!("Backed up to {}", path))
+        }
+        Command::Restore { path } => {
+            bets.restore_from(path)?;
+            (json!({ "path": path }), format!("Restored from {}", path))
+        }
+        Command::Export { server, format } => {
+            let mut export = Vec::new();
+            bets.export_server(*server, &mut export, *format)?;
+            return Ok(String::from_utf8(export).unwrap());
+        }
+        Command::Import { file, format, into } => {
+            let file = File::open(file).map_err(|err| BetError::Transfer(err.to_string()))?;
+            let server = bets.import_server(BufReader::new(file), *format, *into)?;
+            (json!({ "server": server }), format!("Imported into server {}", server))
+        }
+    };
+    Ok(if cli.json { json.to_string() } else { text })
+}
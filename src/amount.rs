@@ -1,12 +1,41 @@
 use std::{fmt::Display, str::FromStr};
 use anyhow::Error;
+use crate::BetError;
 
-#[derive(Clone, Copy)]
+/// A stake, a number of coins or a fraction of the balance.
+///
+/// With the `serde` feature, coins are written as a number like `100`
+/// and fractions as a percentage like `"50%"`.
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Amount {
     FLAT(u64),
     FRACTION(f32)
 }
 
+impl Amount {
+    // The number of coins this amount represents for a given balance
+    pub(crate) fn stake(&self, balance: u64) -> Result<u64, BetError> {
+        match *self {
+            Amount::FLAT(value) => {
+                if value > balance {
+                    return Err(BetError::NotEnoughMoney);
+                }
+                Ok(value)
+            },
+            Amount::FRACTION(part) => {
+                if !(0. ..=1.).contains(&part) {
+                    return Err(BetError::InvalidFraction);
+                }
+                let value = f32::ceil(balance as f32 * part) as u64;
+                if value == 0 {
+                    return Err(BetError::NotEnoughMoney);
+                }
+                Ok(value)
+            }
+        }
+    }
+}
+
 impl Display for Amount {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -47,4 +76,40 @@ impl From<f32> for Amount {
     fn from(fract: f32) -> Self {
         Amount::FRACTION(fract)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Amount {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Amount::FLAT(value) => serializer.serialize_u64(*value),
+            Amount::FRACTION(part) => {
+                // rounded to 4 decimals, since f32 can't hold most percentages exactly
+                let percent = format!("{:.4}", part * 100.);
+                serializer.collect_str(&format_args!("{}%", percent.trim_end_matches('0').trim_end_matches('.')))
+            }
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Amount {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Coins(u64),
+            Text(String),
+        }
+        let amount = match Repr::deserialize(deserializer)? {
+            Repr::Coins(value) => Amount::FLAT(value),
+            Repr::Text(text) => text.parse().map_err(serde::de::Error::custom)?,
+        };
+        match amount {
+            Amount::FRACTION(part) if !(0. ..=1.).contains(&part) => {
+                Err(serde::de::Error::custom("the percentage must be between 0% and 100%"))
+            }
+            amount => Ok(amount),
+        }
+    }
+}
@@ -0,0 +1,297 @@
+use std::{path::PathBuf, sync::mpsc::Receiver, time::Duration};
+use crate::{
+    Amount, AccountStatus, AccountUpdate, Bet, BetError, BetEvent, BetInfo, Bets, Challenge, ClawbackPolicy, GuessScoring,
+    Idempotent, IntegrityReport, NumericOutcomes, Parlay, Position, RankedPick, ServerConfig, StakeLimits,
+};
+
+// Generates async versions of Bets methods whose arguments can be moved to another thread as is
+macro_rules! forward {
+    ($( $(#[$doc:meta])* fn $name:ident($($arg:ident: $ty:ty),*) -> $ret:ty; )*) => {
+        $(
+            $(#[$doc])*
+            pub async fn $name(&self, $($arg: $ty),*) -> Result<$ret, BetError> {
+                self.run(move |bets| bets.$name($($arg),*)).await
+            }
+        )*
+    };
+}
+
+/// An async facade over `Bets` for tokio runtimes.
+///
+/// Every call runs the blocking SQLite work on tokio's blocking thread pool,
+/// with the same semantics and errors as the `Bets` method of the same name.
+/// `schedule_snapshots` and `metrics` don't block, they're called on `blocking()`.
+#[derive(Debug, Clone)]
+pub struct AsyncBets {
+    bets: Bets,
+}
+
+impl AsyncBets {
+    pub async fn new(db_path: &str) -> Result<Self, BetError> {
+        let db_path = db_path.to_string();
+        let bets = tokio::task::spawn_blocking(move || Bets::new(&db_path))
+            .await
+            .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))?;
+        Ok(AsyncBets { bets })
+    }
+
+    /// The underlying blocking API
+    pub fn blocking(&self) -> &Bets {
+        &self.bets
+    }
+
+    /// Runs any blocking operation on the blocking thread pool
+    pub async fn run<F, T>(&self, f: F) -> Result<T, BetError>
+    where F: FnOnce(&Bets) -> Result<T, BetError> + Send + 'static, T: Send + 'static {
+        let bets = self.bets.clone();
+        tokio::task::spawn_blocking(move || f(&bets))
+            .await
+            .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))
+    }
+
+    forward! {
+        fn create_account(server: u64, user: u64, amount: u64) -> ();
+        fn open_account(server: u64, user: u64) -> AccountUpdate;
+        fn server_config(server: u64) -> ServerConfig;
+        fn bet_limits(bet: u64) -> StakeLimits;
+        fn stake_range(bet: u64, user: u64) -> Option<(u64, Option<u64>)>;
+        fn reset(server: u64, amount: u64) -> ();
+        fn global_income(income: u64) -> ();
+        fn income(server: u64, income: u64) -> Vec<AccountUpdate>;
+        fn pay_income(server: u64) -> Vec<AccountUpdate>;
+        fn outcomes_of_bet(bet: u64) -> Vec<u64>;
+        fn lock_bet(bet: u64) -> ();
+        fn parlay(parlay_uuid: u64) -> Parlay;
+        fn abort_bet(bet: u64) -> Vec<AccountUpdate>;
+        fn resolve(bet: u64, winning_outcome: usize) -> Vec<AccountUpdate>;
+        fn resolve_numeric(bet: u64, value: f64) -> Vec<AccountUpdate>;
+        fn rollback_resolution(bet: u64, policy: ClawbackPolicy) -> Vec<AccountUpdate>;
+        fn purge_resolutions(max_age: Duration) -> usize;
+        fn position(user: u64, bet: u64) -> Position;
+        fn balance(server: u64, user: u64) -> u64;
+        fn account(server: u64, user: u64) -> AccountStatus;
+        fn accounts(server: u64) -> Vec<AccountStatus>;
+        fn get_info(bet_uuid: u64) -> BetInfo;
+        fn bet(bet_uuid: u64) -> Bet;
+        fn latest_bet(server: u64) -> Bet;
+        fn bets_of_server(server: u64) -> Vec<Bet>;
+        fn servers() -> Vec<u64>;
+        fn challenge(challenge_uuid: u64) -> Challenge;
+        fn accept_challenge(challenge_uuid: u64, user: u64) -> AccountUpdate;
+        fn decline_challenge(challenge_uuid: u64, user: u64) -> AccountUpdate;
+        fn expire_challenges() -> Vec<AccountUpdate>;
+        fn claim_challenge(challenge_uuid: u64, user: u64, winner: u64) -> Option<AccountUpdate>;
+        fn resolve_challenge(challenge_uuid: u64, winner: u64) -> AccountUpdate;
+        fn market_prices(bet: u64) -> Vec<f64>;
+        fn market_shares(bet: u64, user: u64) -> Vec<u64>;
+        fn buy_shares(bet: u64, outcome: usize, user: u64, shares: u64) -> AccountUpdate;
+        fn sell_shares(bet: u64, outcome: usize, user: u64, shares: u64) -> AccountUpdate;
+        fn events(after: u64, limit: usize) -> Vec<(u64, BetEvent)>;
+        fn last_event() -> u64;
+        fn subscribe(after: u64) -> Receiver<(u64, BetEvent)>;
+        fn poll_events() -> ();
+        fn prune_events(up_to: u64) -> usize;
+        fn check_integrity() -> IntegrityReport;
+        fn repair_integrity() -> IntegrityReport;
+        fn backup_to(path: PathBuf) -> ();
+        fn snapshot(dir: PathBuf, keep: usize) -> PathBuf;
+        fn restore_from(path: PathBuf) -> ();
+        fn expire_keys(max_age: Duration) -> usize;
+    }
+
+    /// Runs `f` with the idempotency key `key`, see `Bets::with_key`
+    pub async fn with_key<F, T>(&self, key: String, f: F) -> Result<T, BetError>
+    where F: FnOnce(Idempotent<'_>) -> Result<T, BetError> + Send + 'static, T: Send + 'static {
+        self.run(move |bets| f(bets.with_key(key))).await
+    }
+
+    pub async fn adjust_balance<S>(&self, server: u64, user: u64, diff: i64, reason: S) -> Result<AccountUpdate, BetError>
+    where S: ToString {
+        let reason = reason.to_string();
+        self.run(move |bets| bets.adjust_balance(server, user, diff, reason)).await
+    }
+
+    #[cfg(feature = "export")]
+    pub async fn export_server<W>(&self, server: u64, writer: W, format: crate::ExportFormat) -> Result<(), BetError>
+    where W: std::io::Write + Send + 'static {
+        self.run(move |bets| bets.export_server(server, writer, format)).await
+    }
+
+    #[cfg(feature = "export")]
+    pub async fn import_server<R>(&self, reader: R, format: crate::ExportFormat, into: Option<u64>) -> Result<u64, BetError>
+    where R: std::io::Read + Send + 'static {
+        self.run(move |bets| bets.import_server(reader, format, into)).await
+    }
+
+    pub async fn pending_events(&self, consumer: String, limit: usize) -> Result<Vec<(u64, BetEvent)>, BetError> {
+        self.run(move |bets| bets.pending_events(&consumer, limit)).await
+    }
+
+    pub async fn acknowledge_events(&self, consumer: String, events: Vec<u64>) -> Result<(), BetError> {
+        self.run(move |bets| bets.acknowledge_events(&consumer, &events)).await
+    }
+
+    pub async fn set_server_config(&self, server: u64, config: ServerConfig) -> Result<(), BetError> {
+        self.run(move |bets| bets.set_server_config(server, &config)).await
+    }
+
+    pub async fn set_bet_limits(&self, bet: u64, limits: StakeLimits) -> Result<(), BetError> {
+        self.run(move |bets| bets.set_bet_limits(bet, &limits)).await
+    }
+
+    pub async fn create_bet<S1, S2>(
+        &self,
+        bet_uuid: u64,
+        server: u64,
+        author: u64,
+        desc: S1,
+        outcomes: &[S2],
+    ) -> Result<(), BetError>
+    where S1: ToString, S2: ToString {
+        let (desc, outcomes) = (desc.to_string(), outcomes.iter().map(S2::to_string).collect::<Vec<_>>());
+        self.run(move |bets| bets.create_bet(bet_uuid, server, author, desc, &outcomes)).await
+    }
+
+    pub async fn create_numeric_bet<S>(
+        &self,
+        bet_uuid: u64,
+        server: u64,
+        author: u64,
+        desc: S,
+        outcomes: NumericOutcomes,
+        closest_guess: bool,
+    ) -> Result<(), BetError>
+    where S: ToString {
+        let desc = desc.to_string();
+        self.run(move |bets| bets.create_numeric_bet(bet_uuid, server, author, desc, &outcomes, closest_guess))
+            .await
+    }
+
+    pub async fn create_guess_bet<S>(
+        &self,
+        bet_uuid: u64,
+        server: u64,
+        author: u64,
+        desc: S,
+        scoring: GuessScoring,
+    ) -> Result<(), BetError>
+    where S: ToString {
+        let desc = desc.to_string();
+        self.run(move |bets| bets.create_guess_bet(bet_uuid, server, author, desc, scoring)).await
+    }
+
+    pub async fn create_ranked_bet<S1, S2>(
+        &self,
+        bet_uuid: u64,
+        server: u64,
+        author: u64,
+        desc: S1,
+        outcomes: &[S2],
+    ) -> Result<(), BetError>
+    where S1: ToString, S2: ToString {
+        let (desc, outcomes) = (desc.to_string(), outcomes.iter().map(S2::to_string).collect::<Vec<_>>());
+        self.run(move |bets| bets.create_ranked_bet(bet_uuid, server, author, desc, &outcomes)).await
+    }
+
+    pub async fn create_market<S1, S2>(
+        &self,
+        bet_uuid: u64,
+        server: u64,
+        author: u64,
+        desc: S1,
+        outcomes: &[S2],
+        liquidity: f64,
+    ) -> Result<(), BetError>
+    where S1: ToString, S2: ToString {
+        let (desc, outcomes) = (desc.to_string(), outcomes.iter().map(S2::to_string).collect::<Vec<_>>());
+        self.run(move |bets| bets.create_market(bet_uuid, server, author, desc, &outcomes, liquidity)).await
+    }
+
+    pub async fn bet_on<A>(
+        &self,
+        bet: u64,
+        outcome: usize,
+        user: u64,
+        amount: A,
+    ) -> Result<(AccountUpdate, Bet), BetError>
+    where A: Into<Amount> {
+        let amount: Amount = amount.into();
+        self.run(move |bets| bets.bet_on(bet, outcome, user, amount)).await
+    }
+
+    pub async fn guess_on<A>(
+        &self,
+        bet: u64,
+        user: u64,
+        guess: f64,
+        amount: A,
+    ) -> Result<(AccountUpdate, Bet), BetError>
+    where A: Into<Amount> {
+        let amount: Amount = amount.into();
+        self.run(move |bets| bets.guess_on(bet, user, guess, amount)).await
+    }
+
+    pub async fn find_outcome(&self, bet: u64, query: String) -> Result<usize, BetError> {
+        self.run(move |bets| bets.find_outcome(bet, &query)).await
+    }
+
+    pub async fn bet_on_named<A>(
+        &self,
+        bet: u64,
+        outcome: String,
+        user: u64,
+        amount: A,
+    ) -> Result<(AccountUpdate, Bet), BetError>
+    where A: Into<Amount> {
+        let amount: Amount = amount.into();
+        self.run(move |bets| bets.bet_on_named(bet, &outcome, user, amount)).await
+    }
+
+    pub async fn bet_on_ranked<A>(
+        &self,
+        bet: u64,
+        user: u64,
+        pick: RankedPick,
+        amount: A,
+    ) -> Result<AccountUpdate, BetError>
+    where A: Into<Amount> {
+        let amount: Amount = amount.into();
+        self.run(move |bets| bets.bet_on_ranked(bet, user, pick, amount)).await
+    }
+
+    pub async fn resolve_ranking(&self, bet: u64, ranking: Vec<usize>) -> Result<Vec<AccountUpdate>, BetError> {
+        self.run(move |bets| bets.resolve_ranking(bet, &ranking)).await
+    }
+
+    pub async fn create_parlay<A>(
+        &self,
+        parlay_uuid: u64,
+        server: u64,
+        user: u64,
+        legs: Vec<(u64, usize)>,
+        amount: A,
+    ) -> Result<AccountUpdate, BetError>
+    where A: Into<Amount> {
+        let amount: Amount = amount.into();
+        self.run(move |bets| bets.create_parlay(parlay_uuid, server, user, &legs, amount)).await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn propose_challenge<S, A>(
+        &self,
+        challenge_uuid: u64,
+        server: u64,
+        challenger: u64,
+        opponent: u64,
+        desc: S,
+        amount: A,
+        expires_in: Duration,
+    ) -> Result<AccountUpdate, BetError>
+    where S: ToString, A: Into<Amount> {
+        let (desc, amount): (String, Amount) = (desc.to_string(), amount.into());
+        self.run(move |bets| {
+            bets.propose_challenge(challenge_uuid, server, challenger, opponent, desc, amount, expires_in)
+        })
+        .await
+    }
+}
@@ -0,0 +1,130 @@
+//! Online backups: copying `bets.db` while it's in use can miss the changes still in the WAL file,
+//! these go through SQLite's backup API instead and always produce a consistent snapshot.
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use rusqlite::{backup::Progress, Connection, DatabaseName};
+use crate::{BetError, Bets, SCHEMA_VERSION};
+
+const SNAPSHOT_PREFIX: &str = "snapshot-";
+const SNAPSHOT_SUFFIX: &str = ".db";
+
+fn io_error(err: std::io::Error) -> BetError {
+    BetError::Backup(err.to_string())
+}
+
+/// Snapshots taken in the background by `Bets::schedule_snapshots`, until this is dropped.
+#[derive(Debug)]
+pub struct Snapshots {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+    results: Receiver<Result<PathBuf, BetError>>,
+}
+
+impl Snapshots {
+    /// The path of each snapshot taken, or why it failed, in order.
+    pub fn results(&self) -> &Receiver<Result<PathBuf, BetError>> {
+        &self.results
+    }
+}
+
+impl Drop for Snapshots {
+    fn drop(&mut self) {
+        // the thread stops as soon as the channel is closed
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Bets {
+    /// Copies the database to `path`, replacing it, while other connections keep using it.
+    ///
+    /// The copy is a single file, without WAL, that `restore_from` accepts.
+    pub fn backup_to<P: AsRef<Path>>(&self, path: P) -> Result<(), BetError> {
+        let conn = Connection::open(&self.db_path)?;
+        conn.backup(DatabaseName::Main, &path, None)?;
+        let snapshot = Connection::open(&path)?;
+        snapshot.query_row("PRAGMA journal_mode=DELETE;", [], |row| row.get::<usize, String>(0))?;
+        Ok(())
+    }
+
+    /// Takes a snapshot in `dir`, named after the time, and deletes the oldest ones
+    /// so that only the last `keep` remain. Returns the path of the new snapshot.
+    pub fn snapshot<P: AsRef<Path>>(&self, dir: P, keep: usize) -> Result<PathBuf, BetError> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir).map_err(io_error)?;
+        let mut millis = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        // zero-padded so that the names sort in chronological order
+        let name = |millis| dir.join(format!("{}{:016}{}", SNAPSHOT_PREFIX, millis, SNAPSHOT_SUFFIX));
+        while name(millis).exists() {
+            millis += 1;
+        }
+        let path = name(millis);
+        self.backup_to(&path)?;
+        let mut snapshots = fs::read_dir(dir)
+            .map_err(io_error)?
+            .map(|entry| entry.map(|entry| entry.path()).map_err(io_error))
+            .collect::<Result<Vec<_>, _>>()?;
+        snapshots.retain(|snapshot| {
+            snapshot.file_name().and_then(|name| name.to_str()).is_some_and(|name| {
+                name.starts_with(SNAPSHOT_PREFIX) && name.ends_with(SNAPSHOT_SUFFIX)
+            })
+        });
+        snapshots.sort();
+        for old in &snapshots[..snapshots.len().saturating_sub(keep.max(1))] {
+            fs::remove_file(old).map_err(io_error)?;
+        }
+        Ok(path)
+    }
+
+    /// Takes a snapshot in `dir` every `every`, keeping the last `keep`, see `snapshot`.
+    /// The first one is taken after `every`, the schedule stops when the returned `Snapshots` is dropped.
+    pub fn schedule_snapshots<P: Into<PathBuf>>(&self, dir: P, every: Duration, keep: usize) -> Snapshots {
+        let (stop, stopped) = channel::<()>();
+        let (sender, results) = channel();
+        let bets = self.clone();
+        let dir = dir.into();
+        let thread = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(every) {
+                let _ = sender.send(bets.snapshot(&dir, keep));
+            }
+        });
+        Snapshots { stop: Some(stop), thread: Some(thread), results }
+    }
+
+    /// Replaces the content of the database with a snapshot written by `backup_to`,
+    /// after checking that it has the current schema version and is not corrupted.
+    ///
+    /// Other connections see the restored database on their next query,
+    /// subscribers get the events that were committed after the snapshot again.
+    pub fn restore_from<P: AsRef<Path>>(&self, path: P) -> Result<(), BetError> {
+        let path = path.as_ref();
+        if !path.is_file() {
+            return Err(BetError::Backup(format!("{} is not a file", path.display())));
+        }
+        let snapshot = Connection::open(path)?;
+        let version = snapshot.query_row("PRAGMA user_version", [], |row| row.get::<usize, u32>(0))?;
+        if version != SCHEMA_VERSION {
+            return Err(BetError::Backup(format!(
+                "the snapshot has schema version {}, expected {}",
+                version, SCHEMA_VERSION
+            )));
+        }
+        let check = snapshot.query_row("PRAGMA quick_check", [], |row| row.get::<usize, String>(0))?;
+        if check != "ok" {
+            return Err(BetError::Backup(format!("the snapshot is corrupted: {}", check)));
+        }
+        drop(snapshot);
+        let mut conn = Connection::open(&self.db_path)?;
+        conn.restore(DatabaseName::Main, path, None::<fn(Progress)>)?;
+        self.rewind_subscribers(self.last_event()?);
+        self.publish();
+        Ok(())
+    }
+}
@@ -1,5 +1,16 @@
-use rusqlite::Connection;
-use crate::{BetError, Outcome, BetInfo};
+use rusqlite::{Connection, OptionalExtension, Row, params};
+use crate::{amount::Amount, events, BetError, BetEvent, Outcome, BetInfo, ServerConfig, StakeLimits};
+
+// Reads the 5 columns of stake limits starting at column i
+pub(crate) fn stake_limits_of(row: &Row, i: usize) -> rusqlite::Result<StakeLimits> {
+    Ok(StakeLimits {
+        min_stake: row.get::<usize, Option<u64>>(i)?,
+        max_stake: row.get::<usize, Option<u64>>(i + 1)?,
+        min_fraction: row.get::<usize, Option<f32>>(i + 2)?,
+        max_fraction: row.get::<usize, Option<f32>>(i + 3)?,
+        max_pool_share: row.get::<usize, Option<f32>>(i + 4)?,
+    })
+}
 
 pub(crate) trait BetConnection {
     fn outcomes_of_bet(&self, bet: u64) -> Result<Vec<u64>, BetError>;
@@ -14,9 +25,23 @@ pub(crate) trait BetConnection {
 
     fn assert_bet_not_deleted(&self, bet: u64) -> Result<(), BetError>;
 
+    fn is_guess_bet(&self, bet: u64) -> Result<bool, BetError>;
+
+    fn is_market(&self, bet: u64) -> Result<bool, BetError>;
+
     fn bet_info(&self, bet: u64) -> Result<BetInfo, BetError>;
 
     fn balance(&self, server: u64, user: u64) -> Result<u64, BetError>;
+
+    fn server_config(&self, server: u64) -> Result<ServerConfig, BetError>;
+
+    fn bet_limits(&self, bet: u64) -> Result<StakeLimits, BetError>;
+
+    fn stake_range(&self, server: u64, user: u64, bet: Option<u64>) -> Result<Option<(u64, Option<u64>)>, BetError>;
+
+    fn stake(&self, server: u64, user: u64, bet: Option<u64>, amount: Amount) -> Result<u64, BetError>;
+
+    fn events(&self, after: u64, limit: usize) -> Result<Vec<(u64, BetEvent)>, BetError>;
 }
 
 impl BetConnection for Connection {
@@ -54,8 +79,8 @@ impl BetConnection for Connection {
             wagers.push((row.get::<usize, u64>(0)?, row.get::<usize, u64>(1)?));
         }
         Ok(Outcome {
-            desc: desc,
-            wagers: wagers,
+            desc,
+            wagers,
         })
     }
 
@@ -85,6 +110,26 @@ impl BetConnection for Connection {
         }
     }
 
+    fn is_guess_bet(&self, bet: u64) -> Result<bool, BetError> {
+        Ok(self.prepare(
+                "SELECT * 
+                FROM GuessBet
+                WHERE bet = ?1",
+            )
+            .unwrap()
+            .exists([bet])?)
+    }
+
+    fn is_market(&self, bet: u64) -> Result<bool, BetError> {
+        Ok(self.prepare(
+                "SELECT * 
+                FROM Market
+                WHERE bet = ?1",
+            )
+            .unwrap()
+            .exists([bet])?)
+    }
+
     fn bet_info(&self, bet: u64) -> Result<BetInfo, BetError> {
         let (desc, server, author, is_open) = self.prepare(
             "SELECT desc, server, author, is_open 
@@ -115,4 +160,111 @@ impl BetConnection for Connection {
             .unwrap()
             .query_row([server, user], |row| row.get::<usize, u64>(0))?)
     }
-}
\ No newline at end of file
+
+    fn server_config(&self, server: u64) -> Result<ServerConfig, BetError> {
+        Ok(self.prepare(
+                "SELECT starting_balance, fee, allow_fractions, income, 
+                    min_stake, max_stake, min_fraction, max_fraction, max_pool_share
+                    FROM ServerConfig
+                    WHERE server = ?1",
+            )
+            .unwrap()
+            .query_row([server], |row| Ok(ServerConfig {
+                starting_balance: row.get::<usize, u64>(0)?,
+                fee: row.get::<usize, f64>(1)?,
+                allow_fractions: row.get::<usize, bool>(2)?,
+                income: row.get::<usize, u64>(3)?,
+                limits: stake_limits_of(row, 4)?,
+            }))
+            .optional()?
+            .unwrap_or_default())
+    }
+
+    fn bet_limits(&self, bet: u64) -> Result<StakeLimits, BetError> {
+        Ok(self.prepare(
+                "SELECT min_stake, max_stake, min_fraction, max_fraction, max_pool_share
+                    FROM BetLimits
+                    WHERE bet = ?1",
+            )
+            .unwrap()
+            .query_row([bet], |row| stake_limits_of(row, 0))
+            .optional()?
+            .unwrap_or_default())
+    }
+
+    // The (min, max) stake allowed for a user, on a bet or on the server
+    // None if the limits leave no possible stake
+    fn stake_range(&self, server: u64, user: u64, bet: Option<u64>) -> Result<Option<(u64, Option<u64>)>, BetError> {
+        let balance = self.balance(server, user)?;
+        let mut limits = vec![self.server_config(server)?.limits];
+        if let Some(bet) = bet {
+            limits.push(self.bet_limits(bet)?);
+        }
+        let mut min = 1;
+        let mut max: Option<u64> = None;
+        for limit in limits {
+            min = min
+                .max(limit.min_stake.unwrap_or(0))
+                .max(f32::ceil(balance as f32 * limit.min_fraction.unwrap_or(0.)) as u64);
+            for bound in [
+                limit.max_stake,
+                limit.max_fraction.map(|fraction| f32::floor(balance as f32 * fraction) as u64),
+            ].into_iter().flatten() {
+                max = Some(max.map_or(bound, |max| max.min(bound)));
+            }
+            if let (Some(bet), Some(share)) = (bet, limit.max_pool_share) {
+                // (mine + stake) <= share * (others + mine + stake)
+                let (mine, others) = self.prepare(
+                    "SELECT 
+                        IFNULL(SUM(CASE WHEN user = ?2 THEN amount ELSE 0 END), 0),
+                        IFNULL(SUM(CASE WHEN user = ?2 THEN 0 ELSE amount END), 0)
+                    FROM (
+                        SELECT user, amount FROM Wager WHERE bet = ?1
+                        UNION ALL
+                        SELECT user, amount FROM RankedWager WHERE bet = ?1 AND payout IS NULL
+                        UNION ALL
+                        SELECT user, MAX(cost, 0) FROM MarketShares WHERE bet = ?1 AND payout IS NULL
+                    )",
+                )
+                .unwrap()
+                .query_row([bet, user], |row| Ok((row.get::<usize, u64>(0)?, row.get::<usize, u64>(1)?)))?;
+                if others > 0 && share < 1. {
+                    let bound = (f32::floor(share * others as f32 / (1. - share)) as u64).saturating_sub(mine);
+                    max = Some(max.map_or(bound, |max| max.min(bound)));
+                }
+            }
+        }
+        if max.is_some_and(|max| max < min) {
+            return Ok(None);
+        }
+        Ok(Some((min, max)))
+    }
+
+    // The number of coins a user stakes with an amount, following the server config and the bet limits
+    fn stake(&self, server: u64, user: u64, bet: Option<u64>, amount: Amount) -> Result<u64, BetError> {
+        if let Amount::FRACTION(_) = amount {
+            if !self.server_config(server)?.allow_fractions {
+                return Err(BetError::FractionsNotAllowed);
+            }
+        }
+        let stake = amount.stake(self.balance(server, user)?)?;
+        let (min, max) = self.stake_range(server, user, bet)?.ok_or(BetError::NoStakeAllowed)?;
+        if stake < min || max.is_some_and(|max| stake > max) {
+            return Err(BetError::StakeOutOfRange { min, max });
+        }
+        Ok(stake)
+    }
+
+    fn events(&self, after: u64, limit: usize) -> Result<Vec<(u64, BetEvent)>, BetError> {
+        Ok(self.prepare(
+                "SELECT id, kind, server, bet, user, outcome, amount, balance
+                FROM Event
+                WHERE id > ?1
+                ORDER BY id
+                LIMIT ?2",
+            )
+            .unwrap()
+            .query_map(params![after, limit.min(i64::MAX as usize) as i64], events::event_of)?
+            .collect::<Result<Vec<_>, _>>()?)
+    }
+}
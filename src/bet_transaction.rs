@@ -1,11 +1,36 @@
+use std::collections::HashMap;
 use rusqlite::{Transaction, params};
-use crate::{AccountUpdate, BetError};
+use crate::{utils, AccountUpdate, BetError, BetEvent, Outcome};
 
 pub(crate) trait BetTransaction {
+    fn emit(&self, event: BetEvent) -> Result<(), BetError>;
+
     fn change_balance(&self, server: u64, user: u64, amount: i64) -> Result<AccountUpdate, BetError>;
+
+    fn pending_parlay_legs(&self, bet: u64) -> Result<Vec<(u64, usize, u64)>, BetError>;
+
+    fn settle_parlay_legs(&self, bet: u64, payouts: Option<&[(u64, u64)]>) -> Result<Vec<AccountUpdate>, BetError>;
+
+    fn record_resolution(
+        &self, bet: u64, server: u64, outcome: usize, outcomes: &[Outcome], payouts: &HashMap<u64, u64>,
+    ) -> Result<(), BetError>;
+
+    fn record_key(&self, key: &str, operation: &str, account_updates: &[AccountUpdate]) -> Result<(), BetError>;
 }
 
 impl BetTransaction for Transaction<'_> {
+    // Records an event, it is committed with the change it describes
+    fn emit(&self, event: BetEvent) -> Result<(), BetError> {
+        let (kind, server, bet, user, outcome, amount, balance) = event.columns();
+        self.execute(
+            "INSERT
+            INTO Event (kind, server, bet, user, outcome, amount, balance)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![kind, server, bet, user, outcome, amount, balance],
+        )?;
+        Ok(())
+    }
+
     fn change_balance(&self, server: u64, user: u64, amount: i64) -> Result<AccountUpdate, BetError> {
         let balance = self.query_row(
             "UPDATE Account
@@ -15,8 +40,126 @@ impl BetTransaction for Transaction<'_> {
             params![amount, server, user],
             |row | row.get::<usize, u64>(0)
         )?;
-        Ok(AccountUpdate {
+        let acc_update = AccountUpdate {
             server, user, diff: amount, balance,
-        })
+        };
+        self.emit(BetEvent::BalanceChanged(acc_update.clone()))?;
+        Ok(acc_update)
+    }
+
+    // The pending parlays with a leg on the bet, as (parlay, outcome, value): their value rides on the leg
+    fn pending_parlay_legs(&self, bet: u64) -> Result<Vec<(u64, usize, u64)>, BetError> {
+        Ok(self.prepare(
+            "SELECT Parlay.uuid, ParlayLeg.outcome, Parlay.value
+            FROM ParlayLeg
+            JOIN Parlay ON Parlay.uuid = ParlayLeg.parlay
+            WHERE ParlayLeg.bet = ?1 AND ParlayLeg.odds IS NULL AND Parlay.payout IS NULL",
+        )
+        .unwrap()
+        .query_map([bet], |row| Ok((
+            row.get::<usize, u64>(0)?,
+            row.get::<usize, usize>(1)?,
+            row.get::<usize, u64>(2)?,
+        )))?
+        .collect::<Result<Vec<_>, _>>()?)
     }
-}
\ No newline at end of file
+
+    // Settles the legs of the pending parlays on a bet that was resolved with the (parlay, payout)
+    // of each leg, or aborted if payouts is None. A parlay that lost its value is settled with a payout of 0,
+    // and one with every leg decided is paid its value.
+    fn settle_parlay_legs(&self, bet: u64, payouts: Option<&[(u64, u64)]>) -> Result<Vec<AccountUpdate>, BetError> {
+        match payouts {
+            Some(payouts) => {
+                for (parlay, payout) in payouts {
+                    let value = self.query_row("SELECT value FROM Parlay WHERE uuid = ?1", [parlay], |row| row.get::<usize, u64>(0))?;
+                    self.execute(
+                        "UPDATE ParlayLeg
+                        SET odds = ?1, stake = ?2, payout = ?3
+                        WHERE parlay = ?4 AND bet = ?5",
+                        params![*payout as f64 / value as f64, value, payout, parlay, bet],
+                    )?;
+                    self.execute("UPDATE Parlay SET value = ?1 WHERE uuid = ?2", [payout, parlay])?;
+                }
+            }
+            // legs on aborted bets are void
+            None => {
+                self.execute(
+                    "UPDATE ParlayLeg
+                    SET odds = 1
+                    WHERE bet = ?1 AND odds IS NULL AND parlay IN (SELECT uuid FROM Parlay WHERE payout IS NULL)",
+                    [bet],
+                )?;
+            }
+        };
+        self.execute(
+            "UPDATE Parlay
+            SET payout = 0
+            WHERE payout IS NULL AND value = 0 AND uuid IN (SELECT parlay FROM ParlayLeg WHERE bet = ?1)",
+            [bet],
+        )?;
+        let complete = self.prepare(
+            "SELECT uuid, server, user, value
+            FROM Parlay
+            WHERE payout IS NULL
+            AND uuid IN (SELECT parlay FROM ParlayLeg WHERE bet = ?1)
+            AND NOT EXISTS (SELECT * FROM ParlayLeg WHERE parlay = Parlay.uuid AND odds IS NULL)",
+        )
+        .unwrap()
+        .query_map([bet], |row| Ok((
+            row.get::<usize, u64>(0)?,
+            row.get::<usize, u64>(1)?,
+            row.get::<usize, u64>(2)?,
+            row.get::<usize, u64>(3)?,
+        )))?
+        .collect::<Result<Vec<_>, _>>()?;
+        let mut account_updates = Vec::new();
+        for (parlay, server, user, value) in complete {
+            account_updates.push(self.change_balance(server, user, value as i64)?);
+            self.execute("UPDATE Parlay SET payout = ?1 WHERE uuid = ?2", [value, parlay])?;
+        }
+        Ok(account_updates)
+    }
+
+    // Keeps the stakes and payouts of a resolved bet so that it can be rolled back
+    fn record_resolution(
+        &self, bet: u64, server: u64, outcome: usize, outcomes: &[Outcome], payouts: &HashMap<u64, u64>,
+    ) -> Result<(), BetError> {
+        self.execute(
+            "INSERT 
+            INTO Resolution (bet, server, outcome, time) 
+            VALUES (?1, ?2, ?3, ?4)",
+            params![bet, server, outcome, utils::now()],
+        )?;
+        for (i, outcome_status) in outcomes.iter().enumerate() {
+            for (user, stake) in &outcome_status.wagers {
+                self.execute(
+                    "INSERT 
+                    INTO Payout (bet, outcome, server, user, stake, payout) 
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![bet, i, server, user, stake, payouts.get(user).unwrap_or(&0)],
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    // Keeps the result of an operation made with an idempotency key,
+    // a concurrent operation with the same key fails with `BetError::AlreadyExists`
+    fn record_key(&self, key: &str, operation: &str, account_updates: &[AccountUpdate]) -> Result<(), BetError> {
+        self.execute(
+            "INSERT 
+            INTO IdempotencyKey (key, operation, created) 
+            VALUES (?1, ?2, ?3)",
+            params![key, operation, utils::now()],
+        )?;
+        for (i, update) in account_updates.iter().enumerate() {
+            self.execute(
+                "INSERT 
+                INTO IdempotentUpdate (key, number, server, user, diff, balance) 
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![key, i, update.server, update.user, update.diff, update.balance],
+            )?;
+        }
+        Ok(())
+    }
+}
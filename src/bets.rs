@@ -1,27 +1,32 @@
-use crate::{utils, amount::Amount, BetError, AccountUpdate, Bet, AccountStatus, bet_connection::BetConnection, bet_transaction::BetTransaction, BetInfo, Position};
-use rusqlite::{Connection, Result, Transaction, params};
+use crate::{utils, numeric, metrics::Counter, events::Subscriber, BetEvent, NumericOutcomes, GuessScoring, amount::Amount, BetError, AccountUpdate, Bet, AccountStatus, bet_connection::BetConnection, bet_transaction::BetTransaction, BetInfo, Position, ClawbackPolicy, Parlay, ParlayLeg, ServerConfig, StakeLimits};
+use rusqlite::{Connection, OptionalExtension, Result, Transaction, params};
 use std::collections::HashMap;
-use itertools::izip;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use itertools::{izip, Itertools};
+
+/// The version of the database schema, stored in the database by `Bets::new`
+/// and checked before restoring a snapshot.
+pub const SCHEMA_VERSION: u32 = 1;
 
 #[derive(Debug, Clone)]
 pub struct Bets {
-    db_path: String,
+    pub(crate) db_path: String,
+    pub(crate) subscribers: Arc<Mutex<Vec<Subscriber>>>,
+    #[cfg(feature = "metrics")]
+    pub(crate) metrics: Arc<Mutex<crate::metrics::Metrics>>,
 }
 
 impl Bets {
     pub fn new(db_path: &str) -> Result<Self, BetError> {
         let conn = Connection::open(db_path)?;
         
-        // Enable WAL mode
-        conn.execute("PRAGMA journal_mode=WAL;", [])?;
-
-        // Optionally verify WAL mode is set (this step is optional)
-        let wal_check: String = conn.query_row(
-            "PRAGMA journal_mode;",
+        // Enable WAL mode, the pragma returns the mode that is now in effect
+        conn.query_row(
+            "PRAGMA journal_mode=WAL;",
             [],
-            |row| row.get(0),
+            |row| row.get::<usize, String>(0),
         )?;
-        println!("Journal mode set to: {}", wal_check); // Should print "WAL"
         
         conn.execute(
             "CREATE TABLE IF NOT EXISTS Account (
@@ -70,80 +75,529 @@ impl Bets {
             )",
             [],
         )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS Resolution (
+                bet INTEGER PRIMARY KEY REFERENCES Bet(uuid) ON DELETE CASCADE,
+                server INTEGER,
+                outcome INTEGER NOT NULL,
+                time INTEGER NOT NULL
+            )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS Payout (
+                bet INTEGER,
+                outcome INTEGER,
+                server INTEGER,
+                user INTEGER,
+                stake INTEGER NOT NULL,
+                payout INTEGER NOT NULL,
+                FOREIGN KEY(bet) REFERENCES Resolution(bet) ON DELETE CASCADE,
+                PRIMARY KEY(user, bet)
+            )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS Parlay (
+                uuid INTEGER PRIMARY KEY,
+                server INTEGER,
+                user INTEGER,
+                stake INTEGER NOT NULL,
+                value INTEGER NOT NULL,
+                payout INTEGER,
+                FOREIGN KEY(server, user) REFERENCES Account(server, user) ON DELETE CASCADE
+            )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS ParlayLeg (
+                parlay INTEGER REFERENCES Parlay(uuid) ON DELETE CASCADE,
+                bet INTEGER,
+                outcome INTEGER,
+                odds REAL,
+                stake INTEGER,
+                payout INTEGER,
+                FOREIGN KEY(bet, outcome) REFERENCES Outcome(bet, number),
+                PRIMARY KEY(parlay, bet)
+            )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS NumericBet (
+                bet INTEGER PRIMARY KEY REFERENCES Bet(uuid) ON DELETE CASCADE,
+                closest_guess INTEGER NOT NULL
+            )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS NumericOutcome (
+                bet INTEGER,
+                number INTEGER,
+                low REAL,
+                high REAL,
+                FOREIGN KEY(bet, number) REFERENCES Outcome(bet, number) ON DELETE CASCADE,
+                PRIMARY KEY(bet, number)
+            )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS GuessBet (
+                bet INTEGER PRIMARY KEY REFERENCES Bet(uuid) ON DELETE CASCADE,
+                scoring INTEGER NOT NULL
+            )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS Guess (
+                bet INTEGER,
+                user INTEGER,
+                guess REAL NOT NULL,
+                FOREIGN KEY(bet) REFERENCES GuessBet(bet) ON DELETE CASCADE,
+                PRIMARY KEY(user, bet)
+            )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS RankedBet (
+                bet INTEGER PRIMARY KEY REFERENCES Bet(uuid) ON DELETE CASCADE
+            )",
+            [],
+        )?;
+        // pool is 0 for top 3 picks and n for the exact order of the first n outcomes,
+        // the payout is kept once resolved so that it can be rolled back
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS RankedWager (
+                bet INTEGER REFERENCES RankedBet(bet) ON DELETE CASCADE,
+                server INTEGER,
+                user INTEGER,
+                pool INTEGER,
+                pick TEXT NOT NULL,
+                amount INTEGER NOT NULL,
+                payout INTEGER,
+                FOREIGN KEY(server, user) REFERENCES Account(server, user) ON DELETE CASCADE,
+                PRIMARY KEY(user, bet, pool)
+            )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS Market (
+                bet INTEGER PRIMARY KEY REFERENCES Bet(uuid) ON DELETE CASCADE,
+                liquidity REAL NOT NULL
+            )",
+            [],
+        )?;
+        // cost is what the user spent net of what they sold,
+        // the payout is kept once resolved so that it can be rolled back
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS MarketShares (
+                bet INTEGER REFERENCES Market(bet) ON DELETE CASCADE,
+                outcome INTEGER,
+                server INTEGER,
+                user INTEGER,
+                shares INTEGER NOT NULL,
+                cost INTEGER NOT NULL,
+                payout INTEGER,
+                FOREIGN KEY(server, user) REFERENCES Account(server, user) ON DELETE CASCADE,
+                PRIMARY KEY(user, bet, outcome)
+            )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS Challenge (
+                uuid INTEGER PRIMARY KEY,
+                server INTEGER,
+                challenger INTEGER,
+                opponent INTEGER,
+                desc TEXT,
+                stake INTEGER NOT NULL,
+                expires INTEGER NOT NULL,
+                accepted INTEGER NOT NULL,
+                challenger_claim INTEGER,
+                opponent_claim INTEGER
+            )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS ServerConfig (
+                server INTEGER PRIMARY KEY,
+                starting_balance INTEGER NOT NULL,
+                fee REAL NOT NULL,
+                allow_fractions INTEGER NOT NULL,
+                income INTEGER NOT NULL,
+                min_stake INTEGER,
+                max_stake INTEGER,
+                min_fraction REAL,
+                max_fraction REAL,
+                max_pool_share REAL
+            )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS BetLimits (
+                bet INTEGER PRIMARY KEY REFERENCES Bet(uuid) ON DELETE CASCADE,
+                min_stake INTEGER,
+                max_stake INTEGER,
+                min_fraction REAL,
+                max_fraction REAL,
+                max_pool_share REAL
+            )",
+            [],
+        )?;
+        // the outbox of events, written in the same transaction as the changes they describe
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS Event (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                kind TEXT NOT NULL,
+                server INTEGER NOT NULL,
+                bet INTEGER,
+                user INTEGER,
+                outcome INTEGER,
+                amount INTEGER,
+                balance INTEGER
+            )",
+            [],
+        )?;
+        // every event up to acked_up_to was acknowledged by the consumer,
+        // and the events after it that were acknowledged out of order are in OutboxAck
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS OutboxConsumer (
+                consumer TEXT PRIMARY KEY,
+                acked_up_to INTEGER NOT NULL
+            )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS OutboxAck (
+                consumer TEXT,
+                event INTEGER,
+                PRIMARY KEY(consumer, event)
+            )",
+            [],
+        )?;
+        // the result of the operations made with an idempotency key, to replay them on retries
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS IdempotencyKey (
+                key TEXT PRIMARY KEY,
+                operation TEXT NOT NULL,
+                created INTEGER NOT NULL
+            )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS IdempotentUpdate (
+                key TEXT,
+                number INTEGER,
+                server INTEGER NOT NULL,
+                user INTEGER NOT NULL,
+                diff INTEGER NOT NULL,
+                balance INTEGER NOT NULL,
+                PRIMARY KEY(key, number)
+            )",
+            [],
+        )?;
+        // manual changes of balances, with the reason given
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS Adjustment (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                server INTEGER NOT NULL,
+                user INTEGER NOT NULL,
+                diff INTEGER NOT NULL,
+                reason TEXT NOT NULL,
+                time INTEGER NOT NULL
+            )",
+            [],
+        )?;
+        // resolved bets are kept around so that their resolution can be rolled back, see `purge_resolutions`
         conn.execute(
             "DELETE FROM Bet
             WHERE EXISTS (
                 SELECT bet 
                 FROM ToDelete 
                 WHERE ToDelete.bet = Bet.uuid
+            ) AND NOT EXISTS (
+                SELECT bet 
+                FROM Resolution 
+                WHERE Resolution.bet = Bet.uuid
             )",
             [],
         )?;
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
         Ok(Bets {
             db_path: db_path.to_string(),
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+            #[cfg(feature = "metrics")]
+            metrics: Arc::default(),
         })
     }
 
     pub fn create_account(&self, server: u64, user: u64, amount: u64) -> Result<(), BetError> {
-        let conn = Connection::open(&self.db_path)?;
-        conn.execute(
+        self.measure("create_account", || self.insert_account(server, user, amount))?;
+        Ok(())
+    }
+
+    /// Opens an account with the starting balance of the server config.
+    pub fn open_account(&self, server: u64, user: u64) -> Result<AccountUpdate, BetError> {
+        self.measure("open_account", || {
+            let balance = self.server_config(server)?.starting_balance;
+            self.insert_account(server, user, balance)
+        })
+    }
+
+    fn insert_account(&self, server: u64, user: u64, balance: u64) -> Result<AccountUpdate, BetError> {
+        let mut conn = Connection::open(&self.db_path)?;
+        let tx = conn.transaction()?;
+        tx.execute(
             "INSERT 
             INTO Account (server, user, balance) 
             VALUES (?1, ?2, ?3)",
-            [server, user, amount],
+            [server, user, balance],
+        )?;
+        let acc_update = AccountUpdate { server, user, diff: balance as i64, balance };
+        tx.emit(BetEvent::BalanceChanged(acc_update.clone()))?;
+        tx.commit()?;
+        self.publish();
+        Ok(acc_update)
+    }
+
+    /// The config of a server, or the default one if it was never set.
+    pub fn server_config(&self, server: u64) -> Result<ServerConfig, BetError> {
+        let conn = Connection::open(&self.db_path)?;
+        conn.server_config(server)
+    }
+
+    pub fn set_server_config(&self, server: u64, config: &ServerConfig) -> Result<(), BetError> {
+        config.validate()?;
+        let conn = Connection::open(&self.db_path)?;
+        Bets::write_server_config(&conn, server, config)
+    }
+
+    pub(crate) fn write_server_config(conn: &Connection, server: u64, config: &ServerConfig) -> Result<(), BetError> {
+        let limits = &config.limits;
+        conn.execute(
+            "INSERT or replace
+            INTO ServerConfig (
+                server, starting_balance, fee, allow_fractions, income,
+                min_stake, max_stake, min_fraction, max_fraction, max_pool_share
+            )
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                server, config.starting_balance, config.fee, config.allow_fractions, config.income,
+                limits.min_stake, limits.max_stake, limits.min_fraction, limits.max_fraction, limits.max_pool_share
+            ],
         )?;
         Ok(())
     }
 
+    /// The stake limits specific to a bet, on top of the server's.
+    pub fn bet_limits(&self, bet: u64) -> Result<StakeLimits, BetError> {
+        let conn = Connection::open(&self.db_path)?;
+        conn.bet_limits(bet)
+    }
+
+    pub fn set_bet_limits(&self, bet: u64, limits: &StakeLimits) -> Result<(), BetError> {
+        limits.validate()?;
+        let conn = Connection::open(&self.db_path)?;
+        conn.bet_info(bet)?;
+        Bets::write_bet_limits(&conn, bet, limits)
+    }
+
+    pub(crate) fn write_bet_limits(conn: &Connection, bet: u64, limits: &StakeLimits) -> Result<(), BetError> {
+        conn.execute(
+            "INSERT or replace
+            INTO BetLimits (bet, min_stake, max_stake, min_fraction, max_fraction, max_pool_share)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                bet, limits.min_stake, limits.max_stake, limits.min_fraction, limits.max_fraction, limits.max_pool_share
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The (min, max) stake a user can currently wager on a bet, max being None if there's no limit
+    /// (other than the user's balance). None if the limits leave no possible stake,
+    /// like a user who already owns the largest share of the pool allowed.
+    pub fn stake_range(&self, bet: u64, user: u64) -> Result<Option<(u64, Option<u64>)>, BetError> {
+        let conn = Connection::open(&self.db_path)?;
+        let bet_info = conn.bet_info(bet)?;
+        conn.stake_range(bet_info.server, user, Some(bet))
+    }
+
     pub fn reset(&self, server: u64, amount: u64) -> Result<(), BetError> {
         let mut conn = Connection::open(&self.db_path)?;
         let tx = conn.transaction()?;
+        let balances = tx
+            .prepare(
+                "SELECT user, balance
+                FROM Account
+                WHERE server = ?1",
+            )
+            .unwrap()
+            .query_map([server], |row| Ok((row.get::<usize, u64>(0)?, row.get::<usize, i64>(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        for (user, balance) in balances {
+            tx.change_balance(server, user, amount as i64 - balance)?;
+        }
+        // the legs reference the outcomes, which don't cascade from Bet, so everything is deleted explicitly
+        tx.execute(
+            "DELETE
+            FROM ParlayLeg
+            WHERE parlay IN (SELECT uuid FROM Parlay WHERE server = ?1)",
+            [server],
+        )?;
+        tx.execute(
+            "DELETE
+            FROM Parlay
+            WHERE server = ?1",
+            [server],
+        )?;
+        let per_bet = [
+            "Payout", "Resolution", "Wager", "NumericOutcome", "NumericBet", "Guess", "GuessBet",
+            "RankedWager", "RankedBet", "MarketShares", "Market", "BetLimits", "ToDelete", "Outcome",
+        ];
+        for table in per_bet {
+            tx.execute(
+                &format!(
+                    "DELETE
+                    FROM {}
+                    WHERE bet IN (SELECT uuid FROM Bet WHERE server = ?1)",
+                    table
+                ),
+                [server],
+            )?;
+        }
         tx.execute(
             "DELETE
             FROM Bet
             WHERE server = ?1",
             [server],
         )?;
+        // rows of the server left by bets that were already deleted
+        for table in ["Payout", "Resolution", "Wager", "RankedWager", "MarketShares"] {
+            tx.execute(
+                &format!(
+                    "DELETE
+                    FROM {}
+                    WHERE server = ?1",
+                    table
+                ),
+                [server],
+            )?;
+        }
         tx.execute(
-            "UPDATE Account
-            SET balance = ?1
-            WHERE server = ?2",
-            [amount, server],
+            "DELETE
+            FROM Challenge
+            WHERE server = ?1",
+            [server],
         )?;
-        Ok(tx.commit()?)
+        tx.commit()?;
+        self.publish();
+        Ok(())
     }
 
     pub fn global_income(&self, income: u64) -> Result<(), BetError> {
-        let conn = Connection::open(&self.db_path)?;
-        conn.execute(
-            "UPDATE Account
-            SET balance = balance + ?1", 
-            [income]
-        )?;
+        self.pay_accounts("", [income], None)?;
         Ok(())
     }
 
     pub fn income(&self, server: u64, income: u64) -> Result<Vec<AccountUpdate>, BetError> {
-        let conn = Connection::open(&self.db_path)?;
-        let mut stmt = conn.prepare(
-            "UPDATE Account
-            SET balance = balance + ?1
-            WHERE server = ?2
-            RETURNING server, user, balance"
-        ).unwrap();
-        let mut account_updates = Vec::new();
-        let mut rows = stmt.query([server, income])?;
-        while let Some(row) = rows.next()? {
-            account_updates.push(AccountUpdate {
-                server: row.get::<usize, u64>(0)?,
-                user: row.get::<usize, u64>(1)?,
-                balance: row.get::<usize, u64>(2)?,
-                diff: income as i64,
-            });
+        self.measure("income", || self.pay_accounts("WHERE server = ?2", [income, server], None))
+    }
+
+    // Adds the income (?1) to the balance of the accounts matching the filter
+    pub(crate) fn pay_accounts<P: rusqlite::Params>(
+        &self,
+        filter: &str,
+        params: P,
+        key: Option<&str>,
+    ) -> Result<Vec<AccountUpdate>, BetError> {
+        let mut conn = Connection::open(&self.db_path)?;
+        let tx = conn.transaction()?;
+        let account_updates = tx.prepare(
+            &format!(
+                "UPDATE Account
+                SET balance = balance + ?1
+                {}
+                RETURNING server, user, balance, ?1",
+                filter
+            )
+        )
+        .unwrap()
+        .query_map(params, |row| Ok(AccountUpdate {
+            server: row.get::<usize, u64>(0)?,
+            user: row.get::<usize, u64>(1)?,
+            balance: row.get::<usize, u64>(2)?,
+            diff: row.get::<usize, i64>(3)?,
+        }))?
+        .collect::<Result<Vec<_>, _>>()?;
+        for acc_update in &account_updates {
+            tx.emit(BetEvent::BalanceChanged(acc_update.clone()))?;
+        }
+        if let Some(key) = key {
+            tx.record_key(key, "income", &account_updates)?;
         }
+        tx.commit()?;
+        self.publish();
         Ok(account_updates)
     }
 
+    /// Adds `diff` coins to a balance (or takes them if negative) outside of any bet,
+    /// the reason is kept in the Adjustment table.
+    pub fn adjust_balance<S>(&self, server: u64, user: u64, diff: i64, reason: S) -> Result<AccountUpdate, BetError>
+    where S: ToString {
+        self.adjust_balance_keyed(server, user, diff, reason, None)
+    }
+
+    pub(crate) fn adjust_balance_keyed<S>(
+        &self,
+        server: u64,
+        user: u64,
+        diff: i64,
+        reason: S,
+        key: Option<&str>,
+    ) -> Result<AccountUpdate, BetError>
+    where S: ToString {
+        let mut conn = Connection::open(&self.db_path)?;
+        if (conn.balance(server, user)? as i64) + diff < 0 {
+            return Err(BetError::NotEnoughMoney);
+        }
+        let tx = conn.transaction()?;
+        let acc_update = tx.change_balance(server, user, diff)?;
+        tx.execute(
+            "INSERT 
+            INTO Adjustment (server, user, diff, reason, time) 
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![server, user, diff, reason.to_string(), utils::now()],
+        )?;
+        if let Some(key) = key {
+            tx.record_key(key, "adjust_balance", std::slice::from_ref(&acc_update))?;
+        }
+        tx.commit()?;
+        self.publish();
+        Ok(acc_update)
+    }
+
+    /// The servers that have accounts or bets
+    pub fn servers(&self) -> Result<Vec<u64>, BetError> {
+        let conn = Connection::open(&self.db_path)?;
+        let servers = conn
+            .prepare(
+                "SELECT server FROM Account
+                UNION
+                SELECT server FROM Bet
+                ORDER BY server",
+            )
+            .unwrap()
+            .query_map([], |row| row.get::<usize, u64>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(servers)
+    }
+
+    /// Gives every account of the server the income of the server config.
+    pub fn pay_income(&self, server: u64) -> Result<Vec<AccountUpdate>, BetError> {
+        let income = self.server_config(server)?.income;
+        self.income(server, income)
+    }
+
     pub fn create_bet<S1, S2>(
         &self,
         bet_uuid: u64,
@@ -153,16 +607,33 @@ impl Bets {
         outcomes: &[S2],
     ) -> Result<(), BetError>
     where S1: ToString, S2: ToString {
-        let desc = desc.to_string();
-        let mut conn = Connection::open(&self.db_path)?;
-        let tx = conn.transaction()?;
+        self.measure("create_bet", || {
+            let mut conn = Connection::open(&self.db_path)?;
+            let tx = conn.transaction()?;
+            Bets::insert_bet(&tx, bet_uuid, server, author, desc, outcomes)?;
+            tx.commit()?;
+            self.count(Counter::BetsCreated, 1);
+            self.publish();
+            Ok(())
+        })
+    }
+
+    pub(crate) fn insert_bet<S1, S2>(
+        tx: &Transaction,
+        bet_uuid: u64,
+        server: u64,
+        author: u64,
+        desc: S1,
+        outcomes: &[S2],
+    ) -> Result<(), BetError>
+    where S1: ToString, S2: ToString {
         tx.execute(
             "INSERT 
             INTO Bet (uuid, server, author, is_open, desc) 
             VALUES (?1, ?2, ?3, ?4, ?5)",
-            params![bet_uuid, server, author, 1, desc],
+            params![bet_uuid, server, author, 1, desc.to_string()],
         )?;
-        for (i, opt) in outcomes.into_iter().enumerate() {
+        for (i, opt) in outcomes.iter().enumerate() {
             tx.execute(
                 "INSERT 
                 INTO Outcome (bet, number, desc) 
@@ -170,7 +641,73 @@ impl Bets {
                 params![bet_uuid, i, opt.to_string()],
             )?;
         }
-        Ok(tx.commit()?)
+        tx.emit(BetEvent::BetCreated { server, bet: bet_uuid, author })?;
+        Ok(())
+    }
+
+    /// Creates a bet on a number, with one outcome per range of values.
+    /// 
+    /// If `closest_guess` is set and nobody bet on the range of the actual value,
+    /// the closest range that has wagers wins instead.
+    pub fn create_numeric_bet<S>(
+        &self,
+        bet_uuid: u64,
+        server: u64,
+        author: u64,
+        desc: S,
+        outcomes: &NumericOutcomes,
+        closest_guess: bool,
+    ) -> Result<(), BetError>
+    where S: ToString {
+        let ranges = outcomes.ranges()?;
+        let labels: Vec<String> = ranges.iter().map(|range| outcomes.label(range)).collect();
+        let mut conn = Connection::open(&self.db_path)?;
+        let tx = conn.transaction()?;
+        Bets::insert_bet(&tx, bet_uuid, server, author, desc, &labels)?;
+        tx.execute(
+            "INSERT 
+            INTO NumericBet (bet, closest_guess) 
+            VALUES (?1, ?2)",
+            params![bet_uuid, closest_guess],
+        )?;
+        for (i, (low, high)) in ranges.iter().enumerate() {
+            tx.execute(
+                "INSERT 
+                INTO NumericOutcome (bet, number, low, high) 
+                VALUES (?1, ?2, ?3, ?4)",
+                params![bet_uuid, i, low, high],
+            )?;
+        }
+        tx.commit()?;
+        self.count(Counter::BetsCreated, 1);
+        self.publish();
+        Ok(())
+    }
+
+    /// Creates a bet where everyone stakes coins on their own guess of a number,
+    /// the pot is split according to `scoring` once the value is known.
+    pub fn create_guess_bet<S>(
+        &self,
+        bet_uuid: u64,
+        server: u64,
+        author: u64,
+        desc: S,
+        scoring: GuessScoring,
+    ) -> Result<(), BetError>
+    where S: ToString {
+        let mut conn = Connection::open(&self.db_path)?;
+        let tx = conn.transaction()?;
+        Bets::insert_bet(&tx, bet_uuid, server, author, desc, &["Guess"])?;
+        tx.execute(
+            "INSERT 
+            INTO GuessBet (bet, scoring) 
+            VALUES (?1, ?2)",
+            params![bet_uuid, u32::from(scoring)],
+        )?;
+        tx.commit()?;
+        self.count(Counter::BetsCreated, 1);
+        self.publish();
+        Ok(())
     }
 
     pub fn outcomes_of_bet(&self, bet: u64) -> Result<Vec<u64>, BetError> {
@@ -185,35 +722,73 @@ impl Bets {
         user: u64,
         amount: A,
     ) -> Result<(AccountUpdate, Bet), BetError>
-    where A: Into<Amount> {
-        let amount: Amount = amount.into();
+    where A: Into<Amount> {
+        self.measure("bet_on", || self.bet_on_keyed(bet, outcome, user, amount.into(), None))
+    }
+
+    pub(crate) fn bet_on_keyed(
+        &self,
+        bet: u64,
+        outcome: usize,
+        user: u64,
+        amount: Amount,
+        key: Option<&str>,
+    ) -> Result<(AccountUpdate, Bet), BetError> {
+        let conn = Connection::open(&self.db_path)?;
+        if conn.is_guess_bet(bet)? {
+            return Err(BetError::InvalidGuess);
+        }
+        if conn.is_market(bet)? {
+            return Err(BetError::MarketTrade);
+        }
+        self.place_wager(bet, outcome, user, amount, key, |_tx| Ok(()))
+    }
+
+    // Places a wager, `before_commit` can add to the transaction
+    pub(crate) fn place_wager<F>(
+        &self,
+        bet: u64,
+        outcome: usize,
+        user: u64,
+        amount: Amount,
+        key: Option<&str>,
+        before_commit: F,
+    ) -> Result<(AccountUpdate, Bet), BetError>
+    where F: FnOnce(&Transaction) -> Result<(), BetError> {
         let mut conn = Connection::open(&self.db_path)?;
+        // the bet, the stakes and the balance are read in the transaction,
+        // so that concurrent wagers can't go over the limits together
+        let tx = conn.transaction()?;
         // check if the bet is open
-        let bet_info = conn.bet_info(bet)?;
+        let bet_info = tx.bet_info(bet)?;
         if !bet_info.is_open {
             return Err(BetError::BetLocked);
         }
-        conn.assert_bet_not_deleted(bet)?;
-        // compute the amount to bet
-        let balance = conn.balance(bet_info.server, user)?;
-        let amount = match amount {
-            Amount::FLAT(value) => {
-                if value > balance {
-                    return Err(BetError::NotEnoughMoney);
-                }
-                value
-            },
-            Amount::FRACTION(part) => {
-                assert!(0. <= part && part <= 1.);
-                let value = f32::ceil(balance as f32 * part) as u64;
-                if value == 0 {
-                    return Err(BetError::NotEnoughMoney);
-                }
-                value
+        tx.assert_bet_not_deleted(bet)?;
+        if !tx.outcomes_of_bet(bet)?.contains(&(outcome as u64)) {
+            return Err(BetError::NotFound);
+        }
+        // a user can only bet on one outcome of a bet
+        if let Some(previous) = tx
+            .prepare(
+                "SELECT outcome 
+                FROM Wager
+                WHERE bet = ?1 AND user = ?2",
+            )
+            .unwrap()
+            .query_row([bet, user], |row| row.get::<usize, u64>(0))
+            .optional()?
+        {
+            if previous != outcome as u64 {
+                return Err(BetError::MultiOpt(vec![
+                    tx.outcome_status(bet, previous)?.desc,
+                    tx.outcome_status(bet, outcome as u64)?.desc,
+                ]));
             }
-        };
+        }
+        // compute the amount to bet
+        let amount = tx.stake(bet_info.server, user, Some(bet), amount)?;
         // bet
-        let tx = conn.transaction()?;
         let acc_update = tx.change_balance(bet_info.server, user, -(amount as i64))?;
         tx.execute(
             "INSERT or ignore
@@ -228,11 +803,19 @@ impl Bets {
             ",
             params![amount, bet, outcome, user],
         )?;
+        tx.emit(BetEvent::WagerPlaced { server: bet_info.server, bet, user, outcome, amount })?;
+        before_commit(&tx)?;
+        if let Some(key) = key {
+            tx.record_key(key, "bet_on", std::slice::from_ref(&acc_update))?;
+        }
         tx.commit()?;
+        self.count(Counter::WagersPlaced, 1);
+        self.count(Counter::CoinsWagered, amount);
+        self.publish();
         Ok((
             acc_update,
             Bet {
-                bet: bet.clone(),
+                bet,
                 desc: bet_info.desc,
                 outcomes: conn.outcomes_statuses(bet)?,
                 is_open: bet_info.is_open,
@@ -242,15 +825,181 @@ impl Bets {
         ))
     }
 
-    pub fn lock_bet(&self, bet: u64) -> Result<(), BetError> {
+    /// Bets on a guess, betting again is only allowed with the same guess.
+    pub fn guess_on<A>(
+        &self,
+        bet: u64,
+        user: u64,
+        guess: f64,
+        amount: A,
+    ) -> Result<(AccountUpdate, Bet), BetError>
+    where A: Into<Amount> {
+        if !guess.is_finite() {
+            return Err(BetError::InvalidGuess);
+        }
         let conn = Connection::open(&self.db_path)?;
-        conn.execute(
-            "UPDATE Bet
-            SET is_open = 0
-            WHERE uuid = ?1",
-            [bet],
+        if !conn.is_guess_bet(bet)? {
+            return Err(BetError::NotFound);
+        }
+        if let Some(previous) = conn
+            .prepare(
+                "SELECT guess 
+                FROM Guess
+                WHERE bet = ?1 AND user = ?2",
+            )
+            .unwrap()
+            .query_row([bet, user], |row| row.get::<usize, f64>(0))
+            .optional()?
+        {
+            if previous != guess {
+                return Err(BetError::MultiOpt(vec![previous.to_string(), guess.to_string()]));
+            }
+        }
+        self.place_wager(bet, 0, user, amount.into(), None, |tx| {
+            tx.execute(
+                "INSERT or ignore
+                INTO Guess (bet, user, guess)
+                VALUES (?1, ?2, ?3)",
+                params![bet, user, guess],
+            )?;
+            Ok(())
+        })
+    }
+
+    pub fn lock_bet(&self, bet: u64) -> Result<(), BetError> {
+        self.measure("lock_bet", || {
+            let mut conn = Connection::open(&self.db_path)?;
+            let tx = conn.transaction()?;
+            // resolved bets stay open until they're purged
+            tx.assert_bet_not_deleted(bet)?;
+            let locked = tx
+                .query_row(
+                    "UPDATE Bet
+                    SET is_open = 0
+                    WHERE uuid = ?1 AND is_open = 1
+                    RETURNING server",
+                    [bet],
+                    |row| row.get::<usize, u64>(0),
+                )
+                .optional()?;
+            if let Some(server) = locked {
+                tx.emit(BetEvent::BetLocked { server, bet })?;
+            }
+            tx.commit()?;
+            if locked.is_some() {
+                self.count(Counter::BetsLocked, 1);
+            }
+            self.publish();
+            Ok(())
+        })
+    }
+
+    /// Places a single wager on several (bet, outcome) legs, which pays only if every leg wins.
+    /// 
+    /// The value of the parlay, at first its stake, rides on each leg in the pool of its bet: when the bet
+    /// is resolved the value is counted as a wager on the outcome of the leg, so a winning leg multiplies it
+    /// by the odds of the pool (after fees) and a losing leg gives it to the winners of the bet.
+    /// The parlay pays its value once every leg is won, coins are only ever moved between the pools.
+    /// Legs on aborted bets are void and count as x1.
+    /// 
+    /// Guess, ranked and market bets have no such pool and can't be legs.
+    pub fn create_parlay<A>(
+        &self,
+        parlay_uuid: u64,
+        server: u64,
+        user: u64,
+        legs: &[(u64, usize)],
+        amount: A,
+    ) -> Result<AccountUpdate, BetError>
+    where A: Into<Amount> {
+        let amount: Amount = amount.into();
+        let mut conn = Connection::open(&self.db_path)?;
+        if legs.len() < 2 || legs.iter().map(|(bet, _)| bet).unique().count() != legs.len() {
+            return Err(BetError::InvalidParlay);
+        }
+        let tx = conn.transaction()?;
+        for (bet, outcome) in legs {
+            let bet_info = tx.bet_info(*bet)?;
+            tx.assert_bet_not_deleted(*bet)?;
+            if bet_info.server != server {
+                return Err(BetError::InvalidParlay);
+            }
+            if !bet_info.is_open {
+                return Err(BetError::BetLocked);
+            }
+            if !tx.outcomes_of_bet(*bet)?.contains(&(*outcome as u64)) {
+                return Err(BetError::NotFound);
+            }
+            if tx
+                .prepare(
+                    "SELECT bet FROM GuessBet WHERE bet = ?1
+                    UNION ALL
+                    SELECT bet FROM RankedBet WHERE bet = ?1
+                    UNION ALL
+                    SELECT bet FROM Market WHERE bet = ?1",
+                )
+                .unwrap()
+                .exists([bet])?
+            {
+                return Err(BetError::InvalidParlay);
+            }
+        }
+        let amount = tx.stake(server, user, None, amount)?;
+        let acc_update = tx.change_balance(server, user, -(amount as i64))?;
+        tx.execute(
+            "INSERT 
+            INTO Parlay (uuid, server, user, stake, value) 
+            VALUES (?1, ?2, ?3, ?4, ?4)",
+            params![parlay_uuid, server, user, amount],
         )?;
-        Ok(())
+        for (bet, outcome) in legs {
+            tx.execute(
+                "INSERT 
+                INTO ParlayLeg (parlay, bet, outcome) 
+                VALUES (?1, ?2, ?3)",
+                params![parlay_uuid, bet, outcome],
+            )?;
+        }
+        tx.emit(BetEvent::ParlayPlaced { server, parlay: parlay_uuid, user, amount })?;
+        tx.commit()?;
+        self.count(Counter::WagersPlaced, 1);
+        self.count(Counter::CoinsWagered, amount);
+        self.publish();
+        Ok(acc_update)
+    }
+
+    pub fn parlay(&self, parlay_uuid: u64) -> Result<Parlay, BetError> {
+        let conn = Connection::open(&self.db_path)?;
+        let (server, user, stake, value, payout) = conn
+            .prepare(
+                "SELECT server, user, stake, value, payout 
+                FROM Parlay
+                WHERE uuid = ?1",
+            )
+            .unwrap()
+            .query_row([parlay_uuid], |row| Ok((
+                row.get::<usize, u64>(0)?,
+                row.get::<usize, u64>(1)?,
+                row.get::<usize, u64>(2)?,
+                row.get::<usize, u64>(3)?,
+                row.get::<usize, Option<u64>>(4)?,
+            )))?;
+        let legs = conn
+            .prepare(
+                "SELECT bet, outcome, odds, stake, payout 
+                FROM ParlayLeg
+                WHERE parlay = ?1",
+            )
+            .unwrap()
+            .query_map([parlay_uuid], |row| Ok(ParlayLeg {
+                bet: row.get::<usize, u64>(0)?,
+                outcome: row.get::<usize, usize>(1)?,
+                odds: row.get::<usize, Option<f64>>(2)?,
+                stake: row.get::<usize, Option<u64>>(3)?,
+                payout: row.get::<usize, Option<u64>>(4)?,
+            }))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Parlay { parlay: parlay_uuid, server, user, stake, value, payout, legs })
     }
 
     fn delete_bet(
@@ -270,7 +1019,10 @@ impl Bets {
     }
 
     pub fn abort_bet(&self, bet: u64) -> Result<Vec<AccountUpdate>, BetError> {
-        let bet = bet;
+        self.measure("abort_bet", || self.abort_keyed(bet, None))
+    }
+
+    pub(crate) fn abort_keyed(&self, bet: u64, key: Option<&str>) -> Result<Vec<AccountUpdate>, BetError> {
         let mut conn = Connection::open(&self.db_path)?;
         conn.assert_bet_not_deleted(bet)?;
         let bet_info = conn.bet_info(bet)?;
@@ -284,24 +1036,100 @@ impl Bets {
         for (user, amount) in wagers {
             account_updates.push(tx.change_balance(bet_info.server, user, amount as i64)?);
         }
+        account_updates.extend(tx.settle_parlay_legs(bet, None)?);
+        account_updates.extend(Bets::refund_ranked_wagers(&tx, bet)?);
+        account_updates.extend(Bets::refund_market_shares(&tx, bet)?);
+        tx.emit(BetEvent::BetAborted { server: bet_info.server, bet })?;
         // delete the bet
         Bets::delete_bet(
             &tx, bet
         )?;
+        if let Some(key) = key {
+            tx.record_key(key, "abort_bet", &account_updates)?;
+        }
         tx.commit()?;
+        self.count(Counter::BetsAborted, 1);
+        self.publish();
+        Ok(account_updates)
+    }
+
+    fn refund_ranked_wagers(tx: &Transaction, bet: u64) -> Result<Vec<AccountUpdate>, BetError> {
+        let wagers = tx.prepare(
+            "SELECT server, user, amount 
+            FROM RankedWager
+            WHERE bet = ?1 AND payout IS NULL",
+        )
+        .unwrap()
+        .query_map([bet], |row| Ok((
+            row.get::<usize, u64>(0)?,
+            row.get::<usize, u64>(1)?,
+            row.get::<usize, i64>(2)?,
+        )))?
+        .collect::<Result<Vec<_>, _>>()?;
+        let mut account_updates = Vec::new();
+        for (server, user, amount) in wagers {
+            account_updates.push(tx.change_balance(server, user, amount)?);
+        }
+        tx.execute("DELETE FROM RankedWager WHERE bet = ?1", [bet])?;
         Ok(account_updates)
     }
 
+    /// Resolves a bet, the wagers on the winning outcome split the whole pool.
+    /// 
+    /// Ranked bets must be resolved with `resolve_ranking` instead, and guess bets with `resolve_numeric`.
     pub fn resolve(
         &self,
         bet: u64,
         winning_outcome: usize,
     ) -> Result<Vec<AccountUpdate>, BetError> {
+        self.measure("resolve", || self.resolve_keyed(bet, winning_outcome, None))
+    }
+
+    pub(crate) fn resolve_keyed(
+        &self,
+        bet: u64,
+        winning_outcome: usize,
+        key: Option<&str>,
+    ) -> Result<Vec<AccountUpdate>, BetError> {
+        let conn = Connection::open(&self.db_path)?;
+        if conn.prepare(
+            "SELECT * 
+            FROM RankedBet
+            WHERE bet = ?1",
+        )
+        .unwrap()
+        .exists([bet])?
+        {
+            return Err(BetError::InvalidRanking);
+        }
+        if conn.is_guess_bet(bet)? {
+            return Err(BetError::InvalidGuess);
+        }
+        self.resolve_with(bet, winning_outcome, key, |_tx| Ok((Vec::new(), 0)))
+    }
+
+    // Resolves a bet, `before_commit` can add to the transaction and report more account updates
+    // along with the coins its fee took
+    pub(crate) fn resolve_with<F>(
+        &self,
+        bet: u64,
+        winning_outcome: usize,
+        key: Option<&str>,
+        before_commit: F,
+    ) -> Result<Vec<AccountUpdate>, BetError>
+    where F: FnOnce(&Transaction) -> Result<(Vec<AccountUpdate>, u64), BetError> {
         let mut conn = Connection::open(&self.db_path)?;
-        let bet_info = conn.bet_info(bet)?;
+        let tx = conn.transaction()?;
+        let bet_info = tx.bet_info(bet)?;
         // retrieve the total of the bet and the winning parts
-        conn.assert_bet_not_deleted(bet)?;
-        let outcomes_statuses = conn.outcomes_statuses(bet)?;
+        tx.assert_bet_not_deleted(bet)?;
+        // an index past the outcomes would have no winners and take every stake
+        if !tx.outcomes_of_bet(bet)?.contains(&(winning_outcome as u64)) {
+            return Err(BetError::InvalidOutcome);
+        }
+        let outcomes_statuses = tx.outcomes_statuses(bet)?;
+        // the value of the pending parlays rides on their leg like a wager
+        let parlay_legs = tx.pending_parlay_legs(bet)?;
         let mut winners: Vec<u64> = Vec::new();
         let mut wins: Vec<u64> = Vec::new();
         let mut total = 0;
@@ -318,28 +1146,510 @@ impl Bets {
                 }
             }
         }
+        let mut winning_parlays = Vec::new();
+        for (parlay, outcome, value) in &parlay_legs {
+            total += value;
+            if *outcome == winning_outcome {
+                winning_parlays.push(*parlay);
+                wins.push(*value);
+            }
+        }
         // Check if bets were placed on more than one outcome
-        let apply_fee = betting_outcomes > 1;
+        let betting_outcomes = outcomes_statuses
+            .iter()
+            .enumerate()
+            .filter(|(i, outcome_status)| {
+                !outcome_status.wagers.is_empty() || parlay_legs.iter().any(|(_, outcome, _)| outcome == i)
+            })
+            .count();
+        let fee = if betting_outcomes > 1 { tx.server_config(bet_info.server)?.fee } else { 0. };
         
         // compute the gains for each winners
         let gains = utils::lrm(total, &wins);
         // update the accounts
         let mut account_updates = Vec::new();
-        let tx = conn.transaction()?;
-        for (user, gain) in izip!(winners, gains) {
-    let net_gain = if apply_fee {
-            (gain as f64 * 0.90) as i64 // Apply 10% fee if necessary
+        let mut payouts = HashMap::new();
+        let mut raked = 0;
+        let winning_wagers = winners.len();
+        for (user, gain) in izip!(winners, &gains) {
+            let net_gain = Bets::net_gain(*gain, fee);
+            raked += gain - net_gain as u64;
+            payouts.insert(user, net_gain as u64);
+            account_updates.push(tx.change_balance(bet_info.server, user, net_gain)?);
+        }
+        // the losing parlays lost their value to the pool
+        let mut parlay_payouts: Vec<(u64, u64)> = parlay_legs.iter().map(|(parlay, _, _)| (*parlay, 0)).collect();
+        for (parlay, gain) in izip!(winning_parlays, &gains[winning_wagers..]) {
+            let net_gain = Bets::net_gain(*gain, fee) as u64;
+            raked += gain - net_gain;
+            parlay_payouts.iter_mut().find(|(leg, _)| *leg == parlay).unwrap().1 = net_gain;
+        }
+        // record the resolution so that it can be rolled back
+        tx.record_resolution(bet, bet_info.server, winning_outcome, &outcomes_statuses, &payouts)?;
+        account_updates.extend(tx.settle_parlay_legs(bet, Some(&parlay_payouts))?);
+        account_updates.extend(Bets::pay_market_shares(&tx, bet, winning_outcome)?);
+        let (side_updates, side_raked) = before_commit(&tx)?;
+        account_updates.extend(side_updates);
+        raked += side_raked;
+        tx.emit(BetEvent::BetResolved { server: bet_info.server, bet, outcome: winning_outcome })?;
+        // delete the bet
+        Bets::delete_bet(&tx, bet)?;
+        if let Some(key) = key {
+            tx.record_key(key, "resolve", &account_updates)?;
+        }
+        tx.commit()?;
+        self.count_resolution(&account_updates, raked);
+        self.publish();
+        Ok(account_updates)
+    }
+
+    pub(crate) fn net_gain(gain: u64, fee: f64) -> i64 {
+        if fee > 0. {
+            (gain as f64 * (1. - fee)) as i64 // Apply the fee if necessary
         } else {
             gain as i64 // Distribute gains without fee
-        };
-        account_updates.push(tx.change_balance(bet_info.server, user, net_gain)?);
+        }
     }
-        // delete the bet
+
+    /// Resolves a numeric bet with the actual value, the range containing it wins.
+    /// The value must be finite.
+    /// 
+    /// For guess bets, the pot is split among the guesses according to their scoring.
+    pub fn resolve_numeric(
+        &self,
+        bet: u64,
+        value: f64,
+    ) -> Result<Vec<AccountUpdate>, BetError> {
+        self.resolve_numeric_keyed(bet, value, None)
+    }
+
+    pub(crate) fn resolve_numeric_keyed(
+        &self,
+        bet: u64,
+        value: f64,
+        key: Option<&str>,
+    ) -> Result<Vec<AccountUpdate>, BetError> {
+        let conn = Connection::open(&self.db_path)?;
+        let scoring = conn
+            .prepare(
+                "SELECT scoring 
+                FROM GuessBet
+                WHERE bet = ?1",
+            )
+            .unwrap()
+            .query_row([bet], |row| row.get::<usize, u32>(0))
+            .optional()?;
+        // a guess bet is measured as a guess resolution only
+        let operation = if scoring.is_some() { "resolve_guess" } else { "resolve_numeric" };
+        self.measure(operation, || self.resolve_value(&conn, bet, value, scoring, key))
+    }
+
+    fn resolve_value(
+        &self,
+        conn: &Connection,
+        bet: u64,
+        value: f64,
+        scoring: Option<u32>,
+        key: Option<&str>,
+    ) -> Result<Vec<AccountUpdate>, BetError> {
+        // a NaN is at distance 0 of every range
+        if !value.is_finite() {
+            return Err(BetError::InvalidBounds);
+        }
+        conn.assert_bet_not_deleted(bet)?;
+        if let Some(scoring) = scoring {
+            return self.resolve_guess(bet, value, GuessScoring::from(scoring), key);
+        }
+        let closest_guess = conn
+            .prepare(
+                "SELECT closest_guess 
+                FROM NumericBet
+                WHERE bet = ?1",
+            )
+            .unwrap()
+            .query_row([bet], |row| row.get::<usize, bool>(0))?;
+        let ranges = conn
+            .prepare(
+                "SELECT number, low, high 
+                FROM NumericOutcome
+                WHERE bet = ?1
+                ORDER BY number",
+            )
+            .unwrap()
+            .query_map([bet], |row| Ok((
+                row.get::<usize, usize>(0)?,
+                (row.get::<usize, Option<f64>>(1)?, row.get::<usize, Option<f64>>(2)?),
+            )))?
+            .collect::<Result<Vec<_>, _>>()?;
+        let winning_outcome = if closest_guess {
+            let outcomes_statuses = conn.outcomes_statuses(bet)?;
+            ranges
+                .iter()
+                .filter(|(number, _)| !outcomes_statuses[*number].wagers.is_empty())
+                // min_by keeps the first of equally close ranges, the lowest one
+                .min_by(|(_, range1), (_, range2)| {
+                    numeric::distance(range1, value).total_cmp(&numeric::distance(range2, value))
+                })
+                .or_else(|| ranges.iter().find(|(_, range)| numeric::distance(range, value) == 0.))
+        } else {
+            ranges.iter().find(|(_, range)| numeric::distance(range, value) == 0.)
+        }
+        .ok_or(BetError::NotFound)?
+        .0;
+        self.resolve_keyed(bet, winning_outcome, key)
+    }
+
+    fn resolve_guess(
+        &self,
+        bet: u64,
+        value: f64,
+        scoring: GuessScoring,
+        key: Option<&str>,
+    ) -> Result<Vec<AccountUpdate>, BetError> {
+        let mut conn = Connection::open(&self.db_path)?;
+        // the wagers are read in the transaction, so that none placed meanwhile is deleted without being paid
+        let tx = conn.transaction()?;
+        let bet_info = tx.bet_info(bet)?;
+        tx.assert_bet_not_deleted(bet)?;
+        let outcomes_statuses = tx.outcomes_statuses(bet)?;
+        let guesses: HashMap<u64, f64> = tx
+            .prepare(
+                "SELECT user, guess 
+                FROM Guess
+                WHERE bet = ?1",
+            )
+            .unwrap()
+            .query_map([bet], |row| Ok((row.get::<usize, u64>(0)?, row.get::<usize, f64>(1)?)))?
+            .collect::<Result<_, _>>()?;
+        // (distance, user, stake) sorted so that closer guesses, then lower user ids, win the ties
+        let mut wagers: Vec<(f64, u64, u64)> = outcomes_statuses
+            .iter()
+            .flat_map(|outcome_status| outcome_status.wagers.iter())
+            .filter_map(|(user, stake)| guesses.get(user).map(|guess| ((guess - value).abs(), *user, *stake)))
+            .collect();
+        wagers.sort_by(|(dist1, user1, _), (dist2, user2, _)| dist1.total_cmp(dist2).then(user1.cmp(user2)));
+        let total = outcomes_statuses
+            .iter()
+            .flat_map(|outcome_status| outcome_status.wagers.iter())
+            .map(|(_, stake)| stake)
+            .sum::<u64>();
+        let weights: Vec<u64> = match scoring {
+            GuessScoring::Closest => {
+                let closest = wagers.first().map(|(dist, _, _)| *dist);
+                wagers
+                    .iter()
+                    .map(|(dist, _, stake)| if Some(*dist) == closest { *stake } else { 0 })
+                    .collect()
+            },
+            // the stakes are taken as a share of the total so that the weights can't saturate
+            GuessScoring::InverseDistance => wagers
+                .iter()
+                .map(|(dist, _, stake)| (*stake as f64 / total as f64 * 1e12 / (1. + dist)) as u64)
+                .collect(),
+        };
+        // the fee applies if the guesses were not all the same
+        let fee = if guesses.values().map(|guess| guess.to_bits()).unique().count() > 1 {
+            tx.server_config(bet_info.server)?.fee
+        } else {
+            0.
+        };
+        let gains = utils::lrm(total, &weights);
+        let mut account_updates = Vec::new();
+        let mut payouts = HashMap::new();
+        let mut raked = 0;
+        for ((_, user, _), gain) in wagers.iter().zip(gains) {
+            let net_gain = Bets::net_gain(gain, fee);
+            raked += gain - net_gain as u64;
+            if net_gain > 0 {
+                payouts.insert(*user, net_gain as u64);
+                account_updates.push(tx.change_balance(bet_info.server, *user, net_gain)?);
+            }
+        }
+        tx.record_resolution(bet, bet_info.server, 0, &outcomes_statuses, &payouts)?;
+        // parlay legs can't be placed on a guess, they are void
+        account_updates.extend(tx.settle_parlay_legs(bet, None)?);
+        tx.emit(BetEvent::BetResolved { server: bet_info.server, bet, outcome: 0 })?;
         Bets::delete_bet(&tx, bet)?;
+        if let Some(key) = key {
+            tx.record_key(key, "resolve", &account_updates)?;
+        }
+        tx.commit()?;
+        self.count_resolution(&account_updates, raked);
+        self.publish();
+        Ok(account_updates)
+    }
+
+    /// Reverts a resolved bet to the locked state: the recorded payouts are clawed back
+    /// and the original wagers are put back in the pools.
+    /// 
+    /// The `policy` decides what happens when a winner already spent (part of) their winnings.
+    /// Parlay legs on this bet go back to pending: the parlays get back the value they rode on it with,
+    /// and the payout of the ones that were completed since is clawed back too. The rollback is refused with
+    /// `BetError::ParlayLost` if such a parlay then lost on another bet, its value went to that bet's winners.
+    pub fn rollback_resolution(
+        &self,
+        bet: u64,
+        policy: ClawbackPolicy,
+    ) -> Result<Vec<AccountUpdate>, BetError> {
+        let mut conn = Connection::open(&self.db_path)?;
+        let server = conn
+            .prepare(
+                "SELECT server 
+                FROM Resolution
+                WHERE bet = ?1",
+            )
+            .unwrap()
+            .query_row([bet], |row| row.get::<usize, u64>(0))?;
+        let mut stmt = conn
+            .prepare(
+                "SELECT outcome, user, stake, payout 
+                FROM Payout
+                WHERE bet = ?1",
+            )
+            .unwrap();
+        let payouts = stmt
+            .query_map([bet], |row| {
+                Ok((
+                    row.get::<usize, u64>(0)?,
+                    row.get::<usize, u64>(1)?,
+                    row.get::<usize, u64>(2)?,
+                    row.get::<usize, u64>(3)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        drop(stmt);
+        let mut account_updates = Vec::new();
+        // Map <user, coins they could not pay back>
+        let mut debts = HashMap::new();
+        let tx = conn.transaction()?;
+        for (_, user, _, payout) in &payouts {
+            if *payout > 0 {
+                account_updates.push(Bets::claw_back(&tx, server, *user, *payout, policy, &mut debts)?);
+            }
+        }
+        // the ranked pools and market shares were paid too
+        let side_payouts = tx.prepare(
+            "SELECT user, payout 
+            FROM RankedWager
+            WHERE bet = ?1 AND payout > 0
+            UNION ALL
+            SELECT user, payout 
+            FROM MarketShares
+            WHERE bet = ?1 AND payout > 0",
+        )
+        .unwrap()
+        .query_map([bet], |row| Ok((row.get::<usize, u64>(0)?, row.get::<usize, u64>(1)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+        for (user, payout) in side_payouts {
+            account_updates.push(Bets::claw_back(&tx, server, user, payout, policy, &mut debts)?);
+        }
+        // the parlays get back the value they had before the bet, minus what they won on it
+        let parlay_legs = tx.prepare(
+            "SELECT Parlay.uuid, Parlay.user, Parlay.value, Parlay.payout, ParlayLeg.stake, ParlayLeg.payout
+            FROM ParlayLeg
+            JOIN Parlay ON Parlay.uuid = ParlayLeg.parlay
+            WHERE ParlayLeg.bet = ?1 AND ParlayLeg.stake > 0",
+        )
+        .unwrap()
+        .query_map([bet], |row| Ok((
+            row.get::<usize, u64>(0)?,
+            row.get::<usize, u64>(1)?,
+            row.get::<usize, u64>(2)?,
+            row.get::<usize, Option<u64>>(3)?,
+            row.get::<usize, u64>(4)?,
+            row.get::<usize, u64>(5)?,
+        )))?
+        .collect::<Result<Vec<_>, _>>()?;
+        for (parlay, user, value, settled, stake, payout) in parlay_legs {
+            if tx
+                .prepare("SELECT * FROM ParlayLeg WHERE parlay = ?1 AND bet != ?2 AND stake > 0 AND payout = 0")
+                .unwrap()
+                .exists([parlay, bet])?
+            {
+                return Err(BetError::ParlayLost);
+            }
+            // a completed parlay gives back its payout first
+            if let Some(settled) = settled.filter(|settled| *settled > 0) {
+                account_updates.push(Bets::claw_back(&tx, server, user, settled, policy, &mut debts)?);
+            }
+            let value = value + stake;
+            let value = if value >= payout {
+                value - payout
+            } else {
+                // the legs decided since made it lose value, its user pays for the rest
+                account_updates.push(Bets::claw_back(&tx, server, user, payout - value, policy, &mut debts)?);
+                0
+            };
+            tx.execute(
+                "UPDATE Parlay
+                SET value = ?1, payout = NULL
+                WHERE uuid = ?2",
+                [Bets::pay_debt(&mut debts, user, value), parlay],
+            )?;
+        }
+        // the wagers are put back, minus the debts of their users
+        for (outcome, user, stake, _) in payouts {
+            let stake = Bets::pay_debt(&mut debts, user, stake);
+            if stake > 0 {
+                tx.execute(
+                    "INSERT 
+                    INTO Wager (bet, outcome, server, user, amount)
+                    VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![bet, outcome, server, user, stake],
+                )?;
+            }
+        }
+        let debtors: Vec<u64> = debts.iter().filter(|(_, debt)| **debt > 0).map(|(user, _)| *user).collect();
+        for user in debtors {
+            let ranked_wagers = tx.prepare(
+                "SELECT pool, amount 
+                FROM RankedWager
+                WHERE bet = ?1 AND user = ?2",
+            )
+            .unwrap()
+            .query_map([bet, user], |row| Ok((row.get::<usize, u64>(0)?, row.get::<usize, u64>(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+            for (pool, amount) in ranked_wagers {
+                tx.execute(
+                    "UPDATE RankedWager
+                    SET amount = ?1
+                    WHERE bet = ?2 AND user = ?3 AND pool = ?4",
+                    [Bets::pay_debt(&mut debts, user, amount), bet, user, pool],
+                )?;
+            }
+            // each winning share was paid 1 coin
+            let shares = tx.prepare(
+                "SELECT outcome, shares 
+                FROM MarketShares
+                WHERE bet = ?1 AND user = ?2 AND payout > 0",
+            )
+            .unwrap()
+            .query_map([bet, user], |row| Ok((row.get::<usize, u64>(0)?, row.get::<usize, u64>(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+            for (outcome, shares) in shares {
+                tx.execute(
+                    "UPDATE MarketShares
+                    SET shares = ?1
+                    WHERE bet = ?2 AND user = ?3 AND outcome = ?4",
+                    [Bets::pay_debt(&mut debts, user, shares), bet, user, outcome],
+                )?;
+            }
+        }
+        // putting back more than was taken back would create coins
+        if debts.values().any(|debt| *debt > 0) {
+            return Err(BetError::NotEnoughMoney);
+        }
+        tx.execute(
+            "UPDATE RankedWager
+            SET payout = NULL
+            WHERE bet = ?1",
+            [bet],
+        )?;
+        tx.execute(
+            "UPDATE MarketShares
+            SET payout = NULL
+            WHERE bet = ?1",
+            [bet],
+        )?;
+        tx.execute(
+            "UPDATE ParlayLeg
+            SET odds = NULL, stake = NULL, payout = NULL
+            WHERE bet = ?1",
+            [bet],
+        )?;
+        tx.execute("DELETE FROM Payout WHERE bet = ?1", [bet])?;
+        tx.execute("DELETE FROM Resolution WHERE bet = ?1", [bet])?;
+        tx.execute("DELETE FROM ToDelete WHERE bet = ?1", [bet])?;
+        tx.execute(
+            "UPDATE Bet
+            SET is_open = 0
+            WHERE uuid = ?1",
+            [bet],
+        )?;
+        // the bet is back to the locked state
+        tx.emit(BetEvent::BetRolledBack { server, bet })?;
         tx.commit()?;
+        self.publish();
         Ok(account_updates)
     }
 
+    fn claw_back(
+        tx: &Transaction,
+        server: u64,
+        user: u64,
+        payout: u64,
+        policy: ClawbackPolicy,
+        debts: &mut HashMap<u64, u64>,
+    ) -> Result<AccountUpdate, BetError> {
+        let balance = tx.balance(server, user)?;
+        let clawback = match policy {
+            ClawbackPolicy::Refuse if balance < payout => {
+                return Err(BetError::NotEnoughMoney);
+            }
+            ClawbackPolicy::Refuse => payout,
+            ClawbackPolicy::Partial => payout.min(balance),
+        };
+        *debts.entry(user).or_default() += payout - clawback;
+        tx.change_balance(server, user, -(clawback as i64))
+    }
+
+    // Pays (part of) the debt of a user with an amount they get back on the bet, returns what's left of it
+    fn pay_debt(debts: &mut HashMap<u64, u64>, user: u64, amount: u64) -> u64 {
+        let debt = debts.entry(user).or_default();
+        let paid = amount.min(*debt);
+        *debt -= paid;
+        amount - paid
+    }
+
+    /// Forgets the resolutions older than `max_age`, returns how many bets were deleted.
+    /// 
+    /// Resolved bets are kept so that they can be rolled back, this deletes them with their outcomes,
+    /// payouts and side tables. Bets with legs of parlays are kept until the parlays are settled
+    /// and the other resolutions they rode on can't be rolled back either, then the parlays are deleted too.
+    pub fn purge_resolutions(&self, max_age: Duration) -> Result<usize, BetError> {
+        let mut conn = Connection::open(&self.db_path)?;
+        let tx = conn.transaction()?;
+        let resolved_before = utils::now().saturating_sub(max_age.as_secs());
+        let purged = tx
+            .prepare(
+                "SELECT bet 
+                FROM Resolution
+                WHERE time <= ?1 AND NOT EXISTS (
+                    SELECT *
+                    FROM ParlayLeg
+                    JOIN Parlay ON Parlay.uuid = ParlayLeg.parlay
+                    WHERE ParlayLeg.bet = Resolution.bet AND (
+                        Parlay.payout IS NULL
+                        OR EXISTS (
+                            SELECT *
+                            FROM ParlayLeg AS Other
+                            JOIN Resolution AS OtherResolution ON OtherResolution.bet = Other.bet
+                            WHERE Other.parlay = Parlay.uuid AND OtherResolution.time > ?1
+                        )
+                    )
+                )",
+            )
+            .unwrap()
+            .query_map([resolved_before], |row| row.get::<usize, u64>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        for bet in &purged {
+            let parlays = tx
+                .prepare("SELECT parlay FROM ParlayLeg WHERE bet = ?1")
+                .unwrap()
+                .query_map([bet], |row| row.get::<usize, u64>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            for parlay in parlays {
+                tx.execute("DELETE FROM ParlayLeg WHERE parlay = ?1", [parlay])?;
+                tx.execute("DELETE FROM Parlay WHERE uuid = ?1", [parlay])?;
+            }
+            // the other tables cascade from Bet or Outcome
+            tx.execute("DELETE FROM Outcome WHERE bet = ?1", [bet])?;
+            tx.execute("DELETE FROM Bet WHERE uuid = ?1", [bet])?;
+        }
+        tx.commit()?;
+        Ok(purged.len())
+    }
+
     pub fn position(&self, user: u64, bet: u64) -> Result<Position, BetError> {
         let conn = Connection::open(&self.db_path)?;
         let (outcome, amount) = conn
@@ -370,14 +1680,32 @@ impl Bets {
                 ",
         )
         .unwrap().query_row([server, user], |row| row.get::<usize, u64>(0))?;
+        // a market position counts for what an abort would refund, its cost unless it was sold at a profit
         let mut stmt = conn.prepare(
             "SELECT amount
             FROM Wager
-            WHERE server = ?1 AND user = ?2"
+            WHERE server = ?1 AND user = ?2
+            UNION ALL
+            SELECT amount
+            FROM RankedWager
+            WHERE server = ?1 AND user = ?2 AND payout IS NULL
+            UNION ALL
+            SELECT value
+            FROM Parlay
+            WHERE server = ?1 AND user = ?2 AND payout IS NULL
+            UNION ALL
+            SELECT stake
+            FROM Challenge
+            WHERE server = ?1 AND (challenger = ?2 OR (opponent = ?2 AND accepted = 1))
+            UNION ALL
+            SELECT MAX(SUM(cost), 0)
+            FROM MarketShares
+            WHERE server = ?1 AND user = ?2 AND payout IS NULL
+            GROUP BY bet"
         ).unwrap();
-        let mut rows = stmt.query_map([server, user], |row| row.get::<usize, u64>(0))?;
+        let rows = stmt.query_map([server, user], |row| row.get::<usize, u64>(0))?;
         let mut in_bet = 0;
-        while let Some(amount_res) = rows.next() {
+        for amount_res in rows {
             in_bet += amount_res?;
         }
         Ok(AccountStatus { user, balance, in_bet })
@@ -404,7 +1732,28 @@ impl Bets {
             .prepare(
                 "SELECT user, amount 
                     FROM Wager
-                    WHERE server = ?1",
+                    WHERE server = ?1
+                    UNION ALL
+                    SELECT user, amount 
+                    FROM RankedWager
+                    WHERE server = ?1 AND payout IS NULL
+                    UNION ALL
+                    SELECT user, value 
+                    FROM Parlay
+                    WHERE server = ?1 AND payout IS NULL
+                    UNION ALL
+                    SELECT challenger, stake 
+                    FROM Challenge
+                    WHERE server = ?1
+                    UNION ALL
+                    SELECT opponent, stake 
+                    FROM Challenge
+                    WHERE server = ?1 AND accepted = 1
+                    UNION ALL
+                    SELECT user, MAX(SUM(cost), 0) 
+                    FROM MarketShares
+                    WHERE server = ?1 AND payout IS NULL
+                    GROUP BY bet, user",
             )
             .unwrap();
         let mut rows = stmt.query([server])?;
@@ -421,8 +1770,8 @@ impl Bets {
         Ok(accounts
             .into_iter()
             .map(|(user, balance)| AccountStatus {
-                user: user,
-                balance: balance,
+                user,
+                balance,
                 in_bet: *wagers.get(&user).unwrap_or(&0),
             })
             .collect())
@@ -432,4 +1781,57 @@ impl Bets {
         let conn = Connection::open(&self.db_path)?;
         conn.bet_info(bet_uuid)
     }
+
+    /// A bet with the wagers on each of its outcomes
+    pub fn bet(&self, bet_uuid: u64) -> Result<Bet, BetError> {
+        let conn = Connection::open(&self.db_path)?;
+        let bet_info = conn.bet_info(bet_uuid)?;
+        Ok(Bet {
+            bet: bet_uuid,
+            desc: bet_info.desc,
+            outcomes: conn.outcomes_statuses(bet_uuid)?,
+            is_open: bet_info.is_open,
+            server: bet_info.server,
+            author: bet_info.author,
+        })
+    }
+
+    /// The bets of a server that are not resolved or aborted
+    pub fn bets_of_server(&self, server: u64) -> Result<Vec<Bet>, BetError> {
+        let conn = Connection::open(&self.db_path)?;
+        let bets = conn
+            .prepare(
+                "SELECT uuid 
+                FROM Bet
+                WHERE server = ?1 AND uuid NOT IN (SELECT bet FROM ToDelete)
+                ORDER BY uuid",
+            )
+            .unwrap()
+            .query_map([server], |row| row.get::<usize, u64>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        bets.into_iter().map(|bet| self.bet(bet)).collect()
+    }
+
+    /// The bet of the server that was created last and is not resolved or aborted.
+    ///
+    /// Bets are ordered by their `BetCreated` event since uuids don't follow the creation order,
+    /// bets whose event was pruned count as older than the others and are ordered by uuid.
+    pub fn latest_bet(&self, server: u64) -> Result<Bet, BetError> {
+        let conn = Connection::open(&self.db_path)?;
+        let bet = conn
+            .prepare(
+                "SELECT uuid
+                FROM Bet
+                WHERE server = ?1 AND uuid NOT IN (SELECT bet FROM ToDelete)
+                ORDER BY (
+                    SELECT MAX(id)
+                    FROM Event
+                    WHERE kind = 'BetCreated' AND Event.bet = Bet.uuid
+                ) DESC NULLS LAST, uuid DESC
+                LIMIT 1",
+            )
+            .unwrap()
+            .query_row([server], |row| row.get::<usize, u64>(0))?;
+        self.bet(bet)
+    }
 }
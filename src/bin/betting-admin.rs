@@ -0,0 +1,14 @@
+//! Inspects and repairs a bets database, run with --help for the commands.
+use betting::admin::{self, Cli};
+use clap::Parser;
+
+fn main() {
+    let cli = Cli::parse();
+    match admin::run(&cli) {
+        Ok(output) => println!("{}", output),
+        Err(err) => {
+            eprintln!("error: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
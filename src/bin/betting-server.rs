@@ -0,0 +1,15 @@
+//! A REST/JSON API over a bets database, see `betting::server` for the routes.
+//!
+//! Usage: betting-server [database path] [address]
+use betting::{server, Bets};
+
+fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut args = std::env::args().skip(1);
+    let db_path = args.next().unwrap_or_else(|| "bets.db".to_string());
+    let addr = args.next().unwrap_or_else(|| "127.0.0.1:8080".to_string());
+    let bets = Bets::new(&db_path)?;
+    let http = tiny_http::Server::http(&addr)?;
+    println!("Serving {} on http://{}", db_path, addr);
+    server::serve(&bets, &http);
+    Ok(())
+}
@@ -0,0 +1,229 @@
+use std::time::Duration;
+use rusqlite::{Connection, Transaction, params};
+use crate::{utils, amount::Amount, metrics::Counter, AccountUpdate, BetError, BetEvent, Bets, Challenge, bet_connection::BetConnection, bet_transaction::BetTransaction};
+
+fn challenge_of(conn: &Connection, challenge: u64) -> Result<Challenge, BetError> {
+    Ok(conn
+        .prepare(
+            "SELECT server, challenger, opponent, desc, stake, expires, accepted
+            FROM Challenge
+            WHERE uuid = ?1",
+        )
+        .unwrap()
+        .query_row([challenge], |row| Ok(Challenge {
+            challenge,
+            server: row.get::<usize, u64>(0)?,
+            challenger: row.get::<usize, u64>(1)?,
+            opponent: row.get::<usize, u64>(2)?,
+            desc: row.get::<usize, String>(3)?,
+            stake: row.get::<usize, u64>(4)?,
+            expires: row.get::<usize, u64>(5)?,
+            accepted: row.get::<usize, u32>(6)? != 0,
+        }))?)
+}
+
+// Gives back the stakes of a challenge that was not accepted and deletes it
+fn refund(tx: &Transaction, challenge: &Challenge) -> Result<AccountUpdate, BetError> {
+    if tx.execute("DELETE FROM Challenge WHERE uuid = ?1 AND accepted = 0", [challenge.challenge])? != 1 {
+        return Err(BetError::NotFound);
+    }
+    tx.emit(BetEvent::ChallengeRefunded { server: challenge.server, challenge: challenge.challenge })?;
+    tx.change_balance(challenge.server, challenge.challenger, challenge.stake as i64)
+}
+
+impl Bets {
+    /// Challenges another user to a 1v1 bet, the challenger's stake is escrowed right away.
+    ///
+    /// The opponent has `expires_in` to accept, after which `expire_challenges` refunds the challenger.
+    #[allow(clippy::too_many_arguments)]
+    pub fn propose_challenge<S, A>(
+        &self,
+        challenge_uuid: u64,
+        server: u64,
+        challenger: u64,
+        opponent: u64,
+        desc: S,
+        amount: A,
+        expires_in: Duration,
+    ) -> Result<AccountUpdate, BetError>
+    where S: ToString, A: Into<Amount> {
+        if challenger == opponent {
+            return Err(BetError::NotParticipant);
+        }
+        let mut conn = Connection::open(&self.db_path)?;
+        // the balance is read in the transaction, so that concurrent stakes can't go over it together
+        let tx = conn.transaction()?;
+        // the opponent must have an account
+        tx.balance(server, opponent)?;
+        let amount = tx.stake(server, challenger, None, amount.into())?;
+        let acc_update = tx.change_balance(server, challenger, -(amount as i64))?;
+        // SQLite integers are signed
+        let expires = utils::now().saturating_add(expires_in.as_secs()).min(i64::MAX as u64);
+        tx.execute(
+            "INSERT
+            INTO Challenge (uuid, server, challenger, opponent, desc, stake, expires, accepted)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![challenge_uuid, server, challenger, opponent, desc.to_string(), amount, expires, 0],
+        )?;
+        tx.emit(BetEvent::ChallengeProposed { server, challenge: challenge_uuid, user: challenger, amount })?;
+        tx.commit()?;
+        self.count(Counter::WagersPlaced, 1);
+        self.count(Counter::CoinsWagered, amount);
+        self.publish();
+        Ok(acc_update)
+    }
+
+    pub fn challenge(&self, challenge_uuid: u64) -> Result<Challenge, BetError> {
+        let conn = Connection::open(&self.db_path)?;
+        challenge_of(&conn, challenge_uuid)
+    }
+
+    /// The opponent accepts the challenge, their stake (the same as the challenger's) is escrowed.
+    pub fn accept_challenge(&self, challenge_uuid: u64, user: u64) -> Result<AccountUpdate, BetError> {
+        let mut conn = Connection::open(&self.db_path)?;
+        let tx = conn.transaction()?;
+        let challenge = challenge_of(&tx, challenge_uuid)?;
+        if user != challenge.opponent {
+            return Err(BetError::NotParticipant);
+        }
+        if challenge.accepted {
+            return Err(BetError::BetLocked);
+        }
+        if utils::now() >= challenge.expires {
+            return Err(BetError::Expired);
+        }
+        if tx.balance(challenge.server, user)? < challenge.stake {
+            return Err(BetError::NotEnoughMoney);
+        }
+        let acc_update = tx.change_balance(challenge.server, user, -(challenge.stake as i64))?;
+        let accepted = tx.execute(
+            "UPDATE Challenge
+            SET accepted = 1
+            WHERE uuid = ?1 AND accepted = 0",
+            [challenge_uuid],
+        )?;
+        if accepted != 1 {
+            return Err(BetError::BetLocked);
+        }
+        tx.emit(BetEvent::ChallengeAccepted { server: challenge.server, challenge: challenge_uuid, user })?;
+        tx.commit()?;
+        self.count(Counter::WagersPlaced, 1);
+        self.count(Counter::CoinsWagered, challenge.stake);
+        self.publish();
+        Ok(acc_update)
+    }
+
+    /// The opponent declines the challenge, or the challenger withdraws it,
+    /// before it's accepted. The challenger is refunded.
+    pub fn decline_challenge(&self, challenge_uuid: u64, user: u64) -> Result<AccountUpdate, BetError> {
+        let mut conn = Connection::open(&self.db_path)?;
+        let tx = conn.transaction()?;
+        let challenge = challenge_of(&tx, challenge_uuid)?;
+        if user != challenge.opponent && user != challenge.challenger {
+            return Err(BetError::NotParticipant);
+        }
+        if challenge.accepted {
+            return Err(BetError::BetLocked);
+        }
+        let acc_update = refund(&tx, &challenge)?;
+        tx.commit()?;
+        self.publish();
+        Ok(acc_update)
+    }
+
+    /// Refunds the challengers of every challenge that expired without being accepted.
+    pub fn expire_challenges(&self) -> Result<Vec<AccountUpdate>, BetError> {
+        let mut conn = Connection::open(&self.db_path)?;
+        let tx = conn.transaction()?;
+        let expired = tx
+            .prepare(
+                "SELECT uuid
+                FROM Challenge
+                WHERE accepted = 0 AND expires <= ?1",
+            )
+            .unwrap()
+            .query_map([utils::now()], |row| row.get::<usize, u64>(0))?
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .map(|challenge| challenge_of(&tx, challenge))
+            .collect::<Result<Vec<_>, _>>()?;
+        let account_updates = expired
+            .iter()
+            .map(|challenge| refund(&tx, challenge))
+            .collect::<Result<Vec<_>, _>>()?;
+        tx.commit()?;
+        self.publish();
+        Ok(account_updates)
+    }
+
+    /// A participant reports who won, the challenge is settled once both agree.
+    /// Returns the account update of the winner if it was settled.
+    pub fn claim_challenge(
+        &self,
+        challenge_uuid: u64,
+        user: u64,
+        winner: u64,
+    ) -> Result<Option<AccountUpdate>, BetError> {
+        let mut conn = Connection::open(&self.db_path)?;
+        let tx = conn.transaction()?;
+        let challenge = challenge_of(&tx, challenge_uuid)?;
+        let claim_column = if user == challenge.challenger {
+            "challenger_claim"
+        } else if user == challenge.opponent {
+            "opponent_claim"
+        } else {
+            return Err(BetError::NotParticipant);
+        };
+        if winner != challenge.challenger && winner != challenge.opponent {
+            return Err(BetError::NotParticipant);
+        }
+        if !challenge.accepted {
+            return Err(BetError::NotAccepted);
+        }
+        tx.execute(
+            &format!("UPDATE Challenge SET {} = ?1 WHERE uuid = ?2", claim_column),
+            [winner, challenge_uuid],
+        )?;
+        let agreed = tx
+            .prepare(
+                "SELECT *
+                FROM Challenge
+                WHERE uuid = ?1 AND challenger_claim = opponent_claim",
+            )
+            .unwrap()
+            .exists([challenge_uuid])?;
+        let acc_update = if agreed {
+            Some(Bets::settle_challenge(&tx, &challenge, winner)?)
+        } else {
+            None
+        };
+        tx.commit()?;
+        self.publish();
+        Ok(acc_update)
+    }
+
+    /// A moderator settles an accepted challenge, whatever the participants claimed.
+    pub fn resolve_challenge(&self, challenge_uuid: u64, winner: u64) -> Result<AccountUpdate, BetError> {
+        let mut conn = Connection::open(&self.db_path)?;
+        let tx = conn.transaction()?;
+        let challenge = challenge_of(&tx, challenge_uuid)?;
+        if winner != challenge.challenger && winner != challenge.opponent {
+            return Err(BetError::NotParticipant);
+        }
+        if !challenge.accepted {
+            return Err(BetError::NotAccepted);
+        }
+        let acc_update = Bets::settle_challenge(&tx, &challenge, winner)?;
+        tx.commit()?;
+        self.publish();
+        Ok(acc_update)
+    }
+
+    fn settle_challenge(tx: &Transaction, challenge: &Challenge, winner: u64) -> Result<AccountUpdate, BetError> {
+        if tx.execute("DELETE FROM Challenge WHERE uuid = ?1 AND accepted = 1", [challenge.challenge])? != 1 {
+            return Err(BetError::NotFound);
+        }
+        tx.emit(BetEvent::ChallengeSettled { server: challenge.server, challenge: challenge.challenge, winner })?;
+        tx.change_balance(challenge.server, winner, 2 * challenge.stake as i64)
+    }
+}
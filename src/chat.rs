@@ -0,0 +1,197 @@
+//! Chat commands, like `!bet 100 1` or `!bet 50% Alice`, independent of the chat platform.
+//!
+//! | Command | Who | Effect |
+//! |---|---|---|
+//! | `!bet <amount> <outcome>` | anyone | bets on the current prediction, the amount is a number of coins, a percentage or `all` |
+//! | `!balance` | anyone | shows the user's coins |
+//! | `!predict <question> \| <outcome> \| <outcome>...` | moderators | starts a prediction |
+//! | `!lock` | moderators, the author | locks the current prediction |
+//! | `!resolve <outcome>` | moderators, the author | resolves the current prediction |
+//! | `!abort` | moderators, the author | aborts the current prediction, refunding the wagers |
+//!
+//! Outcomes are given by name, which can be a prefix or misspelled, or by their number starting at 1.
+//! The current prediction of a server is its latest bet that is not resolved or aborted,
+//! and users get an account with the server's starting balance on their first command.
+use itertools::Itertools;
+use crate::{amount::Amount, AccountUpdate, Bet, BetError, Bets};
+#[cfg(feature = "twitch")]
+pub mod twitch;
+#[cfg(feature = "discord")]
+pub mod discord;
+
+/// A chat command, see the module docs for the syntax
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChatCommand {
+    Bet { amount: Amount, outcome: String },
+    Balance,
+    Predict { desc: String, outcomes: Vec<String> },
+    Lock,
+    Resolve { outcome: String },
+    Abort,
+}
+
+/// A chat message, as seen by `respond`
+#[derive(Debug, Clone)]
+pub struct ChatMessage<'a> {
+    /// The channel or guild the message was sent in
+    pub server: u64,
+    pub user: u64,
+    /// A unique id of the message, used as the uuid of the bets it creates
+    pub id: u64,
+    /// How to address the user in replies
+    pub name: &'a str,
+    /// Whether the user can start and settle predictions
+    pub is_mod: bool,
+    pub text: &'a str,
+}
+
+fn parse_amount(amount: &str) -> Option<Amount> {
+    if ["all", "allin", "all-in"].contains(&amount.to_lowercase().as_str()) {
+        return Some(Amount::FRACTION(1.));
+    }
+    match amount.parse::<Amount>().ok()? {
+        Amount::FRACTION(part) if !(0. ..=1.).contains(&part) => None,
+        amount => Some(amount),
+    }
+}
+
+/// Parses a chat command, `None` if the message is not one,
+/// the usage of the command if it's malformed.
+pub fn parse(text: &str) -> Option<Result<ChatCommand, &'static str>> {
+    let text = text.trim().strip_prefix('!')?;
+    let (name, args) = text.split_once(' ').unwrap_or((text, ""));
+    let args = args.trim();
+    Some(match name.to_lowercase().as_str() {
+        "bet" => args
+            .split_once(' ')
+            .and_then(|(amount, outcome)| Some(ChatCommand::Bet { amount: parse_amount(amount)?, outcome: outcome.trim().to_string() }))
+            .ok_or("!bet <amount> <outcome>"),
+        "balance" => Ok(ChatCommand::Balance),
+        "predict" => {
+            let mut parts = args.split('|').map(str::trim);
+            let desc = parts.next().unwrap_or_default().to_string();
+            let outcomes = parts.map(str::to_string).collect::<Vec<_>>();
+            if desc.is_empty() || outcomes.len() < 2 || outcomes.iter().any(String::is_empty) {
+                Err("!predict <question> | <outcome> | <outcome>...")
+            } else {
+                Ok(ChatCommand::Predict { desc, outcomes })
+            }
+        }
+        "lock" => Ok(ChatCommand::Lock),
+        "resolve" if args.is_empty() => Err("!resolve <outcome>"),
+        "resolve" => Ok(ChatCommand::Resolve { outcome: args.to_string() }),
+        "abort" => Ok(ChatCommand::Abort),
+        _ => return None,
+    })
+}
+
+// The index of an outcome given by its number starting at 1, or by name with `Bet::find_outcome`,
+// an outcome named like the number still wins
+fn outcome_index(bet: &Bet, outcome: &str) -> Result<usize, BetError> {
+    if let Some(i) = bet.outcomes.iter().position(|candidate| candidate.desc.eq_ignore_ascii_case(outcome)) {
+        return Ok(i);
+    }
+    match outcome.parse::<usize>() {
+        Ok(i) if (1..=bet.outcomes.len()).contains(&i) => Ok(i - 1),
+        _ => bet.find_outcome(outcome),
+    }
+}
+
+fn outcomes_text(bet: &Bet) -> String {
+    bet.outcomes.iter().enumerate().map(|(i, outcome)| format!("{}. {}", i + 1, outcome.desc)).join(" | ")
+}
+
+fn payout_text(updates: &[AccountUpdate]) -> String {
+    let paid = updates.iter().filter(|update| update.diff > 0).collect::<Vec<_>>();
+    format!("{} users got {} coins", paid.len(), paid.iter().map(|update| update.diff).sum::<i64>())
+}
+
+// The current bet, if the user can lock, resolve or abort it
+fn settled_bet(bets: &Bets, message: &ChatMessage) -> Result<Option<Bet>, BetError> {
+    let bet = bets.latest_bet(message.server)?;
+    Ok((message.is_mod || bet.author == message.user).then_some(bet))
+}
+
+fn ensure_account(bets: &Bets, server: u64, user: u64) -> Result<(), BetError> {
+    match bets.open_account(server, user) {
+        Ok(_) | Err(BetError::AlreadyExists) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+fn error_text(err: &BetError) -> String {
+    match err {
+        BetError::NotFound => "there's no prediction running".to_string(),
+        BetError::NotEnoughMoney => "you don't have enough coins".to_string(),
+        BetError::BetLocked => "bets are locked".to_string(),
+        BetError::AmbiguousOutcome(candidates) => format!("did you mean {} ?", candidates.join(" or ")),
+        BetError::MultiOpt(options) => format!("you already bet on {}", options[0]),
+        err => err.to_string(),
+    }
+}
+
+fn execute(bets: &Bets, message: &ChatMessage, command: ChatCommand) -> Result<String, BetError> {
+    let (server, user, name) = (message.server, message.user, message.name);
+    ensure_account(bets, server, user)?;
+    let unknown_outcome = |bet: &Bet| format!("{}, the outcomes are {}", name, outcomes_text(bet));
+    let not_allowed = |action: &str| format!("{}, only moderators can {}", name, action);
+    Ok(match command {
+        ChatCommand::Bet { amount, outcome } => {
+            let bet = bets.latest_bet(server)?;
+            let outcome = match outcome_index(&bet, &outcome) {
+                Err(BetError::NotFound) => return Ok(unknown_outcome(&bet)),
+                outcome => outcome?,
+            };
+            let (update, bet) = bets.bet_on(bet.bet, outcome, user, amount)?;
+            format!(
+                "{} bet {} on {}, {} coins left",
+                name, -update.diff, bet.outcomes[outcome].desc, update.balance
+            )
+        }
+        ChatCommand::Balance => {
+            let account = bets.account(server, user)?;
+            format!("{} has {} coins, {} in bets", name, account.balance, account.in_bet)
+        }
+        ChatCommand::Predict { .. } if !message.is_mod => not_allowed("start predictions"),
+        ChatCommand::Predict { desc, outcomes } => {
+            bets.create_bet(message.id, server, user, &desc, &outcomes)?;
+            let bet = bets.bet(message.id)?;
+            format!("Prediction: {} {} - bet with !bet <amount> <outcome>", bet.desc, outcomes_text(&bet))
+        }
+        ChatCommand::Lock => {
+            let Some(bet) = settled_bet(bets, message)? else {
+                return Ok(not_allowed("settle predictions"));
+            };
+            bets.lock_bet(bet.bet)?;
+            format!("Bets are locked: {}", bet.desc)
+        }
+        ChatCommand::Resolve { outcome } => {
+            let Some(bet) = settled_bet(bets, message)? else {
+                return Ok(not_allowed("settle predictions"));
+            };
+            let outcome = match outcome_index(&bet, &outcome) {
+                Err(BetError::NotFound) => return Ok(unknown_outcome(&bet)),
+                outcome => outcome?,
+            };
+            let updates = bets.resolve(bet.bet, outcome)?;
+            format!("{} won! {}", bet.outcomes[outcome].desc, payout_text(&updates))
+        }
+        ChatCommand::Abort => {
+            let Some(bet) = settled_bet(bets, message)? else {
+                return Ok(not_allowed("settle predictions"));
+            };
+            let updates = bets.abort_bet(bet.bet)?;
+            format!("Prediction aborted, {}", payout_text(&updates))
+        }
+    })
+}
+
+/// The reply to a message, `None` if it's not a command.
+/// Replies are a single line, failures are explained to the user rather than returned.
+pub fn respond(bets: &Bets, message: &ChatMessage) -> Option<String> {
+    Some(match parse(message.text)? {
+        Ok(command) => execute(bets, message, command)
+            .unwrap_or_else(|err| format!("{}, {}", message.name, error_text(&err))),
+        Err(usage) => format!("{}, usage: {}", message.name, usage),
+    })
+}
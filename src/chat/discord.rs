@@ -0,0 +1,380 @@
+//! Discord interactions: the bets are posted as embeds with a button per outcome,
+//! and the slash commands and buttons are parsed back into `Bets` calls.
+//!
+//! The server is the guild id and the user is the Discord user id. Clicking an outcome
+//! opens a modal asking for the amount, members with the Manage Server permission
+//! and the author of a bet can lock, resolve and abort it.
+//! `commands` gives the slash commands to register, `respond` answers an interaction payload.
+use itertools::Itertools;
+use serde_json::{json, Value};
+use super::{ensure_account, error_text};
+use crate::{amount::Amount, render::{Format, Style}, AccountUpdate, Bet, BetError, Bets};
+
+const MANAGE_GUILD: u64 = 1 << 5;
+const ADMINISTRATOR: u64 = 1 << 3;
+const EPHEMERAL: u64 = 1 << 6;
+// Discord allows 25 fields per embed and 25 buttons per message, 5 per row
+const MAX_OUTCOMES: usize = 25;
+
+/// An outcome, picked with a button or typed in a slash command
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutcomeChoice {
+    Index(usize),
+    /// The number of an outcome starting at 1, or its name as in `Bet::find_outcome`
+    Name(String),
+}
+
+/// What an interaction asks for, commands without a `bet` option act on the latest bet of the guild
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Ping,
+    Predict { desc: String, outcomes: Vec<String> },
+    /// An outcome button was clicked, the amount is asked in a modal
+    ChooseAmount { bet: u64, outcome: usize },
+    Bet { bet: Option<u64>, outcome: OutcomeChoice, amount: Amount },
+    Balance,
+    Lock { bet: Option<u64> },
+    Resolve { bet: Option<u64>, outcome: OutcomeChoice },
+    Abort { bet: Option<u64> },
+}
+
+/// An interaction payload sent by Discord
+#[derive(Debug, Clone, PartialEq)]
+pub struct Interaction {
+    /// The id of the interaction, used as the uuid of the bets it creates
+    pub id: u64,
+    pub server: u64,
+    pub user: u64,
+    /// Whether the member can manage the guild
+    pub is_mod: bool,
+    pub action: Action,
+}
+
+/// The slash commands, to register with the application commands endpoint
+pub fn commands() -> Value {
+    let option = |name: &str, description: &str, required: bool| {
+        json!({ "type": 3, "name": name, "description": description, "required": required })
+    };
+    let bet_option = option("bet", "The id of the bet, the latest one by default", false);
+    json!([
+        {
+            "name": "predict",
+            "description": "Start a bet",
+            "options": [
+                option("question", "What to bet on", true),
+                option("outcomes", "Up to 25 outcomes, separated by |", true),
+            ],
+            "default_member_permissions": MANAGE_GUILD.to_string(),
+        },
+        {
+            "name": "bet",
+            "description": "Bet on an outcome",
+            "options": [
+                option("amount", "A number of coins, a percentage like 50% or all", true),
+                option("outcome", "The name or number of the outcome", true),
+                bet_option,
+            ],
+        },
+        { "name": "balance", "description": "Show your coins" },
+        { "name": "lock", "description": "Lock a bet", "options": [bet_option] },
+        {
+            "name": "resolve",
+            "description": "Resolve a bet with the winning outcome",
+            "options": [option("outcome", "The name or number of the outcome", true), bet_option],
+        },
+        { "name": "abort", "description": "Abort a bet, refunding the wagers", "options": [bet_option] },
+    ])
+}
+
+fn snowflake(value: &Value) -> Option<u64> {
+    value.as_str()?.parse().ok()
+}
+
+fn parse_amount(amount: &str) -> Result<Amount, &'static str> {
+    super::parse_amount(amount).ok_or("the amount must be a number of coins, a percentage like 50% or all")
+}
+
+// The bet and outcome in the custom id of a button or modal, like `outcome:{bet}:{outcome}`
+fn custom_ids(data: &Value, prefix: &str) -> Option<(u64, usize)> {
+    let (bet, outcome) = data["custom_id"].as_str()?.strip_prefix(prefix)?.split_once(':')?;
+    Some((bet.parse().ok()?, outcome.parse().ok()?))
+}
+
+/// Parses an interaction payload, the error says what's wrong with it.
+pub fn parse_interaction(payload: &Value) -> Result<Interaction, &'static str> {
+    let user = payload["member"]["user"]["id"].as_str().or(payload["user"]["id"].as_str());
+    let interaction = |action| {
+        Ok(Interaction {
+            id: snowflake(&payload["id"]).ok_or("missing interaction id")?,
+            server: snowflake(&payload["guild_id"]).ok_or("bets only work in servers")?,
+            user: user.and_then(|user| user.parse().ok()).ok_or("missing user")?,
+            is_mod: payload["member"]["permissions"]
+                .as_str()
+                .and_then(|permissions| permissions.parse::<u64>().ok())
+                .is_some_and(|permissions| permissions & (MANAGE_GUILD | ADMINISTRATOR) != 0),
+            action,
+        })
+    };
+    let data = &payload["data"];
+    match payload["type"].as_u64() {
+        // PING, sent when the interactions endpoint is registered
+        Some(1) => Ok(Interaction { id: 0, server: 0, user: 0, is_mod: false, action: Action::Ping }),
+        // APPLICATION_COMMAND
+        Some(2) => {
+            let option = |name: &str| {
+                data["options"]
+                    .as_array()
+                    .and_then(|options| options.iter().find(|option| option["name"] == name))
+                    .and_then(|option| option["value"].as_str())
+            };
+            let bet = match option("bet") {
+                Some(bet) => Some(bet.trim().parse().map_err(|_| "the bet must be an id")?),
+                None => None,
+            };
+            let outcome = || option("outcome").map(|outcome| OutcomeChoice::Name(outcome.trim().to_string())).ok_or("missing outcome");
+            interaction(match data["name"].as_str() {
+                Some("predict") => {
+                    let outcomes = option("outcomes").unwrap_or_default().split('|').map(str::trim).collect::<Vec<_>>();
+                    if outcomes.len() < 2 || outcomes.iter().any(|outcome| outcome.is_empty()) {
+                        return Err("a bet needs at least 2 outcomes, separated by |");
+                    }
+                    if outcomes.len() > MAX_OUTCOMES {
+                        return Err("a bet can have at most 25 outcomes");
+                    }
+                    Action::Predict {
+                        desc: option("question").ok_or("missing question")?.to_string(),
+                        outcomes: outcomes.into_iter().map(str::to_string).collect(),
+                    }
+                }
+                Some("bet") => Action::Bet { bet, outcome: outcome()?, amount: parse_amount(option("amount").unwrap_or_default())? },
+                Some("balance") => Action::Balance,
+                Some("lock") => Action::Lock { bet },
+                Some("resolve") => Action::Resolve { bet, outcome: outcome()? },
+                Some("abort") => Action::Abort { bet },
+                _ => return Err("unknown command"),
+            })
+        }
+        // MESSAGE_COMPONENT, the outcome buttons
+        Some(3) => {
+            let (bet, outcome) = custom_ids(data, "outcome:").ok_or("unknown button")?;
+            interaction(Action::ChooseAmount { bet, outcome })
+        }
+        // MODAL_SUBMIT, the amount of a wager
+        Some(5) => {
+            let (bet, outcome) = custom_ids(data, "wager:").ok_or("unknown modal")?;
+            let amount = data["components"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .flat_map(|row| row["components"].as_array().into_iter().flatten())
+                .find(|input| input["custom_id"] == "amount")
+                .and_then(|input| input["value"].as_str())
+                .ok_or("missing amount")?;
+            interaction(Action::Bet { bet: Some(bet), outcome: OutcomeChoice::Index(outcome), amount: parse_amount(amount)? })
+        }
+        _ => Err("unknown interaction"),
+    }
+}
+
+// Embeds are Markdown, users are mentioned
+fn style() -> Style {
+    Style { format: Format::Markdown, user_name: |user| format!("<@{}>", user), ..Style::default() }
+}
+
+/// An embed showing a bet, with a pool bar per outcome
+pub fn bet_embed(bet: &Bet) -> Value {
+    let style = style();
+    let pools = bet.outcomes.iter().map(|outcome| outcome.wagers.iter().map(|(_, amount)| amount).sum::<u64>()).collect::<Vec<_>>();
+    let total = pools.iter().sum::<u64>();
+    let fields = bet
+        .outcomes
+        .iter()
+        .zip(&pools)
+        .enumerate()
+        .take(MAX_OUTCOMES)
+        .map(|(i, (outcome, pool))| {
+            let share = if total > 0 { *pool as f64 / total as f64 } else { 0. };
+            json!({
+                "name": format!("{}. {}", i + 1, outcome.desc),
+                "value": format!(
+                    "{} {:.0}% · {} · {} bettors",
+                    style.bar(share), share * 100., style.amount(*pool), outcome.wagers.len()
+                ),
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "title": bet.desc,
+        "fields": fields,
+        "footer": { "text": format!("Bet {} · {}", bet.bet, if bet.is_open { "open" } else { "locked" }) },
+        "color": if bet.is_open { 0x57f287 } else { 0xed4245 },
+    })
+}
+
+/// A message showing a bet, with a button per outcome while it's open
+pub fn bet_message(bet: &Bet) -> Value {
+    let rows = bet
+        .outcomes
+        .iter()
+        .enumerate()
+        .take(MAX_OUTCOMES)
+        .map(|(i, outcome)| {
+            json!({
+                "type": 2,
+                "style": 1,
+                "label": format!("{}. {}", i + 1, outcome.desc).chars().take(80).collect::<String>(),
+                "custom_id": format!("outcome:{}:{}", bet.bet, i),
+                "disabled": !bet.is_open,
+            })
+        })
+        .chunks(5)
+        .into_iter()
+        .map(|buttons| json!({ "type": 1, "components": buttons.collect::<Vec<_>>() }))
+        .collect::<Vec<_>>();
+    json!({ "embeds": [bet_embed(bet)], "components": rows })
+}
+
+/// An embed listing balance changes, like the payouts of a resolution
+pub fn account_updates_embed(title: &str, updates: &[AccountUpdate]) -> Value {
+    let style = style();
+    let description = match updates {
+        [] => "No balance changed".to_string(),
+        updates => updates
+            .iter()
+            .map(|update| format!("{} {:+} → {}", (style.user_name)(update.user), update.diff, style.amount(update.balance)))
+            .join("\n"),
+    };
+    json!({ "title": title, "description": description })
+}
+
+fn message(data: Value) -> Value {
+    // CHANNEL_MESSAGE_WITH_SOURCE
+    json!({ "type": 4, "data": data })
+}
+
+fn ephemeral(content: &str) -> Value {
+    message(json!({ "content": content, "flags": EPHEMERAL }))
+}
+
+// The chat replies start with the user's name, the ephemeral messages with the error itself
+fn capitalize(text: &str) -> String {
+    let mut chars = text.chars();
+    chars.next().map(|c| c.to_uppercase().chain(chars).collect()).unwrap_or_default()
+}
+
+fn pick_outcome(bet: &Bet, outcome: &OutcomeChoice) -> Result<usize, BetError> {
+    match outcome {
+        OutcomeChoice::Index(i) if *i < bet.outcomes.len() => Ok(*i),
+        OutcomeChoice::Index(_) => Err(BetError::NotFound),
+        OutcomeChoice::Name(name) => super::outcome_index(bet, name),
+    }
+}
+
+fn execute(bets: &Bets, interaction: Interaction) -> Result<Value, BetError> {
+    let (server, user) = (interaction.server, interaction.user);
+    let bet_of = |bet: Option<u64>| -> Result<Bet, BetError> {
+        let bet = match bet {
+            Some(bet) => bets.bet(bet)?,
+            None => bets.latest_bet(server)?,
+        };
+        // a bet id from another guild is as good as missing
+        if bet.server != server {
+            return Err(BetError::NotFound);
+        }
+        Ok(bet)
+    };
+    let can_settle = |bet: &Bet| interaction.is_mod || bet.author == user;
+    let unknown_outcome = |bet: &Bet| {
+        let outcomes = bet.outcomes.iter().enumerate().map(|(i, outcome)| format!("{}. {}", i + 1, outcome.desc)).join(", ");
+        ephemeral(&format!("The outcomes are {}", outcomes))
+    };
+    let not_allowed = || ephemeral("Only the author of the bet and server managers can do that");
+    Ok(match interaction.action {
+        // PONG
+        Action::Ping => json!({ "type": 1 }),
+        Action::Predict { .. } if !interaction.is_mod => ephemeral("Only server managers can start bets"),
+        Action::Predict { desc, outcomes } => {
+            bets.create_bet(interaction.id, server, user, &desc, &outcomes)?;
+            message(bet_message(&bets.bet(interaction.id)?))
+        }
+        Action::ChooseAmount { bet, outcome } => {
+            let bet = bet_of(Some(bet))?;
+            if outcome >= bet.outcomes.len() {
+                return Ok(unknown_outcome(&bet));
+            }
+            // MODAL
+            json!({
+                "type": 9,
+                "data": {
+                    "custom_id": format!("wager:{}:{}", bet.bet, outcome),
+                    "title": format!("Bet on {}", bet.outcomes[outcome].desc).chars().take(45).collect::<String>(),
+                    "components": [{
+                        "type": 1,
+                        "components": [{
+                            "type": 4,
+                            "custom_id": "amount",
+                            "label": "Amount (coins, 50% or all)",
+                            "style": 1,
+                            "required": true,
+                        }],
+                    }],
+                },
+            })
+        }
+        Action::Bet { bet, outcome, amount } => {
+            let bet = bet_of(bet)?;
+            let outcome = match pick_outcome(&bet, &outcome) {
+                Err(BetError::NotFound) => return Ok(unknown_outcome(&bet)),
+                outcome => outcome?,
+            };
+            ensure_account(bets, server, user)?;
+            let (update, bet) = bets.bet_on(bet.bet, outcome, user, amount)?;
+            message(json!({
+                "content": format!("<@{}> bet {} on {}, {} coins left", user, -update.diff, bet.outcomes[outcome].desc, update.balance),
+                "embeds": [bet_embed(&bet)],
+            }))
+        }
+        Action::Balance => {
+            ensure_account(bets, server, user)?;
+            let account = bets.account(server, user)?;
+            ephemeral(&format!("You have {} coins, {} in bets", account.balance, account.in_bet))
+        }
+        Action::Lock { bet } => {
+            let bet = bet_of(bet)?;
+            if !can_settle(&bet) {
+                return Ok(not_allowed());
+            }
+            bets.lock_bet(bet.bet)?;
+            message(bet_message(&bets.bet(bet.bet)?))
+        }
+        Action::Resolve { bet, outcome } => {
+            let bet = bet_of(bet)?;
+            if !can_settle(&bet) {
+                return Ok(not_allowed());
+            }
+            let outcome = match pick_outcome(&bet, &outcome) {
+                Err(BetError::NotFound) => return Ok(unknown_outcome(&bet)),
+                outcome => outcome?,
+            };
+            let updates = bets.resolve(bet.bet, outcome)?;
+            let title = format!("{}: {} won", bet.desc, bet.outcomes[outcome].desc);
+            message(json!({ "embeds": [account_updates_embed(&title, &updates)] }))
+        }
+        Action::Abort { bet } => {
+            let bet = bet_of(bet)?;
+            if !can_settle(&bet) {
+                return Ok(not_allowed());
+            }
+            let updates = bets.abort_bet(bet.bet)?;
+            message(json!({ "embeds": [account_updates_embed(&format!("{}: aborted", bet.desc), &updates)] }))
+        }
+    })
+}
+
+/// The response to an interaction payload, failures are sent back as ephemeral messages.
+pub fn respond(bets: &Bets, payload: &Value) -> Value {
+    match parse_interaction(payload) {
+        Ok(interaction) => execute(bets, interaction).unwrap_or_else(|err| ephemeral(&capitalize(&error_text(&err)))),
+        Err(reason) => ephemeral(reason),
+    }
+}
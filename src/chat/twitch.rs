@@ -0,0 +1,146 @@
+//! Answers chat commands in Twitch channels, over Twitch's IRC interface.
+//!
+//! The server of a message is the id of its channel and the user is the Twitch user id,
+//! moderators and the broadcaster can start and settle predictions.
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpStream, ToSocketAddrs},
+};
+use super::{respond, ChatMessage};
+use crate::Bets;
+
+/// The address of Twitch's IRC server, without TLS
+pub const TWITCH_IRC: &str = "irc.chat.twitch.tv:6667";
+
+/// A connection to Twitch chat, logged in and joined to the channels
+pub struct Twitch {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+// A chat message, with the IRCv3 tags Twitch adds to it
+struct Privmsg<'a> {
+    tags: Vec<(&'a str, String)>,
+    channel: &'a str,
+    text: &'a str,
+}
+
+impl Privmsg<'_> {
+    fn tag(&self, name: &str) -> Option<&str> {
+        self.tags.iter().find(|(tag, _)| *tag == name).map(|(_, value)| value.as_str())
+    }
+}
+
+// Tag values escape spaces, semicolons and backslashes
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('s') => unescaped.push(' '),
+            Some(':') => unescaped.push(';'),
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            Some(escaped) => unescaped.push(escaped),
+            None => break,
+        }
+    }
+    unescaped
+}
+
+// The id tag of a message is a UUID, its low 63 bits make a bet uuid that fits in SQLite
+fn message_id(id: &str) -> Option<u64> {
+    let id = u128::from_str_radix(&id.replace('-', ""), 16).ok()?;
+    Some(id as u64 & i64::MAX as u64)
+}
+
+fn privmsg(line: &str) -> Option<Privmsg<'_>> {
+    let (tags, line) = match line.strip_prefix('@') {
+        Some(line) => line.split_once(' ')?,
+        None => ("", line),
+    };
+    // skip the prefix naming the sender
+    let line = match line.strip_prefix(':') {
+        Some(line) => line.split_once(' ')?.1,
+        None => line,
+    };
+    let (channel, text) = line.strip_prefix("PRIVMSG ")?.split_once(" :")?;
+    let tags = tags
+        .split(';')
+        .filter_map(|tag| tag.split_once('='))
+        .map(|(tag, value)| (tag, unescape(value)))
+        .collect();
+    Some(Privmsg { tags, channel: channel.trim(), text })
+}
+
+impl Twitch {
+    /// Connects to an IRC server like `TWITCH_IRC` and joins the channels, given by name.
+    /// The token is an OAuth token with the chat:read and chat:edit scopes.
+    pub fn connect<A: ToSocketAddrs>(addr: A, nick: &str, token: &str, channels: &[&str]) -> io::Result<Self> {
+        let writer = TcpStream::connect(addr)?;
+        let mut twitch = Twitch { reader: BufReader::new(writer.try_clone()?), writer };
+        twitch.send("CAP REQ :twitch.tv/tags twitch.tv/commands")?;
+        twitch.send(&format!("PASS oauth:{}", token.trim_start_matches("oauth:")))?;
+        twitch.send(&format!("NICK {}", nick.to_lowercase()))?;
+        for channel in channels {
+            twitch.send(&format!("JOIN #{}", channel.trim_start_matches('#').to_lowercase()))?;
+        }
+        Ok(twitch)
+    }
+
+    fn send(&mut self, line: &str) -> io::Result<()> {
+        write!(self.writer, "{}\r\n", line)?;
+        self.writer.flush()
+    }
+
+    /// Answers the chat commands until the server closes the connection or asks to reconnect.
+    /// Messages without an id are skipped, since they would have no uuid for the bets they create.
+    pub fn run(&mut self, bets: &Bets) -> io::Result<()> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let line = line.trim_end();
+            if let Some(server) = line.strip_prefix("PING ") {
+                self.send(&format!("PONG {}", server))?;
+                continue;
+            }
+            if line.ends_with(" RECONNECT") {
+                return Ok(());
+            }
+            let Some(message) = privmsg(line) else {
+                continue;
+            };
+            let (Some(server), Some(user)) = (
+                message.tag("room-id").and_then(|id| id.parse().ok()),
+                message.tag("user-id").and_then(|id| id.parse().ok()),
+            ) else {
+                continue;
+            };
+            // it would have no uuid for the bets it creates
+            let Some(id) = message.tag("id").and_then(message_id) else {
+                continue;
+            };
+            let badges = message.tag("badges").unwrap_or_default();
+            let chat_message = ChatMessage {
+                server,
+                user,
+                id,
+                name: message.tag("display-name").unwrap_or("someone"),
+                is_mod: message.tag("mod") == Some("1") || badges.split(',').any(|badge| badge.starts_with("broadcaster/")),
+                text: message.text,
+            };
+            if let Some(reply) = respond(bets, &chat_message) {
+                // names and descriptions are written by users, a line break would start another command
+                let reply = reply.replace(['\r', '\n'], " ");
+                self.send(&format!("PRIVMSG {} :{}", message.channel, reply))?;
+            }
+        }
+    }
+}
@@ -1,11 +1,13 @@
 use thiserror::Error;
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Position {
     pub outcome: usize,
     pub amount: u64
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AccountUpdate {
     pub server: u64,
     pub user: u64,
@@ -13,6 +15,7 @@ pub struct AccountUpdate {
     pub balance: u64,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct BetInfo {
     pub desc: String,
     pub server: u64,
@@ -20,6 +23,8 @@ pub struct BetInfo {
     pub is_open: bool
 }
 
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Bet {
     pub bet: u64,
     pub server: u64,
@@ -29,18 +34,151 @@ pub struct Bet {
     pub is_open: bool
 }
 
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Outcome {
     pub desc: String,
     // [(user, amount), ]
+    #[cfg_attr(feature = "serde", serde(with = "wagers"))]
     pub wagers: Vec<(u64, u64)>,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ParlayLeg {
+    pub bet: u64,
+    pub outcome: usize,
+    // None while the bet is unresolved, 1 if it was aborted, what the leg multiplied the value by otherwise
+    pub odds: Option<f64>,
+    // the value of the parlay that rode on the leg and what it became, once the bet is resolved
+    pub stake: Option<u64>,
+    pub payout: Option<u64>,
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Parlay {
+    pub parlay: u64,
+    pub server: u64,
+    pub user: u64,
+    pub stake: u64,
+    // the stake, times the odds of the legs won so far
+    pub value: u64,
+    // None while the parlay is pending, 0 if it was lost
+    pub payout: Option<u64>,
+    pub legs: Vec<ParlayLeg>,
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Challenge {
+    pub challenge: u64,
+    pub server: u64,
+    pub challenger: u64,
+    pub opponent: u64,
+    pub desc: String,
+    // the stake of each side, the winner takes twice that
+    pub stake: u64,
+    // unix timestamp (in seconds) after which the challenge can no longer be accepted
+    pub expires: u64,
+    pub accepted: bool,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AccountStatus {
     pub user: u64,
     pub balance: u64,
     pub in_bet: u64,
 }
 
+/// Bounds on the stake of a wager, the most restrictive of the server's and the bet's apply
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(default))]
+pub struct StakeLimits {
+    /// The smallest stake, in coins
+    pub min_stake: Option<u64>,
+    /// The largest stake, in coins
+    pub max_stake: Option<u64>,
+    /// The smallest stake as a fraction of the user's balance, between 0 and 1
+    pub min_fraction: Option<f32>,
+    /// The largest stake as a fraction of the user's balance, between 0 and 1
+    pub max_fraction: Option<f32>,
+    /// The largest part of a bet's pool a single user can own, between 0 and 1,
+    /// it only applies once someone else bet
+    pub max_pool_share: Option<f32>,
+}
+
+impl StakeLimits {
+    pub(crate) fn validate(&self) -> Result<(), BetError> {
+        if [self.min_fraction, self.max_fraction, self.max_pool_share]
+            .iter()
+            .flatten()
+            .any(|fraction| !(0. ..=1.).contains(fraction))
+        {
+            return Err(BetError::InvalidConfig("the stake fractions must be between 0 and 1"));
+        }
+        if let (Some(min_stake), Some(max_stake)) = (self.min_stake, self.max_stake) {
+            if max_stake < min_stake {
+                return Err(BetError::InvalidConfig("the maximum stake must be at least the minimum stake"));
+            }
+        }
+        if let (Some(min_fraction), Some(max_fraction)) = (self.min_fraction, self.max_fraction) {
+            if max_fraction < min_fraction {
+                return Err(BetError::InvalidConfig("the maximum fraction must be at least the minimum fraction"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The settings of a server, every operation on the server follows them
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(default))]
+pub struct ServerConfig {
+    /// The balance of new accounts opened with `open_account`
+    pub starting_balance: u64,
+    /// The part of the gains that is taken when a bet is resolved, between 0 and 1
+    pub fee: f64,
+    /// The bounds on the stakes of every wager of the server
+    pub limits: StakeLimits,
+    /// Whether amounts can be a fraction of the balance, like "50%"
+    pub allow_fractions: bool,
+    /// The amount given to every account by `pay_income`
+    pub income: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            starting_balance: 100,
+            fee: 0.10,
+            limits: StakeLimits::default(),
+            allow_fractions: true,
+            income: 10,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub(crate) fn validate(&self) -> Result<(), BetError> {
+        if !(0. ..=1.).contains(&self.fee) {
+            return Err(BetError::InvalidConfig("the fee must be between 0 and 1"));
+        }
+        self.limits.validate()
+    }
+}
+
+/// What to do when rolling back a resolution while a winner
+/// no longer has the coins they won
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum ClawbackPolicy {
+    /// Abort the rollback with `BetError::NotEnoughMoney`
+    Refuse,
+    /// Take back whatever is left, the missing coins are taken out of what the winner
+    /// gets back on the bet (wagers, ranked wagers and shares). The rollback is refused
+    /// with `BetError::NotEnoughMoney` if that's not enough, since it would create coins.
+    Partial,
+}
+
 #[derive(Error, Debug)]
 pub enum BetError {
     #[error("betting on multiple option")]
@@ -53,10 +191,129 @@ pub enum BetError {
     BetLocked,
     #[error("uuid already exists")]
     AlreadyExists,
+    #[error("a parlay needs at least 2 legs on distinct open bets of the same server, without guess, ranked or market bets")]
+    InvalidParlay,
+    #[error("a parlay with a leg on this bet was lost on another bet since")]
+    ParlayLost,
+    #[error("the winning outcome is not an outcome of the bet")]
+    InvalidOutcome,
+    #[error("numeric bounds must be finite and strictly increasing")]
+    InvalidBounds,
+    #[error("the ranking must list distinct outcomes of a ranked bet")]
+    InvalidRanking,
+    #[error("guess bets take finite guesses with guess_on and are resolved with resolve_numeric")]
+    InvalidGuess,
+    #[error("the liquidity of a market must be positive")]
+    InvalidLiquidity,
+    #[error("markets are traded with buy_shares and sell_shares")]
+    MarketTrade,
+    #[error("insufficient shares")]
+    NotEnoughShares,
+    #[error("not a participant of the challenge")]
+    NotParticipant,
+    #[error("the challenge was not accepted")]
+    NotAccepted,
+    #[error("the challenge expired")]
+    Expired,
+    #[error("invalid server config: {0}")]
+    InvalidConfig(&'static str),
+    #[error("the stake must be between {min} and {}", max.map_or("anything".to_string(), |max| max.to_string()))]
+    StakeOutOfRange { min: u64, max: Option<u64> },
+    #[error("the stake limits leave no possible stake")]
+    NoStakeAllowed,
+    #[error("fractions of the balance are not allowed on this server")]
+    FractionsNotAllowed,
+    #[error("a fraction of the balance must be between 0% and 100%")]
+    InvalidFraction,
+    #[error("the outcome could be {}", .0.join(" or "))]
+    AmbiguousOutcome(Vec<String>),
+    #[error("the idempotency key was used for another operation")]
+    KeyReused,
+    #[error("invalid transfer: {0}")]
+    Transfer(String),
+    #[error("backup failed: {0}")]
+    Backup(String),
     #[error("rusqlite error: {0}")]
     InternalError(rusqlite::Error),
 }
 
+impl BetError {
+    /// A stable identifier of the error, like `not_enough_money`
+    pub fn code(&self) -> &'static str {
+        match self {
+            BetError::NotFound => "not_found",
+            BetError::AlreadyExists => "already_exists",
+            BetError::BetLocked => "bet_locked",
+            BetError::MultiOpt(_) => "multiple_options",
+            BetError::KeyReused => "key_reused",
+            BetError::NotEnoughMoney => "not_enough_money",
+            BetError::NotEnoughShares => "not_enough_shares",
+            BetError::StakeOutOfRange { .. } => "stake_out_of_range",
+            BetError::NoStakeAllowed => "no_stake_allowed",
+            BetError::AmbiguousOutcome(_) => "ambiguous_outcome",
+            BetError::FractionsNotAllowed => "fractions_not_allowed",
+            BetError::InvalidFraction => "invalid_fraction",
+            BetError::Expired => "expired",
+            BetError::NotAccepted => "not_accepted",
+            BetError::NotParticipant => "not_participant",
+            BetError::InvalidParlay => "invalid_parlay",
+            BetError::ParlayLost => "parlay_lost",
+            BetError::InvalidOutcome => "invalid_outcome",
+            BetError::InvalidBounds => "invalid_bounds",
+            BetError::InvalidRanking => "invalid_ranking",
+            BetError::InvalidGuess => "invalid_guess",
+            BetError::InvalidLiquidity => "invalid_liquidity",
+            BetError::MarketTrade => "market_trade",
+            BetError::InvalidConfig(_) => "invalid_config",
+            BetError::Transfer(_) => "invalid_transfer",
+            BetError::Backup(_) => "backup_failed",
+            BetError::InternalError(_) => "internal_error",
+        }
+    }
+}
+
+// Wagers are written as `[{"user", "amount"}]` rather than pairs
+#[cfg(feature = "serde")]
+mod wagers {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    struct Wager {
+        user: u64,
+        amount: u64,
+    }
+
+    pub fn serialize<S: Serializer>(wagers: &[(u64, u64)], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(wagers.iter().map(|(user, amount)| Wager { user: *user, amount: *amount }))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<(u64, u64)>, D::Error> {
+        Ok(Vec::<Wager>::deserialize(deserializer)?.into_iter().map(|wager| (wager.user, wager.amount)).collect())
+    }
+}
+
+/// Written as `{"code", "message"}` with the details of the error, like `"min"` and `"max"` for
+/// `StakeOutOfRange`. There's no `Deserialize` since database errors can't be rebuilt.
+#[cfg(feature = "serde")]
+impl serde::Serialize for BetError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("code", self.code())?;
+        map.serialize_entry("message", &self.to_string())?;
+        match self {
+            BetError::MultiOpt(options) => map.serialize_entry("options", options)?,
+            BetError::AmbiguousOutcome(candidates) => map.serialize_entry("candidates", candidates)?,
+            BetError::StakeOutOfRange { min, max } => {
+                map.serialize_entry("min", min)?;
+                map.serialize_entry("max", max)?;
+            }
+            _ => {}
+        }
+        map.end()
+    }
+}
+
 impl From<rusqlite::Error> for BetError {
     fn from(err: rusqlite::Error) -> Self {
         // the only error we want to separate is the unique constraint violation
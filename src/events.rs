@@ -0,0 +1,301 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+use rusqlite::{Connection, Row, params};
+use crate::{AccountUpdate, BetError, Bets, bet_connection::BetConnection};
+
+/// A change to the bets or accounts, events are numbered in the order they were committed.
+///
+/// With the `serde` feature, events are written as their fields with their `"kind"`, like `"bet_created"`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(tag = "kind", rename_all = "snake_case")
+)]
+pub enum BetEvent {
+    BetCreated { server: u64, bet: u64, author: u64 },
+    WagerPlaced { server: u64, bet: u64, user: u64, outcome: usize, amount: u64 },
+    /// A wager on a pick of a ranked bet other than the winner, see `RankedPick` for the pools
+    RankedWagerPlaced { server: u64, bet: u64, user: u64, pool: usize, amount: u64 },
+    /// Shares of a market outcome bought, or sold if `shares` is negative
+    SharesTraded { server: u64, bet: u64, user: u64, outcome: usize, shares: i64 },
+    BetLocked { server: u64, bet: u64 },
+    BetResolved { server: u64, bet: u64, outcome: usize },
+    BetAborted { server: u64, bet: u64 },
+    /// The resolution of the bet was rolled back, it's locked again
+    BetRolledBack { server: u64, bet: u64 },
+    BalanceChanged(AccountUpdate),
+    /// A parlay placed by `user` with a stake of `amount`, see `Bets::parlay` for its legs
+    ParlayPlaced { server: u64, parlay: u64, user: u64, amount: u64 },
+    /// A 1v1 challenge proposed by `user`, who escrowed `amount`
+    ChallengeProposed { server: u64, challenge: u64, user: u64, amount: u64 },
+    /// The opponent `user` accepted the challenge and escrowed the same amount
+    ChallengeAccepted { server: u64, challenge: u64, user: u64 },
+    ChallengeSettled { server: u64, challenge: u64, winner: u64 },
+    /// A challenge that was declined, withdrawn or that expired, the challenger got their stake back
+    ChallengeRefunded { server: u64, challenge: u64 },
+}
+
+impl BetEvent {
+    /// The server where it happened
+    pub fn server(&self) -> u64 {
+        self.columns().1
+    }
+
+    /// The bet it's about, if any
+    pub fn bet(&self) -> Option<u64> {
+        match self {
+            // the column holds the uuid of the parlay or challenge
+            BetEvent::ParlayPlaced { .. }
+            | BetEvent::ChallengeProposed { .. }
+            | BetEvent::ChallengeAccepted { .. }
+            | BetEvent::ChallengeSettled { .. }
+            | BetEvent::ChallengeRefunded { .. } => None,
+            _ => self.columns().2,
+        }
+    }
+}
+
+// (kind, server, bet, user, outcome, amount, balance) as stored in the Event table
+type EventColumns = (&'static str, u64, Option<u64>, Option<u64>, Option<usize>, Option<i64>, Option<u64>);
+
+impl BetEvent {
+    pub(crate) fn columns(&self) -> EventColumns {
+        match self {
+            BetEvent::BetCreated { server, bet, author } => {
+                ("BetCreated", *server, Some(*bet), Some(*author), None, None, None)
+            }
+            BetEvent::WagerPlaced { server, bet, user, outcome, amount } => {
+                ("WagerPlaced", *server, Some(*bet), Some(*user), Some(*outcome), Some(*amount as i64), None)
+            }
+            BetEvent::RankedWagerPlaced { server, bet, user, pool, amount } => {
+                ("RankedWagerPlaced", *server, Some(*bet), Some(*user), Some(*pool), Some(*amount as i64), None)
+            }
+            BetEvent::SharesTraded { server, bet, user, outcome, shares } => {
+                ("SharesTraded", *server, Some(*bet), Some(*user), Some(*outcome), Some(*shares), None)
+            }
+            BetEvent::BetLocked { server, bet } => ("BetLocked", *server, Some(*bet), None, None, None, None),
+            BetEvent::BetResolved { server, bet, outcome } => {
+                ("BetResolved", *server, Some(*bet), None, Some(*outcome), None, None)
+            }
+            BetEvent::BetAborted { server, bet } => ("BetAborted", *server, Some(*bet), None, None, None, None),
+            BetEvent::BetRolledBack { server, bet } => ("BetRolledBack", *server, Some(*bet), None, None, None, None),
+            BetEvent::BalanceChanged(update) => (
+                "BalanceChanged", update.server, None, Some(update.user), None, Some(update.diff), Some(update.balance),
+            ),
+            BetEvent::ParlayPlaced { server, parlay, user, amount } => {
+                ("ParlayPlaced", *server, Some(*parlay), Some(*user), None, Some(*amount as i64), None)
+            }
+            BetEvent::ChallengeProposed { server, challenge, user, amount } => {
+                ("ChallengeProposed", *server, Some(*challenge), Some(*user), None, Some(*amount as i64), None)
+            }
+            BetEvent::ChallengeAccepted { server, challenge, user } => {
+                ("ChallengeAccepted", *server, Some(*challenge), Some(*user), None, None, None)
+            }
+            BetEvent::ChallengeSettled { server, challenge, winner } => {
+                ("ChallengeSettled", *server, Some(*challenge), Some(*winner), None, None, None)
+            }
+            BetEvent::ChallengeRefunded { server, challenge } => {
+                ("ChallengeRefunded", *server, Some(*challenge), None, None, None, None)
+            }
+        }
+    }
+}
+
+// Reads an event from a row of (id, kind, server, bet, user, outcome, amount, balance)
+pub(crate) fn event_of(row: &Row) -> rusqlite::Result<(u64, BetEvent)> {
+    let server = row.get::<usize, u64>(2)?;
+    let bet = || row.get::<usize, u64>(3);
+    let user = || row.get::<usize, u64>(4);
+    let outcome = || row.get::<usize, usize>(5);
+    let amount = || row.get::<usize, i64>(6);
+    let event = match row.get::<usize, String>(1)?.as_str() {
+        "BetCreated" => BetEvent::BetCreated { server, bet: bet()?, author: user()? },
+        "WagerPlaced" => BetEvent::WagerPlaced {
+            server, bet: bet()?, user: user()?, outcome: outcome()?, amount: amount()? as u64,
+        },
+        "RankedWagerPlaced" => BetEvent::RankedWagerPlaced {
+            server, bet: bet()?, user: user()?, pool: outcome()?, amount: amount()? as u64,
+        },
+        "SharesTraded" => BetEvent::SharesTraded {
+            server, bet: bet()?, user: user()?, outcome: outcome()?, shares: amount()?,
+        },
+        "BetLocked" => BetEvent::BetLocked { server, bet: bet()? },
+        "BetResolved" => BetEvent::BetResolved { server, bet: bet()?, outcome: outcome()? },
+        "BetAborted" => BetEvent::BetAborted { server, bet: bet()? },
+        "BetRolledBack" => BetEvent::BetRolledBack { server, bet: bet()? },
+        "ParlayPlaced" => BetEvent::ParlayPlaced { server, parlay: bet()?, user: user()?, amount: amount()? as u64 },
+        "ChallengeProposed" => BetEvent::ChallengeProposed {
+            server, challenge: bet()?, user: user()?, amount: amount()? as u64,
+        },
+        "ChallengeAccepted" => BetEvent::ChallengeAccepted { server, challenge: bet()?, user: user()? },
+        "ChallengeSettled" => BetEvent::ChallengeSettled { server, challenge: bet()?, winner: user()? },
+        "ChallengeRefunded" => BetEvent::ChallengeRefunded { server, challenge: bet()? },
+        _ => BetEvent::BalanceChanged(AccountUpdate {
+            server, user: user()?, diff: amount()?, balance: row.get::<usize, u64>(7)?,
+        }),
+    };
+    Ok((row.get::<usize, u64>(0)?, event))
+}
+
+// A channel fed with the events that come after `last_event`
+#[derive(Debug)]
+pub(crate) struct Subscriber {
+    sender: Sender<(u64, BetEvent)>,
+    last_event: u64,
+}
+
+impl Bets {
+    /// The events committed after the event `after` (0 for all of them), oldest first.
+    ///
+    /// Events are kept in the database, a consumer that stores the id of the last event it handled
+    /// can catch up after a restart, or follow the changes made by other processes.
+    pub fn events(&self, after: u64, limit: usize) -> Result<Vec<(u64, BetEvent)>, BetError> {
+        let conn = Connection::open(&self.db_path)?;
+        conn.events(after, limit)
+    }
+
+    /// The id of the last event, 0 if there's none yet.
+    pub fn last_event(&self) -> Result<u64, BetError> {
+        let conn = Connection::open(&self.db_path)?;
+        Ok(conn.query_row("SELECT IFNULL(MAX(id), 0) FROM Event", [], |row| row.get::<usize, u64>(0))?)
+    }
+
+    /// Subscribes to the events committed after the event `after`, as (id, event).
+    ///
+    /// The missed events are sent right away, and the new ones as soon as they are committed through
+    /// this `Bets` (or one of its clones). Changes made by other processes are sent on `poll_events`.
+    /// The subscription ends when the receiver is dropped.
+    pub fn subscribe(&self, after: u64) -> Result<Receiver<(u64, BetEvent)>, BetError> {
+        let (sender, receiver) = channel();
+        self.subscribers.lock().unwrap().push(Subscriber { sender, last_event: after });
+        self.poll_events()?;
+        Ok(receiver)
+    }
+
+    /// Sends the events that were not sent yet to the subscribers.
+    pub fn poll_events(&self) -> Result<(), BetError> {
+        // the lock is held until the events are sent, so that they are sent in order
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let Some(after) = subscribers.iter().map(|subscriber| subscriber.last_event).min() else {
+            return Ok(());
+        };
+        let conn = Connection::open(&self.db_path)?;
+        let events = conn.events(after, usize::MAX)?;
+        subscribers.retain_mut(|subscriber| {
+            let last_event = subscriber.last_event;
+            for (id, event) in events.iter().filter(|(id, _)| *id > last_event) {
+                if subscriber.sender.send((*id, event.clone())).is_err() {
+                    return false;
+                }
+                subscriber.last_event = *id;
+            }
+            true
+        });
+        Ok(())
+    }
+
+    /// The events that a consumer has not acknowledged yet, oldest first. The event table is an outbox:
+    /// an event is written in the same transaction as the change it describes,
+    /// so it can't be lost, even if the process crashes right after the commit.
+    ///
+    /// Events are delivered at least once: they are pending until acknowledged,
+    /// consumers should use the event id to skip the ones they already handled.
+    /// A new consumer starts from the first event.
+    pub fn pending_events(&self, consumer: &str, limit: usize) -> Result<Vec<(u64, BetEvent)>, BetError> {
+        let conn = Connection::open(&self.db_path)?;
+        let events = conn
+            .prepare(
+                "SELECT id, kind, server, bet, user, outcome, amount, balance
+                FROM Event
+                WHERE id > IFNULL((SELECT acked_up_to FROM OutboxConsumer WHERE consumer = ?1), 0)
+                AND id NOT IN (SELECT event FROM OutboxAck WHERE consumer = ?1)
+                ORDER BY id
+                LIMIT ?2",
+            )
+            .unwrap()
+            .query_map(params![consumer, limit.min(i64::MAX as usize) as i64], event_of)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(events)
+    }
+
+    /// Marks events as handled by a consumer, they won't be pending anymore.
+    pub fn acknowledge_events(&self, consumer: &str, events: &[u64]) -> Result<(), BetError> {
+        let mut conn = Connection::open(&self.db_path)?;
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT or ignore
+            INTO OutboxConsumer (consumer, acked_up_to)
+            VALUES (?1, 0)",
+            [consumer],
+        )?;
+        let last_event = tx.query_row("SELECT IFNULL(MAX(id), 0) FROM Event", [], |row| row.get::<usize, u64>(0))?;
+        // events that don't exist yet can't be acknowledged
+        for event in events.iter().filter(|event| **event <= last_event) {
+            tx.execute(
+                "INSERT or ignore
+                INTO OutboxAck (consumer, event)
+                VALUES (?1, ?2)",
+                params![consumer, event],
+            )?;
+        }
+        // move acked_up_to to just before the first pending event, and forget the acks up to it
+        let first_pending = tx
+            .query_row(
+                "SELECT MIN(id)
+                FROM Event
+                WHERE id > (SELECT acked_up_to FROM OutboxConsumer WHERE consumer = ?1)
+                AND id NOT IN (SELECT event FROM OutboxAck WHERE consumer = ?1)",
+                [consumer],
+                |row| row.get::<usize, Option<u64>>(0),
+            )?;
+        let acked_up_to = match first_pending {
+            Some(event) => event - 1,
+            None => last_event,
+        };
+        tx.execute(
+            "UPDATE OutboxConsumer
+            SET acked_up_to = MAX(acked_up_to, ?1)
+            WHERE consumer = ?2",
+            params![acked_up_to, consumer],
+        )?;
+        tx.execute(
+            "DELETE
+            FROM OutboxAck
+            WHERE consumer = ?1 AND event <= (SELECT acked_up_to FROM OutboxConsumer WHERE consumer = ?1)",
+            [consumer],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Deletes the events up to `up_to` that every outbox consumer acknowledged,
+    /// returns how many were removed. A consumer is known once it acknowledged events, even none.
+    /// The last event is always kept, so that `last_event` stays right.
+    ///
+    /// Subscribers and consumers that are behind won't get the deleted events anymore.
+    pub fn prune_events(&self, up_to: u64) -> Result<usize, BetError> {
+        let conn = Connection::open(&self.db_path)?;
+        Ok(conn.execute(
+            "DELETE
+            FROM Event
+            WHERE id <= ?1
+            AND id < (SELECT MAX(id) FROM Event)
+            AND id <= IFNULL((SELECT MIN(acked_up_to) FROM OutboxConsumer), ?1)",
+            [up_to.min(i64::MAX as u64)],
+        )?)
+    }
+
+    // Sends the events again to the subscribers that are past `last_event`,
+    // the ids after it may be reused once the database is restored from a snapshot
+    pub(crate) fn rewind_subscribers(&self, last_event: u64) {
+        for subscriber in self.subscribers.lock().unwrap().iter_mut() {
+            subscriber.last_event = subscriber.last_event.min(last_event);
+        }
+    }
+
+    // Called after each commit, the events are in the database already
+    // so those that can't be sent now will be sent on the next poll
+    pub(crate) fn publish(&self) {
+        let _ = self.poll_events();
+    }
+}
@@ -0,0 +1,1271 @@
+//! Moving a server's economy between databases: its config, accounts, open bets with their wagers
+//! and side tables (numeric ranges, guesses, ranked wagers, market shares), pending parlays and challenges,
+//! the history of manual balance adjustments and the ledger of balance changes.
+//!
+//! Resolved bets, settled parlays and the other events stay in the original database, along with
+//! the legs of pending parlays that are already decided: the value of the parlay carries their result.
+use std::{
+    collections::{HashMap, HashSet},
+    io::{Read, Write},
+};
+use itertools::Itertools;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use serde::{Deserialize, Serialize};
+use crate::{
+    bet_connection::BetConnection, bet_transaction::BetTransaction, numeric::Range, AccountUpdate, BetError, BetEvent,
+    utils, Bets, GuessScoring, NumericOutcomes, Outcome, ServerConfig, StakeLimits,
+};
+
+const VERSION: u64 = 2;
+
+/// The reason of the adjustments that give their balance to the imported accounts without a ledger
+pub const IMPORT_REASON: &str = "import";
+
+/// The format of an export
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "admin", derive(clap::ValueEnum))]
+pub enum ExportFormat {
+    /// A JSON document
+    Json,
+    /// One row per record, with the columns `record, bet, user, outcome, value, time, text`
+    Csv,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Account {
+    user: u64,
+    balance: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExportedBet {
+    bet: u64,
+    author: u64,
+    desc: String,
+    is_open: bool,
+    limits: StakeLimits,
+    outcomes: Vec<Outcome>,
+    // at most one of them, for the bets that aren't regular ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    numeric: Option<NumericBet>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    guess: Option<GuessBet>,
+    // the wagers on the show and exact order pools, the winner pool is in the outcomes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ranked: Option<Vec<RankedWager>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    market: Option<Market>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct NumericBet {
+    closest_guess: bool,
+    // the (low, high) range of each outcome
+    ranges: Vec<Range>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct GuessBet {
+    scoring: GuessScoring,
+    guesses: Vec<Guess>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Guess {
+    user: u64,
+    guess: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RankedWager {
+    user: u64,
+    pool: usize,
+    pick: Vec<usize>,
+    amount: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Market {
+    liquidity: f64,
+    shares: Vec<Shares>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Shares {
+    user: u64,
+    outcome: usize,
+    shares: u64,
+    // what the user spent net of what they sold, the coins are held by the market
+    cost: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExportedParlay {
+    parlay: u64,
+    user: u64,
+    stake: u64,
+    value: u64,
+    // the legs that are not decided yet
+    legs: Vec<ExportedLeg>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExportedLeg {
+    bet: u64,
+    outcome: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExportedChallenge {
+    challenge: u64,
+    challenger: u64,
+    opponent: u64,
+    desc: String,
+    stake: u64,
+    expires: u64,
+    accepted: bool,
+    // who each participant said won, once accepted
+    #[serde(default)]
+    challenger_claim: Option<u64>,
+    #[serde(default)]
+    opponent_claim: Option<u64>,
+}
+
+// A BalanceChanged event
+#[derive(Serialize, Deserialize)]
+struct LedgerEntry {
+    user: u64,
+    diff: i64,
+    balance: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Adjustment {
+    user: u64,
+    diff: i64,
+    reason: String,
+    time: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ServerExport {
+    version: u64,
+    server: u64,
+    config: ServerConfig,
+    accounts: Vec<Account>,
+    bets: Vec<ExportedBet>,
+    #[serde(default)]
+    parlays: Vec<ExportedParlay>,
+    #[serde(default)]
+    challenges: Vec<ExportedChallenge>,
+    adjustments: Vec<Adjustment>,
+    #[serde(default)]
+    ledger: Vec<LedgerEntry>,
+    // the coins in the balances, wagers, markets, parlays and challenges, checked on import
+    total: u64,
+}
+
+// A line of the CSV format
+#[derive(Serialize, Deserialize, Default)]
+struct Row {
+    record: String,
+    bet: Option<u64>,
+    user: Option<u64>,
+    outcome: Option<usize>,
+    value: Option<String>,
+    time: Option<u64>,
+    text: Option<String>,
+}
+
+fn invalid<E: ToString>(err: E) -> BetError {
+    BetError::Transfer(err.to_string())
+}
+
+// Adds up coins, refusing amounts that don't fit in a u64
+fn sum_coins<I: IntoIterator<Item = u64>>(amounts: I) -> Result<u64, BetError> {
+    amounts
+        .into_iter()
+        .try_fold(0u64, |total, amount| total.checked_add(amount))
+        .ok_or_else(|| invalid("the coins add up to more than the largest possible amount"))
+}
+
+// The coins in the wagers of the bets and in the stakes of the parlays and challenges,
+// which are counted in the accounts
+fn wagered(bets: &[ExportedBet], parlays: &[ExportedParlay], challenges: &[ExportedChallenge]) -> Result<u64, BetError> {
+    let wagers = bets.iter().flat_map(|bet| &bet.outcomes).flat_map(|outcome| &outcome.wagers).map(|(_, amount)| *amount);
+    let ranked = bets.iter().flat_map(|bet| bet.ranked.iter().flatten()).map(|wager| wager.amount);
+    let parlays = parlays.iter().map(|parlay| parlay.value);
+    let challenges = challenges
+        .iter()
+        .map(|challenge| if challenge.accepted { challenge.stake.checked_mul(2) } else { Some(challenge.stake) });
+    let challenges = challenges
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| invalid("the coins add up to more than the largest possible amount"))?;
+    sum_coins(wagers.chain(ranked).chain(parlays).chain(challenges))
+}
+
+// The coins held by the markets, and the part of them counted in the accounts:
+// the position of a user in a market is what they spent on it, if they didn't sell at a profit
+fn in_markets(bets: &[ExportedBet]) -> Result<(u64, u64), BetError> {
+    let overflow = || invalid("the coins add up to more than the largest possible amount");
+    let (mut total, mut positions) = (0u64, 0u64);
+    for bet in bets {
+        let Some(market) = &bet.market else {
+            continue;
+        };
+        let mut spent: HashMap<u64, i64> = HashMap::new();
+        for shares in &market.shares {
+            let spent = spent.entry(shares.user).or_default();
+            *spent = spent.checked_add(shares.cost).ok_or_else(overflow)?;
+        }
+        let held = spent.values().try_fold(0i64, |held, spent| held.checked_add(*spent)).ok_or_else(overflow)?;
+        let held = u64::try_from(held).map_err(|_| invalid(format!("the market {} holds a negative amount", bet.bet)))?;
+        total = sum_coins([total, held])?;
+        positions = sum_coins([positions, sum_coins(spent.values().map(|spent| (*spent).max(0) as u64))?])?;
+    }
+    Ok((total, positions))
+}
+
+fn limit_settings(limits: &StakeLimits) -> [(&'static str, Option<String>); 5] {
+    [
+        ("min_stake", limits.min_stake.map(|value| value.to_string())),
+        ("max_stake", limits.max_stake.map(|value| value.to_string())),
+        ("min_fraction", limits.min_fraction.map(|value| value.to_string())),
+        ("max_fraction", limits.max_fraction.map(|value| value.to_string())),
+        ("max_pool_share", limits.max_pool_share.map(|value| value.to_string())),
+    ]
+}
+
+fn parse<T: std::str::FromStr>(name: &str, value: Option<&str>) -> Result<T, BetError> {
+    value
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| invalid(format!("invalid value for {}", name)))
+}
+
+fn required<T>(name: &str, value: Option<T>) -> Result<T, BetError> {
+    value.ok_or_else(|| invalid(format!("missing {}", name)))
+}
+
+fn set_limit(limits: &mut StakeLimits, name: &str, value: Option<&str>) -> Result<bool, BetError> {
+    let value = value.filter(|value| !value.is_empty());
+    match name {
+        "min_stake" => limits.min_stake = value.map(|value| parse(name, Some(value))).transpose()?,
+        "max_stake" => limits.max_stake = value.map(|value| parse(name, Some(value))).transpose()?,
+        "min_fraction" => limits.min_fraction = value.map(|value| parse(name, Some(value))).transpose()?,
+        "max_fraction" => limits.max_fraction = value.map(|value| parse(name, Some(value))).transpose()?,
+        "max_pool_share" => limits.max_pool_share = value.map(|value| parse(name, Some(value))).transpose()?,
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+impl ExportedBet {
+    // The rows of the side table of the bet, if it isn't a regular one
+    fn side_rows(&self) -> Vec<Row> {
+        let row = |record: &str| Row { record: record.to_string(), bet: Some(self.bet), ..Row::default() };
+        let mut rows = Vec::new();
+        if let Some(numeric) = &self.numeric {
+            rows.push(Row { value: Some(numeric.closest_guess.to_string()), ..row("numeric") });
+            let bound = |bound: Option<f64>| Some(bound.map(|bound| bound.to_string()).unwrap_or_default());
+            for (number, (low, high)) in numeric.ranges.iter().enumerate() {
+                rows.push(Row { outcome: Some(number), value: bound(*low), text: bound(*high), ..row("range") });
+            }
+        }
+        if let Some(guess) = &self.guess {
+            rows.push(Row { value: Some(u32::from(guess.scoring).to_string()), ..row("guess_bet") });
+            for exported in &guess.guesses {
+                rows.push(Row { user: Some(exported.user), value: Some(exported.guess.to_string()), ..row("guess") });
+            }
+        }
+        if let Some(ranked) = &self.ranked {
+            rows.push(row("ranked"));
+            for wager in ranked {
+                rows.push(Row {
+                    user: Some(wager.user),
+                    outcome: Some(wager.pool),
+                    value: Some(wager.amount.to_string()),
+                    text: Some(wager.pick.iter().join(",")),
+                    ..row("ranked_wager")
+                });
+            }
+        }
+        if let Some(market) = &self.market {
+            rows.push(Row { value: Some(market.liquidity.to_string()), ..row("market") });
+            for shares in &market.shares {
+                rows.push(Row {
+                    user: Some(shares.user),
+                    outcome: Some(shares.outcome),
+                    value: Some(shares.shares.to_string()),
+                    text: Some(shares.cost.to_string()),
+                    ..row("shares")
+                });
+            }
+        }
+        rows
+    }
+
+    // Checks the side table of the bet against its outcomes and wagers
+    fn validate_side(&self, users: &HashSet<u64>) -> Result<(), BetError> {
+        let bet = self.bet;
+        let kinds = [self.numeric.is_some(), self.guess.is_some(), self.ranked.is_some(), self.market.is_some()];
+        if kinds.into_iter().filter(|kind| *kind).count() > 1 {
+            return Err(invalid(format!("bet {} has several kinds", bet)));
+        }
+        if let Some(numeric) = &self.numeric {
+            let bounds = numeric.ranges.iter().skip(1).map(|(low, _)| low.unwrap_or(f64::NAN)).collect();
+            let ranges = NumericOutcomes::Ranges(bounds).ranges().map_err(|_| invalid(format!("bet {} has invalid ranges", bet)))?;
+            if ranges != numeric.ranges || ranges.len() != self.outcomes.len() {
+                return Err(invalid(format!("the ranges of bet {} don't match its outcomes", bet)));
+            }
+        }
+        if let Some(guess) = &self.guess {
+            if self.outcomes.len() != 1 {
+                return Err(invalid(format!("the guess bet {} must have a single outcome", bet)));
+            }
+            let bettors: HashSet<_> = self.outcomes[0].wagers.iter().map(|(user, _)| *user).collect();
+            let mut guessers = HashSet::new();
+            for exported in &guess.guesses {
+                if !exported.guess.is_finite() {
+                    return Err(invalid(format!("the guess of user {} on bet {} is not a number", exported.user, bet)));
+                }
+                if !guessers.insert(exported.user) {
+                    return Err(invalid(format!("user {} has several guesses on bet {}", exported.user, bet)));
+                }
+            }
+            if guessers != bettors {
+                return Err(invalid(format!("the guesses of bet {} don't match its wagers", bet)));
+            }
+        }
+        if let Some(ranked) = &self.ranked {
+            let mut pools = HashSet::new();
+            for wager in ranked {
+                if !users.contains(&wager.user) {
+                    return Err(invalid(format!("user {} bet on {} without an account", wager.user, bet)));
+                }
+                if !pools.insert((wager.user, wager.pool)) {
+                    return Err(invalid(format!("user {} has several wagers in pool {} of bet {}", wager.user, wager.pool, bet)));
+                }
+                if wager.amount == 0 {
+                    return Err(invalid(format!("the wager of user {} on bet {} is empty", wager.user, bet)));
+                }
+                // the winner pool is the one of the outcomes, a show pick is a single outcome
+                let picked = if wager.pool == 0 { 1 } else { wager.pool };
+                if wager.pool == 1
+                    || wager.pick.len() != picked
+                    || !wager.pick.iter().all_unique()
+                    || wager.pick.iter().any(|outcome| *outcome >= self.outcomes.len())
+                {
+                    return Err(invalid(format!("the pick of user {} in pool {} of bet {} is invalid", wager.user, wager.pool, bet)));
+                }
+            }
+        }
+        if let Some(market) = &self.market {
+            if !(market.liquidity.is_finite() && market.liquidity > 0.) {
+                return Err(invalid(format!("the market {} has an invalid liquidity", bet)));
+            }
+            if self.outcomes.iter().any(|outcome| !outcome.wagers.is_empty()) {
+                return Err(invalid(format!("the market {} has wagers", bet)));
+            }
+            let mut holdings = HashSet::new();
+            for shares in &market.shares {
+                if !users.contains(&shares.user) {
+                    return Err(invalid(format!("user {} holds shares of {} without an account", shares.user, bet)));
+                }
+                if shares.outcome >= self.outcomes.len() || !holdings.insert((shares.user, shares.outcome)) {
+                    return Err(invalid(format!("the shares of user {} on bet {} are invalid", shares.user, bet)));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ServerExport {
+    fn rows(&self) -> Vec<Row> {
+        let value = |record: &str, value: String| Row {
+            record: record.to_string(),
+            value: Some(value),
+            ..Row::default()
+        };
+        let mut rows = vec![
+            value("version", self.version.to_string()),
+            value("server", self.server.to_string()),
+            value("total", self.total.to_string()),
+        ];
+        let config = &self.config;
+        let settings = [
+            ("starting_balance", Some(config.starting_balance.to_string())),
+            ("fee", Some(config.fee.to_string())),
+            ("allow_fractions", Some(config.allow_fractions.to_string())),
+            ("income", Some(config.income.to_string())),
+        ];
+        for (name, setting) in settings.into_iter().chain(limit_settings(&config.limits)) {
+            rows.push(Row {
+                record: "setting".to_string(),
+                value: setting,
+                text: Some(name.to_string()),
+                ..Row::default()
+            });
+        }
+        for account in &self.accounts {
+            rows.push(Row { user: Some(account.user), ..value("account", account.balance.to_string()) });
+        }
+        for bet in &self.bets {
+            rows.push(Row {
+                bet: Some(bet.bet),
+                user: Some(bet.author),
+                text: Some(bet.desc.clone()),
+                ..value("bet", bet.is_open.to_string())
+            });
+            for (name, limit) in limit_settings(&bet.limits) {
+                if limit.is_some() {
+                    rows.push(Row {
+                        record: "bet_limit".to_string(),
+                        bet: Some(bet.bet),
+                        value: limit,
+                        text: Some(name.to_string()),
+                        ..Row::default()
+                    });
+                }
+            }
+            for (number, outcome) in bet.outcomes.iter().enumerate() {
+                rows.push(Row {
+                    record: "outcome".to_string(),
+                    bet: Some(bet.bet),
+                    outcome: Some(number),
+                    text: Some(outcome.desc.clone()),
+                    ..Row::default()
+                });
+                for (user, amount) in &outcome.wagers {
+                    rows.push(Row {
+                        bet: Some(bet.bet),
+                        user: Some(*user),
+                        outcome: Some(number),
+                        ..value("wager", amount.to_string())
+                    });
+                }
+            }
+            rows.extend(bet.side_rows());
+        }
+        for parlay in &self.parlays {
+            rows.push(Row {
+                bet: Some(parlay.parlay),
+                user: Some(parlay.user),
+                text: Some(parlay.stake.to_string()),
+                ..value("parlay", parlay.value.to_string())
+            });
+            for leg in &parlay.legs {
+                rows.push(Row { bet: Some(leg.bet), outcome: Some(leg.outcome), ..value("parlay_leg", parlay.parlay.to_string()) });
+            }
+        }
+        for challenge in &self.challenges {
+            rows.push(Row {
+                bet: Some(challenge.challenge),
+                user: Some(challenge.challenger),
+                time: Some(challenge.expires),
+                text: Some(challenge.desc.clone()),
+                ..value("challenge", challenge.stake.to_string())
+            });
+            rows.push(Row {
+                bet: Some(challenge.challenge),
+                user: Some(challenge.opponent),
+                ..value("challenge_opponent", challenge.accepted.to_string())
+            });
+            let claims = [(challenge.challenger, challenge.challenger_claim), (challenge.opponent, challenge.opponent_claim)];
+            for (user, winner) in claims {
+                if let Some(winner) = winner {
+                    rows.push(Row { bet: Some(challenge.challenge), user: Some(user), ..value("challenge_claim", winner.to_string()) });
+                }
+            }
+        }
+        for adjustment in &self.adjustments {
+            rows.push(Row {
+                user: Some(adjustment.user),
+                time: Some(adjustment.time),
+                text: Some(adjustment.reason.clone()),
+                ..value("adjustment", adjustment.diff.to_string())
+            });
+        }
+        for entry in &self.ledger {
+            rows.push(Row {
+                user: Some(entry.user),
+                text: Some(entry.balance.to_string()),
+                ..value("ledger", entry.diff.to_string())
+            });
+        }
+        rows
+    }
+
+    fn from_rows(rows: impl Iterator<Item = Result<Row, BetError>>) -> Result<Self, BetError> {
+        let mut export = ServerExport {
+            version: 0,
+            server: 0,
+            config: ServerConfig::default(),
+            accounts: Vec::new(),
+            bets: Vec::new(),
+            parlays: Vec::new(),
+            challenges: Vec::new(),
+            adjustments: Vec::new(),
+            ledger: Vec::new(),
+            total: 0,
+        };
+        for (line, row) in rows.enumerate() {
+            // the first line is the header
+            export.read_row(&row?).map_err(|err| invalid(format!("line {}: {}", line + 2, err)))?;
+        }
+        Ok(export)
+    }
+
+    fn bet_mut(&mut self, bet: Option<u64>) -> Result<&mut ExportedBet, BetError> {
+        let bet = required("bet", bet)?;
+        self.bets
+            .iter_mut()
+            .find(|exported| exported.bet == bet)
+            .ok_or_else(|| invalid(format!("bet {} comes before its bet row", bet)))
+    }
+
+    fn read_row(&mut self, row: &Row) -> Result<(), BetError> {
+        let value = row.value.as_deref();
+        let text = row.text.clone().unwrap_or_default();
+        match row.record.as_str() {
+            "version" => self.version = parse("version", value)?,
+            "server" => self.server = parse("server", value)?,
+            "total" => self.total = parse("total", value)?,
+            "setting" => {
+                let config = &mut self.config;
+                match text.as_str() {
+                    "starting_balance" => config.starting_balance = parse(&text, value)?,
+                    "fee" => config.fee = parse(&text, value)?,
+                    "allow_fractions" => config.allow_fractions = parse(&text, value)?,
+                    "income" => config.income = parse(&text, value)?,
+                    name => {
+                        if !set_limit(&mut config.limits, name, value)? {
+                            return Err(invalid(format!("unknown setting {}", name)));
+                        }
+                    }
+                }
+            }
+            "account" => self.accounts.push(Account { user: required("user", row.user)?, balance: parse("balance", value)? }),
+            "bet" => self.bets.push(ExportedBet {
+                bet: required("bet", row.bet)?,
+                author: required("user", row.user)?,
+                desc: text,
+                is_open: parse("is_open", value)?,
+                limits: StakeLimits::default(),
+                outcomes: Vec::new(),
+                numeric: None,
+                guess: None,
+                ranked: None,
+                market: None,
+            }),
+            "bet_limit" => {
+                if !set_limit(&mut self.bet_mut(row.bet)?.limits, &text, value)? {
+                    return Err(invalid(format!("unknown limit {}", text)));
+                }
+            }
+            "outcome" => {
+                let outcomes = &mut self.bet_mut(row.bet)?.outcomes;
+                if row.outcome != Some(outcomes.len()) {
+                    return Err(invalid("the outcomes of a bet must be numbered in order from 0"));
+                }
+                outcomes.push(Outcome { desc: text, wagers: Vec::new() });
+            }
+            "wager" => {
+                let wager = (required("user", row.user)?, parse("amount", value)?);
+                row.outcome
+                    .and_then(|outcome| self.bet_mut(row.bet).ok()?.outcomes.get_mut(outcome))
+                    .ok_or_else(|| invalid("a wager must follow the row of its outcome"))?
+                    .wagers
+                    .push(wager);
+            }
+            "adjustment" => self.adjustments.push(Adjustment {
+                user: required("user", row.user)?,
+                diff: parse("diff", value)?,
+                reason: text,
+                time: required("time", row.time)?,
+            }),
+            "numeric" => {
+                self.bet_mut(row.bet)?.numeric = Some(NumericBet { closest_guess: parse("closest_guess", value)?, ranges: Vec::new() });
+            }
+            "range" => {
+                let bound = |name, bound: Option<&str>| bound.filter(|bound| !bound.is_empty()).map(|bound| parse(name, Some(bound))).transpose();
+                let range = (bound("low", value)?, bound("high", row.text.as_deref())?);
+                let ranges = &mut self
+                    .bet_mut(row.bet)?
+                    .numeric
+                    .as_mut()
+                    .ok_or_else(|| invalid("a range must follow the numeric row of its bet"))?
+                    .ranges;
+                if row.outcome != Some(ranges.len()) {
+                    return Err(invalid("the ranges of a bet must be numbered in order from 0"));
+                }
+                ranges.push(range);
+            }
+            "guess_bet" => {
+                let scoring = match parse::<u32>("scoring", value)? {
+                    scoring @ (0 | 1) => GuessScoring::from(scoring),
+                    _ => return Err(invalid("invalid value for scoring")),
+                };
+                self.bet_mut(row.bet)?.guess = Some(GuessBet { scoring, guesses: Vec::new() });
+            }
+            "guess" => {
+                let guess = Guess { user: required("user", row.user)?, guess: parse("guess", value)? };
+                self.bet_mut(row.bet)?
+                    .guess
+                    .as_mut()
+                    .ok_or_else(|| invalid("a guess must follow the guess_bet row of its bet"))?
+                    .guesses
+                    .push(guess);
+            }
+            "ranked" => self.bet_mut(row.bet)?.ranked = Some(Vec::new()),
+            "ranked_wager" => {
+                let wager = RankedWager {
+                    user: required("user", row.user)?,
+                    pool: required("pool", row.outcome)?,
+                    pick: text.split(',').map(|outcome| parse("pick", Some(outcome))).collect::<Result<_, _>>()?,
+                    amount: parse("amount", value)?,
+                };
+                self.bet_mut(row.bet)?
+                    .ranked
+                    .as_mut()
+                    .ok_or_else(|| invalid("a ranked wager must follow the ranked row of its bet"))?
+                    .push(wager);
+            }
+            "market" => {
+                self.bet_mut(row.bet)?.market = Some(Market { liquidity: parse("liquidity", value)?, shares: Vec::new() });
+            }
+            "shares" => {
+                let shares = Shares {
+                    user: required("user", row.user)?,
+                    outcome: required("outcome", row.outcome)?,
+                    shares: parse("shares", value)?,
+                    cost: parse("cost", Some(&text))?,
+                };
+                self.bet_mut(row.bet)?
+                    .market
+                    .as_mut()
+                    .ok_or_else(|| invalid("shares must follow the market row of their bet"))?
+                    .shares
+                    .push(shares);
+            }
+            "parlay" => self.parlays.push(ExportedParlay {
+                parlay: required("bet", row.bet)?,
+                user: required("user", row.user)?,
+                stake: parse("stake", Some(&text))?,
+                value: parse("value", value)?,
+                legs: Vec::new(),
+            }),
+            "parlay_leg" => {
+                let parlay: u64 = parse("parlay", value)?;
+                let leg = ExportedLeg { bet: required("bet", row.bet)?, outcome: required("outcome", row.outcome)? };
+                self.parlays
+                    .iter_mut()
+                    .find(|exported| exported.parlay == parlay)
+                    .ok_or_else(|| invalid(format!("parlay {} comes before its parlay row", parlay)))?
+                    .legs
+                    .push(leg);
+            }
+            "challenge" => self.challenges.push(ExportedChallenge {
+                challenge: required("bet", row.bet)?,
+                challenger: required("user", row.user)?,
+                opponent: 0,
+                desc: text,
+                stake: parse("stake", value)?,
+                expires: required("time", row.time)?,
+                accepted: false,
+                challenger_claim: None,
+                opponent_claim: None,
+            }),
+            "challenge_opponent" => {
+                let challenge = required("bet", row.bet)?;
+                let exported = self
+                    .challenges
+                    .iter_mut()
+                    .find(|exported| exported.challenge == challenge)
+                    .ok_or_else(|| invalid(format!("challenge {} comes before its challenge row", challenge)))?;
+                exported.opponent = required("user", row.user)?;
+                exported.accepted = parse("accepted", value)?;
+            }
+            "challenge_claim" => {
+                let challenge = required("bet", row.bet)?;
+                let exported = self
+                    .challenges
+                    .iter_mut()
+                    .find(|exported| exported.challenge == challenge)
+                    .ok_or_else(|| invalid(format!("challenge {} comes before its challenge row", challenge)))?;
+                let (user, winner) = (required("user", row.user)?, Some(parse("winner", value)?));
+                match user {
+                    user if user == exported.challenger => exported.challenger_claim = winner,
+                    user if user == exported.opponent => exported.opponent_claim = winner,
+                    _ => return Err(invalid(format!("user {} is not a participant of challenge {}", user, challenge))),
+                }
+            }
+            "ledger" => self.ledger.push(LedgerEntry {
+                user: required("user", row.user)?,
+                diff: parse("diff", value)?,
+                balance: parse("balance", Some(&text))?,
+            }),
+            record => return Err(invalid(format!("unknown record {}", record))),
+        }
+        Ok(())
+    }
+
+    // Checks that the export is consistent on its own, before anything is written
+    fn validate(&self) -> Result<(), BetError> {
+        // version 1 had no side tables, parlays, challenges or ledger, it is read the same
+        if !(1..=VERSION).contains(&self.version) {
+            return Err(invalid(format!("unsupported export version {}", self.version)));
+        }
+        self.config.validate()?;
+        let mut users = HashSet::new();
+        if let Some(account) = self.accounts.iter().find(|account| !users.insert(account.user)) {
+            return Err(invalid(format!("user {} has several accounts", account.user)));
+        }
+        let mut uuids = HashSet::new();
+        if let Some(bet) = self.bets.iter().find(|bet| !uuids.insert(bet.bet)) {
+            return Err(invalid(format!("bet {} appears several times", bet.bet)));
+        }
+        for bet in &self.bets {
+            bet.limits.validate()?;
+            if bet.outcomes.is_empty() {
+                return Err(invalid(format!("bet {} has no outcome", bet.bet)));
+            }
+            let mut bettors = HashSet::new();
+            for (user, amount) in bet.outcomes.iter().flat_map(|outcome| &outcome.wagers) {
+                if !users.contains(user) {
+                    return Err(invalid(format!("user {} bet on {} without an account", user, bet.bet)));
+                }
+                if !bettors.insert(user) {
+                    return Err(invalid(format!("user {} has several wagers on bet {}", user, bet.bet)));
+                }
+                if *amount == 0 {
+                    return Err(invalid(format!("the wager of user {} on bet {} is empty", user, bet.bet)));
+                }
+            }
+            bet.validate_side(&users)?;
+        }
+        let mut parlays = HashSet::new();
+        for parlay in &self.parlays {
+            if !parlays.insert(parlay.parlay) {
+                return Err(invalid(format!("parlay {} appears several times", parlay.parlay)));
+            }
+            if !users.contains(&parlay.user) {
+                return Err(invalid(format!("user {} placed parlay {} without an account", parlay.user, parlay.parlay)));
+            }
+            if parlay.stake == 0 || parlay.value == 0 || parlay.legs.is_empty() {
+                return Err(invalid(format!("parlay {} is empty", parlay.parlay)));
+            }
+            if !parlay.legs.iter().map(|leg| leg.bet).all_unique() {
+                return Err(invalid(format!("parlay {} has several legs on the same bet", parlay.parlay)));
+            }
+            for leg in &parlay.legs {
+                let valid = self.bets.iter().any(|bet| {
+                    bet.bet == leg.bet
+                        && leg.outcome < bet.outcomes.len()
+                        && bet.guess.is_none()
+                        && bet.ranked.is_none()
+                        && bet.market.is_none()
+                });
+                if !valid {
+                    return Err(invalid(format!("parlay {} has an invalid leg on bet {}", parlay.parlay, leg.bet)));
+                }
+            }
+        }
+        let mut challenges = HashSet::new();
+        for challenge in &self.challenges {
+            if !challenges.insert(challenge.challenge) {
+                return Err(invalid(format!("challenge {} appears several times", challenge.challenge)));
+            }
+            let participants = [challenge.challenger, challenge.opponent];
+            if challenge.challenger == challenge.opponent || !participants.iter().all(|user| users.contains(user)) {
+                return Err(invalid(format!("challenge {} has invalid participants", challenge.challenge)));
+            }
+            if challenge.stake == 0 {
+                return Err(invalid(format!("challenge {} is empty", challenge.challenge)));
+            }
+            let claims = [challenge.challenger_claim, challenge.opponent_claim];
+            if claims.iter().flatten().any(|winner| !challenge.accepted || !participants.contains(winner)) {
+                return Err(invalid(format!("challenge {} has invalid claims", challenge.challenge)));
+            }
+        }
+        // the ledger of each user must be a chain of changes ending with the balance of their account
+        let mut ledgers: HashMap<u64, u64> = HashMap::new();
+        for entry in &self.ledger {
+            if !users.contains(&entry.user) {
+                return Err(invalid(format!("user {} has a ledger without an account", entry.user)));
+            }
+            if let Some(previous) = ledgers.insert(entry.user, entry.balance) {
+                if previous.checked_add_signed(entry.diff) != Some(entry.balance) {
+                    return Err(invalid(format!("the ledger of user {} doesn't add up", entry.user)));
+                }
+            }
+        }
+        for account in &self.accounts {
+            if ledgers.get(&account.user).is_some_and(|balance| *balance != account.balance) {
+                return Err(invalid(format!("the ledger of user {} doesn't end with their balance", account.user)));
+            }
+        }
+        let total = sum_coins([
+            sum_coins(self.accounts.iter().map(|account| account.balance))?,
+            wagered(&self.bets, &self.parlays, &self.challenges)?,
+            in_markets(&self.bets)?.0,
+        ])?;
+        if total != self.total {
+            return Err(invalid(format!("the balances and wagers add up to {} coins instead of {}", total, self.total)));
+        }
+        Ok(())
+    }
+}
+
+impl Bets {
+    /// Writes the config, accounts, open bets with their side tables, pending parlays, challenges,
+    /// balance adjustments and the ledger of balance changes of a server.
+    ///
+    /// Fails with `BetError::Transfer` if some coins of the accounts are in wagers that aren't on its open bets,
+    /// or if the export couldn't be imported back, like a ledger that doesn't end with the balance of its account.
+    pub fn export_server<W: Write>(&self, server: u64, writer: W, format: ExportFormat) -> Result<(), BetError> {
+        let conn = Connection::open(&self.db_path)?;
+        let mut accounts = self.accounts(server)?;
+        accounts.sort_by_key(|account| account.user);
+        let bets = self
+            .bets_of_server(server)?
+            .into_iter()
+            .map(|bet| {
+                Ok(ExportedBet {
+                    limits: conn.bet_limits(bet.bet)?,
+                    numeric: Bets::export_numeric(&conn, bet.bet)?,
+                    guess: Bets::export_guess(&conn, bet.bet)?,
+                    ranked: Bets::export_ranked(&conn, bet.bet)?,
+                    market: Bets::export_market(&conn, bet.bet)?,
+                    bet: bet.bet,
+                    author: bet.author,
+                    desc: bet.desc,
+                    is_open: bet.is_open,
+                    outcomes: bet.outcomes,
+                })
+            })
+            .collect::<Result<Vec<_>, BetError>>()?;
+        let parlays = conn
+            .prepare(
+                "SELECT uuid, user, stake, value
+                FROM Parlay
+                WHERE server = ?1 AND payout IS NULL
+                ORDER BY uuid",
+            )
+            .unwrap()
+            .query_map([server], |row| Ok(ExportedParlay {
+                parlay: row.get::<usize, u64>(0)?,
+                user: row.get::<usize, u64>(1)?,
+                stake: row.get::<usize, u64>(2)?,
+                value: row.get::<usize, u64>(3)?,
+                legs: Vec::new(),
+            }))?
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .map(|parlay| {
+                // the decided legs are carried by the value
+                let legs = conn
+                    .prepare(
+                        "SELECT bet, outcome
+                        FROM ParlayLeg
+                        WHERE parlay = ?1 AND odds IS NULL
+                        ORDER BY bet",
+                    )
+                    .unwrap()
+                    .query_map([parlay.parlay], |row| Ok(ExportedLeg {
+                        bet: row.get::<usize, u64>(0)?,
+                        outcome: row.get::<usize, usize>(1)?,
+                    }))?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(ExportedParlay { legs, ..parlay })
+            })
+            .collect::<Result<Vec<_>, BetError>>()?;
+        let challenges = conn
+            .prepare(
+                "SELECT uuid, challenger, opponent, desc, stake, expires, accepted, challenger_claim, opponent_claim
+                FROM Challenge
+                WHERE server = ?1
+                ORDER BY uuid",
+            )
+            .unwrap()
+            .query_map([server], |row| Ok(ExportedChallenge {
+                challenge: row.get::<usize, u64>(0)?,
+                challenger: row.get::<usize, u64>(1)?,
+                opponent: row.get::<usize, u64>(2)?,
+                desc: row.get::<usize, String>(3)?,
+                stake: row.get::<usize, u64>(4)?,
+                expires: row.get::<usize, u64>(5)?,
+                accepted: row.get::<usize, u32>(6)? != 0,
+                challenger_claim: row.get::<usize, Option<u64>>(7)?,
+                opponent_claim: row.get::<usize, Option<u64>>(8)?,
+            }))?
+            .collect::<Result<Vec<_>, _>>()?;
+        // wagers left on deleted bets would vanish, they have to be repaired first
+        let (in_markets, positions) = in_markets(&bets)?;
+        let wagered = wagered(&bets, &parlays, &challenges)?;
+        let (in_bets, in_open_bets) = (sum_coins(accounts.iter().map(|account| account.in_bet))?, sum_coins([wagered, positions])?);
+        if in_bets != in_open_bets {
+            return Err(invalid(format!(
+                "{} coins are in bets but only {} in the open bets, see Bets::repair_integrity",
+                in_bets, in_open_bets
+            )));
+        }
+        let adjustments = conn
+            .prepare(
+                "SELECT user, diff, reason, time
+                FROM Adjustment
+                WHERE server = ?1
+                ORDER BY id",
+            )
+            .unwrap()
+            .query_map([server], |row| Ok(Adjustment {
+                user: row.get::<usize, u64>(0)?,
+                diff: row.get::<usize, i64>(1)?,
+                reason: row.get::<usize, String>(2)?,
+                time: row.get::<usize, u64>(3)?,
+            }))?
+            .collect::<Result<Vec<_>, _>>()?;
+        let ledger = conn
+            .prepare(
+                "SELECT user, amount, balance
+                FROM Event
+                WHERE kind = 'BalanceChanged' AND server = ?1
+                AND user IN (SELECT user FROM Account WHERE server = ?1)
+                ORDER BY id",
+            )
+            .unwrap()
+            .query_map([server], |row| Ok(LedgerEntry {
+                user: row.get::<usize, u64>(0)?,
+                diff: row.get::<usize, i64>(1)?,
+                balance: row.get::<usize, u64>(2)?,
+            }))?
+            .collect::<Result<Vec<_>, _>>()?;
+        let export = ServerExport {
+            version: VERSION,
+            server,
+            config: conn.server_config(server)?,
+            total: sum_coins([sum_coins(accounts.iter().map(|account| account.balance))?, wagered, in_markets])?,
+            accounts: accounts.into_iter().map(|account| Account { user: account.user, balance: account.balance }).collect(),
+            bets,
+            parlays,
+            challenges,
+            adjustments,
+            ledger,
+        };
+        // what can't be imported back isn't exported, see Bets::check_integrity
+        export.validate()?;
+        match format {
+            ExportFormat::Json => serde_json::to_writer_pretty(writer, &export).map_err(invalid),
+            ExportFormat::Csv => {
+                let mut csv = csv::Writer::from_writer(writer);
+                for row in export.rows() {
+                    csv.serialize(row).map_err(invalid)?;
+                }
+                csv.flush().map_err(invalid)
+            }
+        }
+    }
+
+    fn export_numeric(conn: &Connection, bet: u64) -> Result<Option<NumericBet>, BetError> {
+        let Some(closest_guess) = conn
+            .query_row("SELECT closest_guess FROM NumericBet WHERE bet = ?1", [bet], |row| row.get::<usize, bool>(0))
+            .optional()?
+        else {
+            return Ok(None);
+        };
+        let ranges = conn
+            .prepare(
+                "SELECT low, high
+                FROM NumericOutcome
+                WHERE bet = ?1
+                ORDER BY number",
+            )
+            .unwrap()
+            .query_map([bet], |row| Ok((row.get::<usize, Option<f64>>(0)?, row.get::<usize, Option<f64>>(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Some(NumericBet { closest_guess, ranges }))
+    }
+
+    fn export_guess(conn: &Connection, bet: u64) -> Result<Option<GuessBet>, BetError> {
+        let Some(scoring) = conn
+            .query_row("SELECT scoring FROM GuessBet WHERE bet = ?1", [bet], |row| row.get::<usize, u32>(0))
+            .optional()?
+        else {
+            return Ok(None);
+        };
+        let guesses = conn
+            .prepare(
+                "SELECT user, guess
+                FROM Guess
+                WHERE bet = ?1
+                ORDER BY user",
+            )
+            .unwrap()
+            .query_map([bet], |row| Ok(Guess { user: row.get::<usize, u64>(0)?, guess: row.get::<usize, f64>(1)? }))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Some(GuessBet { scoring: GuessScoring::from(scoring), guesses }))
+    }
+
+    fn export_ranked(conn: &Connection, bet: u64) -> Result<Option<Vec<RankedWager>>, BetError> {
+        if !conn.prepare("SELECT bet FROM RankedBet WHERE bet = ?1").unwrap().exists([bet])? {
+            return Ok(None);
+        }
+        let wagers = conn
+            .prepare(
+                "SELECT user, pool, pick, amount
+                FROM RankedWager
+                WHERE bet = ?1 AND payout IS NULL
+                ORDER BY user, pool",
+            )
+            .unwrap()
+            .query_map([bet], |row| Ok(RankedWager {
+                user: row.get::<usize, u64>(0)?,
+                pool: row.get::<usize, usize>(1)?,
+                pick: row.get::<usize, String>(2)?.split(',').filter_map(|outcome| outcome.parse().ok()).collect(),
+                amount: row.get::<usize, u64>(3)?,
+            }))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Some(wagers))
+    }
+
+    fn export_market(conn: &Connection, bet: u64) -> Result<Option<Market>, BetError> {
+        let Some(liquidity) = conn
+            .query_row("SELECT liquidity FROM Market WHERE bet = ?1", [bet], |row| row.get::<usize, f64>(0))
+            .optional()?
+        else {
+            return Ok(None);
+        };
+        let shares = conn
+            .prepare(
+                "SELECT user, outcome, shares, cost
+                FROM MarketShares
+                WHERE bet = ?1 AND payout IS NULL
+                ORDER BY user, outcome",
+            )
+            .unwrap()
+            .query_map([bet], |row| Ok(Shares {
+                user: row.get::<usize, u64>(0)?,
+                outcome: row.get::<usize, usize>(1)?,
+                shares: row.get::<usize, u64>(2)?,
+                cost: row.get::<usize, i64>(3)?,
+            }))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Some(Market { liquidity, shares }))
+    }
+
+    /// Restores a server written by `export_server`, into the server `into` if given
+    /// so that servers can be merged, returns the server it was imported into.
+    ///
+    /// The exported config is only applied if the server has none, an existing config is kept.
+    /// The import is refused with `BetError::Transfer` if the coins of the balances and wagers don't add up,
+    /// if a side table, parlay, challenge or ledger doesn't match the bets and accounts,
+    /// or if a user already has an account on the server or a bet, parlay or challenge uuid is taken.
+    ///
+    /// The ledger is recorded as the balance changes of its users. The other accounts, whose changes
+    /// predate the events or were pruned, start with an adjustment of their whole balance with the reason `IMPORT_REASON`.
+    pub fn import_server<R: Read>(&self, reader: R, format: ExportFormat, into: Option<u64>) -> Result<u64, BetError> {
+        let export: ServerExport = match format {
+            ExportFormat::Json => serde_json::from_reader(reader).map_err(invalid)?,
+            ExportFormat::Csv => ServerExport::from_rows(
+                csv::Reader::from_reader(reader).into_deserialize::<Row>().map(|row| row.map_err(invalid)),
+            )?,
+        };
+        export.validate()?;
+        let server = into.unwrap_or(export.server);
+        let mut conn = Connection::open(&self.db_path)?;
+        let tx = conn.transaction()?;
+        for account in &export.accounts {
+            if tx.balance(server, account.user).is_ok() {
+                return Err(invalid(format!("user {} already has an account on server {}", account.user, server)));
+            }
+        }
+        for bet in &export.bets {
+            if tx.bet_info(bet.bet).is_ok() {
+                return Err(invalid(format!("bet {} already exists", bet.bet)));
+            }
+        }
+        for parlay in &export.parlays {
+            if tx.prepare("SELECT uuid FROM Parlay WHERE uuid = ?1").unwrap().exists([parlay.parlay])? {
+                return Err(invalid(format!("parlay {} already exists", parlay.parlay)));
+            }
+        }
+        for challenge in &export.challenges {
+            if tx.prepare("SELECT uuid FROM Challenge WHERE uuid = ?1").unwrap().exists([challenge.challenge])? {
+                return Err(invalid(format!("challenge {} already exists", challenge.challenge)));
+            }
+        }
+        // merging into a configured server keeps its settings
+        let configured = tx.query_row("SELECT COUNT(*) FROM ServerConfig WHERE server = ?1", [server], |row| row.get::<usize, u64>(0))? > 0;
+        if !configured {
+            Bets::write_server_config(&tx, server, &export.config)?;
+        }
+        let mut balances = HashMap::new();
+        for account in &export.accounts {
+            tx.execute(
+                "INSERT
+                INTO Account (server, user, balance)
+                VALUES (?1, ?2, ?3)",
+                [server, account.user, account.balance],
+            )?;
+            balances.insert(account.user, account.balance);
+        }
+        for bet in &export.bets {
+            let descs = bet.outcomes.iter().map(|outcome| &outcome.desc).collect::<Vec<_>>();
+            Bets::insert_bet(&tx, bet.bet, server, bet.author, &bet.desc, &descs)?;
+            if bet.limits != StakeLimits::default() {
+                Bets::write_bet_limits(&tx, bet.bet, &bet.limits)?;
+            }
+            for (outcome, (user, amount)) in bet
+                .outcomes
+                .iter()
+                .enumerate()
+                .flat_map(|(number, outcome)| outcome.wagers.iter().map(move |wager| (number, wager)))
+            {
+                tx.execute(
+                    "INSERT
+                    INTO Wager (bet, outcome, server, user, amount)
+                    VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![bet.bet, outcome, server, user, amount],
+                )?;
+                tx.emit(BetEvent::WagerPlaced { server, bet: bet.bet, user: *user, outcome, amount: *amount })?;
+            }
+            Bets::import_side(&tx, server, bet)?;
+            if !bet.is_open {
+                tx.execute("UPDATE Bet SET is_open = 0 WHERE uuid = ?1", [bet.bet])?;
+                tx.emit(BetEvent::BetLocked { server, bet: bet.bet })?;
+            }
+        }
+        for adjustment in &export.adjustments {
+            tx.execute(
+                "INSERT
+                INTO Adjustment (server, user, diff, reason, time)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![server, adjustment.user, adjustment.diff, adjustment.reason, adjustment.time],
+            )?;
+        }
+        for parlay in &export.parlays {
+            tx.execute(
+                "INSERT
+                INTO Parlay (uuid, server, user, stake, value)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![parlay.parlay, server, parlay.user, parlay.stake, parlay.value],
+            )?;
+            for leg in &parlay.legs {
+                tx.execute(
+                    "INSERT
+                    INTO ParlayLeg (parlay, bet, outcome)
+                    VALUES (?1, ?2, ?3)",
+                    params![parlay.parlay, leg.bet, leg.outcome],
+                )?;
+            }
+            tx.emit(BetEvent::ParlayPlaced { server, parlay: parlay.parlay, user: parlay.user, amount: parlay.value })?;
+        }
+        for challenge in &export.challenges {
+            tx.execute(
+                "INSERT
+                INTO Challenge (uuid, server, challenger, opponent, desc, stake, expires, accepted, challenger_claim, opponent_claim)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![
+                    challenge.challenge,
+                    server,
+                    challenge.challenger,
+                    challenge.opponent,
+                    challenge.desc,
+                    challenge.stake,
+                    challenge.expires,
+                    challenge.accepted,
+                    challenge.challenger_claim,
+                    challenge.opponent_claim,
+                ],
+            )?;
+            let (challenge_uuid, amount) = (challenge.challenge, challenge.stake);
+            tx.emit(BetEvent::ChallengeProposed { server, challenge: challenge_uuid, user: challenge.challenger, amount })?;
+            if challenge.accepted {
+                tx.emit(BetEvent::ChallengeAccepted { server, challenge: challenge_uuid, user: challenge.opponent })?;
+            }
+        }
+        for entry in &export.ledger {
+            balances.remove(&entry.user);
+            tx.emit(BetEvent::BalanceChanged(AccountUpdate { server, user: entry.user, diff: entry.diff, balance: entry.balance }))?;
+        }
+        for (user, balance) in balances.into_iter().sorted_by_key(|(user, _)| *user) {
+            tx.execute(
+                "INSERT
+                INTO Adjustment (server, user, diff, reason, time)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![server, user, balance, IMPORT_REASON, utils::now()],
+            )?;
+            tx.emit(BetEvent::BalanceChanged(AccountUpdate { server, user, diff: balance as i64, balance }))?;
+        }
+        tx.commit()?;
+        self.publish();
+        Ok(server)
+    }
+
+    // Writes the side table of a bet that isn't a regular one, with the events of its wagers
+    fn import_side(tx: &Transaction, server: u64, bet: &ExportedBet) -> Result<(), BetError> {
+        if let Some(numeric) = &bet.numeric {
+            tx.execute(
+                "INSERT
+                INTO NumericBet (bet, closest_guess)
+                VALUES (?1, ?2)",
+                params![bet.bet, numeric.closest_guess],
+            )?;
+            for (number, (low, high)) in numeric.ranges.iter().enumerate() {
+                tx.execute(
+                    "INSERT
+                    INTO NumericOutcome (bet, number, low, high)
+                    VALUES (?1, ?2, ?3, ?4)",
+                    params![bet.bet, number, low, high],
+                )?;
+            }
+        }
+        if let Some(guess) = &bet.guess {
+            tx.execute(
+                "INSERT
+                INTO GuessBet (bet, scoring)
+                VALUES (?1, ?2)",
+                params![bet.bet, u32::from(guess.scoring)],
+            )?;
+            for exported in &guess.guesses {
+                tx.execute(
+                    "INSERT
+                    INTO Guess (bet, user, guess)
+                    VALUES (?1, ?2, ?3)",
+                    params![bet.bet, exported.user, exported.guess],
+                )?;
+            }
+        }
+        if let Some(ranked) = &bet.ranked {
+            tx.execute("INSERT INTO RankedBet (bet) VALUES (?1)", [bet.bet])?;
+            for wager in ranked {
+                tx.execute(
+                    "INSERT
+                    INTO RankedWager (bet, server, user, pool, pick, amount)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![bet.bet, server, wager.user, wager.pool, wager.pick.iter().join(","), wager.amount],
+                )?;
+                tx.emit(BetEvent::RankedWagerPlaced { server, bet: bet.bet, user: wager.user, pool: wager.pool, amount: wager.amount })?;
+            }
+        }
+        if let Some(market) = &bet.market {
+            tx.execute(
+                "INSERT
+                INTO Market (bet, liquidity)
+                VALUES (?1, ?2)",
+                params![bet.bet, market.liquidity],
+            )?;
+            for shares in &market.shares {
+                tx.execute(
+                    "INSERT
+                    INTO MarketShares (bet, outcome, server, user, shares, cost)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![bet.bet, shares.outcome, server, shares.user, shares.shares, shares.cost],
+                )?;
+                let (user, outcome, shares) = (shares.user, shares.outcome, shares.shares as i64);
+                tx.emit(BetEvent::SharesTraded { server, bet: bet.bet, user, outcome, shares })?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,172 @@
+use std::time::Duration;
+use rusqlite::{Connection, OptionalExtension};
+use crate::{utils, amount::Amount, AccountUpdate, Bet, BetError, Bets};
+
+/// How long the result of an operation made with an idempotency key is kept by default,
+/// a retry after that is applied again.
+pub const DEFAULT_KEY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Money-moving operations made with an idempotency key, see `Bets::with_key`.
+#[derive(Debug, Clone)]
+pub struct Idempotent<'a> {
+    bets: &'a Bets,
+    key: String,
+    ttl: Duration,
+}
+
+impl Bets {
+    /// Makes an operation idempotent: its result is stored with the key, in the same transaction,
+    /// and a repeated call with the same key returns that result instead of applying it again.
+    ///
+    /// Keys are shared by every operation and server, reusing one for another operation
+    /// fails with `BetError::KeyReused`.
+    pub fn with_key<S: ToString>(&self, key: S) -> Idempotent<'_> {
+        Idempotent { bets: self, key: key.to_string(), ttl: DEFAULT_KEY_TTL }
+    }
+
+    /// Forgets the idempotency keys older than `max_age`, returns how many were removed.
+    pub fn expire_keys(&self, max_age: Duration) -> Result<usize, BetError> {
+        let mut conn = Connection::open(&self.db_path)?;
+        let tx = conn.transaction()?;
+        let created_before = utils::now().saturating_sub(max_age.as_secs());
+        tx.execute(
+            "DELETE
+            FROM IdempotentUpdate
+            WHERE key IN (SELECT key FROM IdempotencyKey WHERE created <= ?1)",
+            [created_before],
+        )?;
+        let expired = tx.execute(
+            "DELETE
+            FROM IdempotencyKey
+            WHERE created <= ?1",
+            [created_before],
+        )?;
+        tx.commit()?;
+        Ok(expired)
+    }
+}
+
+impl Idempotent<'_> {
+    /// Keeps the result for `ttl` instead of `DEFAULT_KEY_TTL`.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    // The result recorded for the key, unless it expired
+    fn replay(&self, operation: &str) -> Result<Option<Vec<AccountUpdate>>, BetError> {
+        let mut conn = Connection::open(&self.bets.db_path)?;
+        let Some((recorded_operation, created)) = conn
+            .prepare(
+                "SELECT operation, created
+                FROM IdempotencyKey
+                WHERE key = ?1",
+            )
+            .unwrap()
+            .query_row([&self.key], |row| Ok((row.get::<usize, String>(0)?, row.get::<usize, u64>(1)?)))
+            .optional()?
+        else {
+            return Ok(None);
+        };
+        if created.saturating_add(self.ttl.as_secs()) <= utils::now() {
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM IdempotentUpdate WHERE key = ?1", [&self.key])?;
+            tx.execute("DELETE FROM IdempotencyKey WHERE key = ?1", [&self.key])?;
+            tx.commit()?;
+            return Ok(None);
+        }
+        if recorded_operation != operation {
+            return Err(BetError::KeyReused);
+        }
+        let account_updates = conn
+            .prepare(
+                "SELECT server, user, diff, balance
+                FROM IdempotentUpdate
+                WHERE key = ?1
+                ORDER BY number",
+            )
+            .unwrap()
+            .query_map([&self.key], |row| Ok(AccountUpdate {
+                server: row.get::<usize, u64>(0)?,
+                user: row.get::<usize, u64>(1)?,
+                diff: row.get::<usize, i64>(2)?,
+                balance: row.get::<usize, u64>(3)?,
+            }))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Some(account_updates))
+    }
+
+    /// `Bets::bet_on`, a replay returns the original account update with the current state of the bet.
+    pub fn bet_on<A>(
+        &self,
+        bet: u64,
+        outcome: usize,
+        user: u64,
+        amount: A,
+    ) -> Result<(AccountUpdate, Bet), BetError>
+    where A: Into<Amount> {
+        let Some(account_updates) = self.replay("bet_on")? else {
+            return self.bets.measure("bet_on", || {
+                self.bets.bet_on_keyed(bet, outcome, user, amount.into(), Some(&self.key))
+            });
+        };
+        Ok((account_updates.into_iter().next().ok_or(BetError::NotFound)?, self.bets.bet(bet)?))
+    }
+
+    /// `Bets::income`
+    pub fn income(&self, server: u64, income: u64) -> Result<Vec<AccountUpdate>, BetError> {
+        match self.replay("income")? {
+            Some(account_updates) => Ok(account_updates),
+            None => self.bets.measure("income", || {
+                self.bets.pay_accounts("WHERE server = ?2", [income, server], Some(&self.key))
+            }),
+        }
+    }
+
+    /// `Bets::pay_income`
+    pub fn pay_income(&self, server: u64) -> Result<Vec<AccountUpdate>, BetError> {
+        let income = self.bets.server_config(server)?.income;
+        self.income(server, income)
+    }
+
+    /// `Bets::resolve`
+    pub fn resolve(&self, bet: u64, winning_outcome: usize) -> Result<Vec<AccountUpdate>, BetError> {
+        match self.replay("resolve")? {
+            Some(account_updates) => Ok(account_updates),
+            None => self.bets.measure("resolve", || self.bets.resolve_keyed(bet, winning_outcome, Some(&self.key))),
+        }
+    }
+
+    /// `Bets::resolve_numeric`, it shares its keys with `resolve`
+    pub fn resolve_numeric(&self, bet: u64, value: f64) -> Result<Vec<AccountUpdate>, BetError> {
+        match self.replay("resolve")? {
+            Some(account_updates) => Ok(account_updates),
+            None => self.bets.resolve_numeric_keyed(bet, value, Some(&self.key)),
+        }
+    }
+
+    /// `Bets::resolve_ranking`, it shares its keys with `resolve`
+    pub fn resolve_ranking(&self, bet: u64, ranking: &[usize]) -> Result<Vec<AccountUpdate>, BetError> {
+        match self.replay("resolve")? {
+            Some(account_updates) => Ok(account_updates),
+            None => self.bets.measure("resolve_ranking", || self.bets.resolve_ranking_keyed(bet, ranking, Some(&self.key))),
+        }
+    }
+
+    /// `Bets::adjust_balance`
+    pub fn adjust_balance<S>(&self, server: u64, user: u64, diff: i64, reason: S) -> Result<AccountUpdate, BetError>
+    where S: ToString {
+        match self.replay("adjust_balance")? {
+            Some(account_updates) => account_updates.into_iter().next().ok_or(BetError::NotFound),
+            None => self.bets.adjust_balance_keyed(server, user, diff, reason, Some(&self.key)),
+        }
+    }
+
+    /// `Bets::abort_bet`
+    pub fn abort_bet(&self, bet: u64) -> Result<Vec<AccountUpdate>, BetError> {
+        match self.replay("abort_bet")? {
+            Some(account_updates) => Ok(account_updates),
+            None => self.bets.measure("abort_bet", || self.bets.abort_keyed(bet, Some(&self.key))),
+        }
+    }
+}
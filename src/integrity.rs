@@ -0,0 +1,343 @@
+//! Consistency checks of the database. Other tools writing to it don't necessarily enforce foreign keys,
+//! and every change of balance made by the library is recorded as a `BalanceChanged` event,
+//! so coins created or destroyed outside of it show up as a gap in these events.
+use std::fmt::{self, Display};
+use rusqlite::{params, Connection};
+use crate::{bet_transaction::BetTransaction, BetError, Bets};
+
+/// An inconsistency found by `Bets::check_integrity`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(tag = "kind", rename_all = "snake_case"))]
+pub enum Violation {
+    NegativeBalance { server: u64, user: u64, balance: i64 },
+    /// The balance is not the one left by its last `BalanceChanged` event
+    UnrecordedChange { server: u64, user: u64, balance: i64, recorded: i64 },
+    /// A `BalanceChanged` event doesn't start from the balance left by the previous one
+    BrokenLedger { server: u64, user: u64, event: u64, previous: i64, diff: i64, balance: i64 },
+    WagerWithoutAccount { bet: u64, server: u64, user: u64, amount: i64 },
+    WagerOnMissingOutcome { bet: u64, outcome: i64, server: u64, user: u64, amount: i64 },
+    WagerOnDeletedBet { bet: u64, server: u64, user: u64, amount: i64 },
+    /// The Bet row is gone, the outcomes can outlive it since they don't cascade from it
+    WagerOnMissingBet { bet: u64, server: u64, user: u64, amount: i64 },
+    /// A ranked wager that wasn't paid, on a bet that doesn't exist
+    RankedWagerOnMissingBet { bet: u64, pool: u64, server: u64, user: u64, amount: i64 },
+    /// The shares of a user in a market that wasn't settled, with what they spent on them
+    SharesOnMissingBet { bet: u64, server: u64, user: u64, cost: i64 },
+    /// An undecided leg of a pending parlay, on a bet that doesn't exist
+    ParlayLegOnMissingBet { parlay: u64, bet: u64, server: u64, user: u64 },
+    BetWithoutOutcome { bet: u64 },
+}
+
+impl Violation {
+    // The wager that can be refunded, if the violation is an orphaned wager
+    fn orphaned_wager(&self) -> Option<(u64, u64, u64, i64)> {
+        match *self {
+            Violation::WagerOnMissingOutcome { bet, server, user, amount, .. }
+            | Violation::WagerOnDeletedBet { bet, server, user, amount }
+            | Violation::WagerOnMissingBet { bet, server, user, amount } => Some((bet, server, user, amount)),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::NegativeBalance { server, user, balance } => {
+                write!(f, "account {}/{} has a negative balance of {}", server, user, balance)
+            }
+            Violation::UnrecordedChange { server, user, balance, recorded } => write!(
+                f,
+                "account {}/{} has {} coins but its last recorded change left it with {}",
+                server, user, balance, recorded
+            ),
+            Violation::BrokenLedger { server, user, event, previous, diff, balance } => write!(
+                f,
+                "account {}/{} went from {} to {} coins at event {} but the change was {:+}",
+                server, user, previous, balance, event, diff
+            ),
+            Violation::WagerWithoutAccount { bet, server, user, .. } => {
+                write!(f, "wager of user {} on bet {} has no account on server {}", user, bet, server)
+            }
+            Violation::WagerOnMissingOutcome { bet, outcome, user, .. } => {
+                write!(f, "wager of user {} on bet {} is on the missing outcome {}", user, bet, outcome)
+            }
+            Violation::WagerOnDeletedBet { bet, user, .. } => {
+                write!(f, "wager of user {} on bet {} is on a deleted bet", user, bet)
+            }
+            Violation::WagerOnMissingBet { bet, user, .. } => {
+                write!(f, "wager of user {} on bet {} is on a bet that doesn't exist", user, bet)
+            }
+            Violation::RankedWagerOnMissingBet { bet, pool, user, .. } => write!(
+                f,
+                "ranked wager of user {} in pool {} of bet {} is on a bet that doesn't exist",
+                user, pool, bet
+            ),
+            Violation::SharesOnMissingBet { bet, user, .. } => {
+                write!(f, "shares of user {} in market {} are on a bet that doesn't exist", user, bet)
+            }
+            Violation::ParlayLegOnMissingBet { parlay, bet, user, .. } => {
+                write!(f, "parlay {} of user {} has a leg on bet {}, which doesn't exist", parlay, user, bet)
+            }
+            Violation::BetWithoutOutcome { bet } => write!(f, "bet {} has no outcome", bet),
+        }
+    }
+}
+
+/// What `Bets::check_integrity` found
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct IntegrityReport {
+    /// The inconsistencies left in the database
+    pub violations: Vec<Violation>,
+    /// The inconsistencies that were repaired, by `Bets::repair_integrity`
+    pub repaired: Vec<Violation>,
+}
+
+impl IntegrityReport {
+    /// Whether the database is consistent
+    pub fn is_ok(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+fn violations(conn: &Connection) -> Result<Vec<Violation>, BetError> {
+    let mut violations = conn
+        .prepare(
+            "SELECT server, user, balance
+            FROM Account
+            WHERE balance < 0",
+        )
+        .unwrap()
+        .query_map([], |row| Ok(Violation::NegativeBalance {
+            server: row.get::<usize, u64>(0)?,
+            user: row.get::<usize, u64>(1)?,
+            balance: row.get::<usize, i64>(2)?,
+        }))?
+        .collect::<Result<Vec<_>, _>>()?;
+    // accounts older than the events have no change recorded, they are trusted
+    violations.extend(
+        conn.prepare(
+            "SELECT Account.server, Account.user, Account.balance, Event.balance
+            FROM Account
+            JOIN Event ON Event.id = (
+                SELECT MAX(id)
+                FROM Event
+                WHERE kind = 'BalanceChanged' AND Event.server = Account.server AND Event.user = Account.user
+            )
+            WHERE Account.balance != Event.balance",
+        )
+        .unwrap()
+        .query_map([], |row| Ok(Violation::UnrecordedChange {
+            server: row.get::<usize, u64>(0)?,
+            user: row.get::<usize, u64>(1)?,
+            balance: row.get::<usize, i64>(2)?,
+            recorded: row.get::<usize, i64>(3)?,
+        }))?
+        .collect::<Result<Vec<_>, _>>()?,
+    );
+    violations.extend(
+        conn.prepare(
+            "SELECT server, user, id, previous, amount, balance
+            FROM (
+                SELECT id, server, user, amount, balance,
+                    LAG(balance) OVER (PARTITION BY server, user ORDER BY id) AS previous
+                FROM Event
+                WHERE kind = 'BalanceChanged'
+            )
+            WHERE previous + amount != balance
+            ORDER BY id",
+        )
+        .unwrap()
+        .query_map([], |row| Ok(Violation::BrokenLedger {
+            server: row.get::<usize, u64>(0)?,
+            user: row.get::<usize, u64>(1)?,
+            event: row.get::<usize, u64>(2)?,
+            previous: row.get::<usize, i64>(3)?,
+            diff: row.get::<usize, i64>(4)?,
+            balance: row.get::<usize, i64>(5)?,
+        }))?
+        .collect::<Result<Vec<_>, _>>()?,
+    );
+    let wagers = [
+        "WHERE NOT EXISTS (SELECT * FROM Account WHERE Account.server = Wager.server AND Account.user = Wager.user)",
+        "WHERE NOT EXISTS (SELECT * FROM Outcome WHERE Outcome.bet = Wager.bet AND Outcome.number = Wager.outcome)",
+        "WHERE bet IN (SELECT bet FROM ToDelete)",
+        "WHERE NOT EXISTS (SELECT * FROM Bet WHERE Bet.uuid = Wager.bet)",
+    ];
+    for (check, filter) in wagers.iter().enumerate() {
+        let mut stmt = conn
+            .prepare(&format!(
+                "SELECT bet, outcome, server, user, amount
+                FROM Wager
+                {}
+                ORDER BY bet, user",
+                filter
+            ))
+            .unwrap();
+        let found = stmt
+            .query_map([], |row| {
+                let (bet, outcome, server, user, amount) = (
+                    row.get::<usize, u64>(0)?,
+                    row.get::<usize, i64>(1)?,
+                    row.get::<usize, u64>(2)?,
+                    row.get::<usize, u64>(3)?,
+                    row.get::<usize, i64>(4)?,
+                );
+                Ok(match check {
+                    0 => Violation::WagerWithoutAccount { bet, server, user, amount },
+                    1 => Violation::WagerOnMissingOutcome { bet, outcome, server, user, amount },
+                    2 => Violation::WagerOnDeletedBet { bet, server, user, amount },
+                    _ => Violation::WagerOnMissingBet { bet, server, user, amount },
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        violations.extend(found);
+    }
+    violations.extend(
+        conn.prepare(
+            "SELECT bet, pool, server, user, amount
+            FROM RankedWager
+            WHERE payout IS NULL AND NOT EXISTS (SELECT * FROM Bet WHERE Bet.uuid = RankedWager.bet)
+            ORDER BY bet, user, pool",
+        )
+        .unwrap()
+        .query_map([], |row| Ok(Violation::RankedWagerOnMissingBet {
+            bet: row.get::<usize, u64>(0)?,
+            pool: row.get::<usize, u64>(1)?,
+            server: row.get::<usize, u64>(2)?,
+            user: row.get::<usize, u64>(3)?,
+            amount: row.get::<usize, i64>(4)?,
+        }))?
+        .collect::<Result<Vec<_>, _>>()?,
+    );
+    violations.extend(
+        conn.prepare(
+            "SELECT bet, server, user, SUM(cost)
+            FROM MarketShares
+            WHERE payout IS NULL AND NOT EXISTS (SELECT * FROM Bet WHERE Bet.uuid = MarketShares.bet)
+            GROUP BY bet, server, user
+            ORDER BY bet, user",
+        )
+        .unwrap()
+        .query_map([], |row| Ok(Violation::SharesOnMissingBet {
+            bet: row.get::<usize, u64>(0)?,
+            server: row.get::<usize, u64>(1)?,
+            user: row.get::<usize, u64>(2)?,
+            cost: row.get::<usize, i64>(3)?,
+        }))?
+        .collect::<Result<Vec<_>, _>>()?,
+    );
+    violations.extend(
+        conn.prepare(
+            "SELECT Parlay.uuid, ParlayLeg.bet, Parlay.server, Parlay.user
+            FROM ParlayLeg
+            JOIN Parlay ON Parlay.uuid = ParlayLeg.parlay
+            WHERE ParlayLeg.odds IS NULL AND Parlay.payout IS NULL
+            AND NOT EXISTS (SELECT * FROM Bet WHERE Bet.uuid = ParlayLeg.bet)
+            ORDER BY Parlay.uuid, ParlayLeg.bet",
+        )
+        .unwrap()
+        .query_map([], |row| Ok(Violation::ParlayLegOnMissingBet {
+            parlay: row.get::<usize, u64>(0)?,
+            bet: row.get::<usize, u64>(1)?,
+            server: row.get::<usize, u64>(2)?,
+            user: row.get::<usize, u64>(3)?,
+        }))?
+        .collect::<Result<Vec<_>, _>>()?,
+    );
+    violations.extend(
+        conn.prepare(
+            "SELECT uuid
+            FROM Bet
+            WHERE NOT EXISTS (SELECT * FROM Outcome WHERE Outcome.bet = Bet.uuid)",
+        )
+        .unwrap()
+        .query_map([], |row| Ok(Violation::BetWithoutOutcome { bet: row.get::<usize, u64>(0)? }))?
+        .collect::<Result<Vec<_>, _>>()?,
+    );
+    Ok(violations)
+}
+
+impl Bets {
+    /// Looks for the inconsistencies that the library itself never leaves behind:
+    /// negative balances, coins created or destroyed without a recorded change,
+    /// wagers without an account, on a missing outcome, a deleted bet or a bet that doesn't exist,
+    /// unsettled ranked wagers, market shares and parlay legs on a bet that doesn't exist, bets without outcomes.
+    pub fn check_integrity(&self) -> Result<IntegrityReport, BetError> {
+        let conn = Connection::open(&self.db_path)?;
+        Ok(IntegrityReport { violations: violations(&conn)?, repaired: Vec::new() })
+    }
+
+    /// `check_integrity`, and repairs what can be repaired without guessing: the wagers
+    /// on a missing outcome, a deleted bet or a bet that doesn't exist are refunded, if their account still exists,
+    /// and so are the ranked wagers and market shares on a bet that doesn't exist, as if it was aborted.
+    /// The parlay legs on a bet that doesn't exist are void, like those on an aborted bet.
+    pub fn repair_integrity(&self) -> Result<IntegrityReport, BetError> {
+        let mut conn = Connection::open(&self.db_path)?;
+        let tx = conn.transaction()?;
+        let mut repaired = Vec::new();
+        let has_account = |server: u64, user: u64| -> Result<bool, BetError> {
+            Ok(tx.query_row(
+                "SELECT COUNT(*)
+                FROM Account
+                WHERE server = ?1 AND user = ?2",
+                [server, user],
+                |row| row.get::<usize, u64>(0),
+            )? > 0)
+        };
+        for violation in violations(&tx)? {
+            match violation {
+                Violation::RankedWagerOnMissingBet { bet, pool, server, user, amount } => {
+                    if has_account(server, user)?
+                        && amount >= 0
+                        && tx.execute(
+                            "DELETE FROM RankedWager WHERE bet = ?1 AND pool = ?2 AND user = ?3 AND payout IS NULL",
+                            params![bet, pool, user],
+                        )? > 0
+                    {
+                        tx.change_balance(server, user, amount)?;
+                        repaired.push(violation);
+                    }
+                }
+                Violation::SharesOnMissingBet { bet, server, user, cost } => {
+                    // like in an aborted market, those who sold at a profit keep it
+                    if has_account(server, user)?
+                        && tx.execute(
+                            "DELETE FROM MarketShares WHERE bet = ?1 AND user = ?2 AND payout IS NULL",
+                            params![bet, user],
+                        )? > 0
+                    {
+                        if cost > 0 {
+                            tx.change_balance(server, user, cost)?;
+                        }
+                        repaired.push(violation);
+                    }
+                }
+                // the other legs on the bet are voided at the same time
+                Violation::ParlayLegOnMissingBet { bet, server, user, .. } => {
+                    if has_account(server, user)? {
+                        tx.settle_parlay_legs(bet, None)?;
+                        repaired.push(violation);
+                    }
+                }
+                _ => {
+                    let Some((bet, server, user, amount)) = violation.orphaned_wager() else {
+                        continue;
+                    };
+                    // the wager may have been refunded already, as it can be orphaned in several ways
+                    if has_account(server, user)?
+                        && amount >= 0
+                        && tx.execute("DELETE FROM Wager WHERE bet = ?1 AND user = ?2", params![bet, user])? > 0
+                    {
+                        tx.change_balance(server, user, amount)?;
+                        repaired.push(violation);
+                    }
+                }
+            }
+        }
+        tx.commit()?;
+        self.publish();
+        Ok(IntegrityReport { violations: violations(&conn)?, repaired })
+    }
+}
@@ -3,15 +3,59 @@ mod db_structs;
 mod bet_connection;
 mod bet_transaction;
 mod bets;
+mod numeric;
+mod ranked;
+mod market;
+mod challenge;
+mod events;
+mod idempotency;
+mod backup;
+mod integrity;
+mod metrics;
+mod lookup;
+pub mod chat;
+pub mod render;
+#[cfg(feature = "export")]
+mod export;
+#[cfg(feature = "async")]
+mod async_bets;
 pub mod utils;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "admin")]
+pub mod admin;
 pub use amount::Amount;
-pub use bets::Bets;
+pub use bets::{Bets, SCHEMA_VERSION};
+pub use numeric::{NumericOutcomes, GuessScoring};
+pub use ranked::RankedPick;
+pub use events::BetEvent;
+pub use idempotency::{Idempotent, DEFAULT_KEY_TTL};
+pub use backup::Snapshots;
+pub use integrity::{IntegrityReport, Violation};
+#[cfg(feature = "export")]
+pub use export::{ExportFormat, IMPORT_REASON};
+#[cfg(feature = "async")]
+pub use async_bets::AsyncBets;
 pub use db_structs::*;
 
 #[cfg(test)]
 mod tests {
     use crate::*;
 
+    fn test_db_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("betting_{}.db", name));
+        path.to_str().unwrap().to_string()
+    }
+
+    // Opens a fresh database in the temp dir, so that tests don't step on each other
+    fn test_bets(name: &str) -> Result<Bets, BetError> {
+        let path = test_db_path(name);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path, suffix));
+        }
+        Bets::new(&path)
+    }
+
     #[test]
     fn bet_demo() -> Result<(), BetError> {
         // variables for readability
@@ -19,7 +63,7 @@ mod tests {
         let bet_id = 1;
         let (alice, bob, charlie) = (0, 1, 2);
         // Create the database
-        let bets = Bets::new("bets.db")?;
+        let bets = test_bets("demo")?;
         // Create 3 accounts on server 1 with 100 starting coins
         bets.create_account(server_id, alice, 100)?;
         bets.create_account(server_id, bob, 100)?;
@@ -28,7 +72,7 @@ mod tests {
         bets.create_bet(
             bet_id, server_id, alice,
             "Who will win the Rocket League 1v1 ?",
-            &vec!["Alice", "Bob"],
+            &["Alice", "Bob"],
         )?;
         // Alice bets on herself (outcome with id 0) with 10 coins
         bets.bet_on(bet_id, 0, alice, 10)?;
@@ -50,11 +94,1509 @@ mod tests {
         // The winning side gets 10 + 40 + 50 = 100 coins
         // split proportionally among the betters
         // Alice had bet 10 out of 60 of this outcome, she wins 100*(10/60) = 16.6 rounded to 17
-        assert_eq!(bets.balance(server_id, alice)?, 107);
+        // minus the 10% fee since both outcomes were bet on, 15
+        assert_eq!(bets.balance(server_id, alice)?, 105);
         // Bob doesn't win anything since he bet on the wrong outcome
         assert_eq!(bets.balance(server_id, bob)?, 60);
         // Charlie had bet 50 out of 60 of this outcome, he wins 100*(50/60) = 83.3 rounded to 83
-        assert_eq!(bets.balance(server_id, charlie)?, 133);
+        // minus the 10% fee, 74
+        assert_eq!(bets.balance(server_id, charlie)?, 124);
+        Ok(())
+    }
+
+    #[test]
+    fn rollback_resolution() -> Result<(), BetError> {
+        let bets = test_bets("rollback")?;
+        let (alice, bob) = (0, 1);
+        bets.create_account(1, alice, 100)?;
+        bets.create_account(1, bob, 100)?;
+        bets.create_bet(1, 1, alice, "Coin flip", &["Heads", "Tails"])?;
+        bets.bet_on(1, 0, alice, 50)?;
+        bets.bet_on(1, 1, bob, 50)?;
+        bets.lock_bet(1)?;
+        assert!(matches!(bets.resolve(1, 2), Err(BetError::InvalidOutcome)));
+        assert_eq!(bets.account(1, alice)?.in_bet, 50);
+        bets.resolve(1, 1)?;
+        assert_eq!(bets.balance(1, bob)?, 140);
+        // the bet survives a restart and can be rolled back
+        let bets = Bets::new(&test_db_path("rollback"))?;
+        let updates = bets.rollback_resolution(1, ClawbackPolicy::Refuse)?;
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].diff, -90);
+        assert_eq!(bets.account(1, alice)?.in_bet, 50);
+        assert_eq!(bets.account(1, bob)?.in_bet, 50);
+        assert_eq!(bets.balance(1, bob)?, 50);
+        assert!(!bets.get_info(1)?.is_open);
+        assert_eq!(bets.events(bets.last_event()? - 1, 10)?[0].1, BetEvent::BetRolledBack { server: 1, bet: 1 });
+        // it was the other side that won
+        bets.resolve(1, 0)?;
+        assert_eq!(bets.balance(1, alice)?, 140);
+        // alice spends more than her own stake, a partial clawback would create coins
+        bets.create_bet(2, 1, bob, "Another one", &["Yes", "No"])?;
+        bets.bet_on(2, 0, alice, 120)?;
+        assert!(matches!(
+            bets.rollback_resolution(1, ClawbackPolicy::Partial),
+            Err(BetError::NotEnoughMoney)
+        ));
+        bets.abort_bet(2)?;
+        // she spends her winnings, the clawback can only be partial and her stake covers the rest
+        bets.create_bet(3, 1, bob, "Yet another one", &["Yes", "No"])?;
+        bets.bet_on(3, 0, alice, 100)?;
+        assert!(matches!(
+            bets.rollback_resolution(1, ClawbackPolicy::Refuse),
+            Err(BetError::NotEnoughMoney)
+        ));
+        let updates = bets.rollback_resolution(1, ClawbackPolicy::Partial)?;
+        assert_eq!(updates[0].diff, -40);
+        assert_eq!(bets.balance(1, alice)?, 0);
+        assert_eq!(bets.account(1, alice)?.in_bet, 100);
+        assert_eq!(bets.account(1, bob)?.in_bet, 50);
+        assert!(matches!(
+            bets.rollback_resolution(1, ClawbackPolicy::Partial),
+            Err(BetError::NotFound)
+        ));
+        // old resolutions can be purged, they can't be rolled back anymore
+        bets.resolve(1, 1)?;
+        // a bet resolved without being locked can't be locked afterwards
+        bets.create_bet(4, 1, bob, "Last one", &["Yes", "No"])?;
+        bets.resolve(4, 0)?;
+        let last_event = bets.last_event()?;
+        assert!(matches!(bets.lock_bet(4), Err(BetError::NotFound)));
+        assert_eq!(bets.last_event()?, last_event);
+        assert_eq!(bets.purge_resolutions(std::time::Duration::from_secs(3600))?, 0);
+        assert_eq!(bets.purge_resolutions(std::time::Duration::ZERO)?, 2);
+        assert!(matches!(bets.get_info(1), Err(BetError::NotFound)));
+        assert!(bets.check_integrity()?.is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn parlay() -> Result<(), BetError> {
+        let bets = test_bets("parlay")?;
+        let (alice, bob, charlie) = (0, 1, 2);
+        for user in [alice, bob, charlie] {
+            bets.create_account(1, user, 100)?;
+        }
+        bets.create_bet(1, 1, alice, "Match 1", &["A", "B"])?;
+        bets.create_bet(2, 1, alice, "Match 2", &["C", "D"])?;
+        bets.create_bet(3, 1, alice, "Match 3", &["E", "F"])?;
+        assert!(matches!(
+            bets.create_parlay(1, 1, charlie, &[(1, 0), (1, 1)], 10),
+            Err(BetError::InvalidParlay)
+        ));
+        bets.create_guess_bet(4, 1, alice, "Score ?", GuessScoring::Closest)?;
+        assert!(matches!(
+            bets.create_parlay(1, 1, charlie, &[(1, 0), (4, 0)], 10),
+            Err(BetError::InvalidParlay)
+        ));
+        assert!(matches!(
+            bets.create_parlay(1, 1, charlie, &[(1, 0), (2, 0)], 1.5),
+            Err(BetError::InvalidFraction)
+        ));
+        bets.create_parlay(1, 1, charlie, &[(1, 0), (2, 0), (3, 0)], 10)?;
+        bets.create_parlay(2, 1, charlie, &[(1, 1), (2, 0)], 10)?;
+        assert_eq!(
+            bets.events(bets.last_event()? - 1, 10)?[0].1,
+            BetEvent::ParlayPlaced { server: 1, parlay: 2, user: charlie, amount: 10 }
+        );
+        assert_eq!(bets.balance(1, charlie)?, 80);
+        assert_eq!(bets.account(1, charlie)?.in_bet, 20);
+        bets.bet_on(1, 0, alice, 20)?;
+        bets.bet_on(1, 1, bob, 20)?;
+        bets.bet_on(2, 0, alice, 10)?;
+        bets.bet_on(2, 1, bob, 30)?;
+        // bet 1: the parlays join the pool of 60 with a 10% fee, 30 on the winner so x1.8, parlay 2 is lost
+        let updates = bets.resolve(1, 0)?;
+        assert_eq!(updates.iter().map(|update| (update.user, update.diff)).collect::<Vec<_>>(), vec![(alice, 36)]);
+        assert_eq!(bets.parlay(2)?.payout, Some(0));
+        let parlay = bets.parlay(1)?;
+        assert_eq!((parlay.value, parlay.legs[0].odds, parlay.legs[0].stake), (18, Some(1.8), Some(10)));
+        assert_eq!(bets.account(1, charlie)?.in_bet, 18);
+        // bet 2: 18 rides on a pool of 58 with 28 on the winner, the parlay gets 37 before the fee
+        bets.resolve(2, 0)?;
+        // bet 3 is aborted, the leg is void and the parlay pays its value
+        let updates = bets.abort_bet(3)?;
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].diff, 33);
+        assert_eq!((bets.balance(1, charlie)?, bets.parlay(1)?.payout), (113, Some(33)));
+        // without a fee, the parlays only move coins between the pools
+        let (dave, erin) = (3, 4);
+        bets.create_account(2, dave, 100)?;
+        bets.create_account(2, erin, 100)?;
+        bets.set_server_config(2, &ServerConfig { fee: 0., ..bets.server_config(2)? })?;
+        let coins = || -> Result<u64, BetError> {
+            Ok(bets.accounts(2)?.iter().map(|account| account.balance + account.in_bet).sum())
+        };
+        bets.create_bet(11, 2, dave, "Match 11", &["A", "B"])?;
+        bets.create_bet(12, 2, dave, "Match 12", &["C", "D"])?;
+        bets.create_parlay(3, 2, dave, &[(11, 0), (12, 0)], 10)?;
+        bets.bet_on(11, 1, erin, 10)?;
+        bets.bet_on(12, 1, erin, 20)?;
+        bets.resolve(11, 0)?;
+        assert_eq!((bets.parlay(3)?.value, coins()?), (20, 200));
+        bets.resolve(12, 0)?;
+        assert_eq!((bets.balance(2, dave)?, bets.balance(2, erin)?, coins()?), (130, 70, 200));
+        // rolling back a leg claws back the payout, the parlay rides on it again with its value before it
+        bets.rollback_resolution(12, ClawbackPolicy::Refuse)?;
+        let parlay = bets.parlay(3)?;
+        assert_eq!((parlay.value, parlay.payout, parlay.legs[1].odds), (20, None, None));
+        assert_eq!((bets.balance(2, dave)?, coins()?), (90, 200));
+        bets.resolve(12, 1)?;
+        assert_eq!((bets.balance(2, erin)?, bets.parlay(3)?.payout, coins()?), (110, Some(0), 200));
+        // its value went to the winners of bet 12, the win on bet 11 can't be taken back
+        assert!(matches!(bets.rollback_resolution(11, ClawbackPolicy::Refuse), Err(BetError::ParlayLost)));
+        // the settled parlays are deleted with the bets they rode on
+        assert_eq!(bets.purge_resolutions(std::time::Duration::ZERO)?, 4);
+        assert!(matches!(bets.parlay(3), Err(BetError::NotFound)));
+        assert!(bets.check_integrity()?.is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn numeric_bets() -> Result<(), BetError> {
+        let bets = test_bets("numeric")?;
+        let (alice, bob) = (0, 1);
+        bets.create_account(1, alice, 100)?;
+        bets.create_account(1, bob, 100)?;
+        assert!(matches!(
+            bets.create_numeric_bet(1, 1, alice, "Kills ?", &NumericOutcomes::Ranges(vec![10., 5.]), false),
+            Err(BetError::InvalidBounds)
+        ));
+        bets.create_numeric_bet(1, 1, alice, "Goals ?", &NumericOutcomes::OverUnder(2.5), false)?;
+        let (_, bet) = bets.bet_on(1, 0, alice, 10)?;
+        assert_eq!(bet.outcomes[0].desc, "Under 2.5");
+        assert_eq!(bet.outcomes[1].desc, "Over 2.5");
+        bets.bet_on(1, 1, bob, 10)?;
+        for value in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            assert!(matches!(bets.resolve_numeric(1, value), Err(BetError::InvalidBounds)));
+        }
+        let updates = bets.resolve_numeric(1, 3.)?;
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].user, bob);
+        // nobody bet on 10 to 20, the closest range with wagers wins
+        bets.create_numeric_bet(2, 1, alice, "Kills ?", &NumericOutcomes::Ranges(vec![10., 20., 30.]), true)?;
+        let (_, bet) = bets.bet_on(2, 0, alice, 10)?;
+        let descs: Vec<_> = bet.outcomes.iter().map(|outcome| outcome.desc.as_str()).collect();
+        assert_eq!(descs, ["< 10", "10 to 20", "20 to 30", ">= 30"]);
+        bets.bet_on(2, 3, bob, 10)?;
+        let updates = bets.resolve_numeric(2, 12.)?;
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].user, alice);
+        Ok(())
+    }
+
+    #[test]
+    fn guess_bets() -> Result<(), BetError> {
+        let bets = test_bets("guess")?;
+        let (alice, bob, charlie) = (0, 1, 2);
+        for user in [alice, bob, charlie] {
+            bets.create_account(1, user, 100)?;
+        }
+        bets.create_guess_bet(1, 1, alice, "How many kills ?", GuessScoring::Closest)?;
+        bets.guess_on(1, alice, 8., 30)?;
+        assert!(matches!(bets.guess_on(1, alice, 9., 10), Err(BetError::MultiOpt(_))));
+        assert!(matches!(bets.guess_on(1, bob, f64::NAN, 10), Err(BetError::InvalidGuess)));
+        assert!(matches!(bets.bet_on(1, 0, bob, 10), Err(BetError::InvalidGuess)));
+        assert!(matches!(bets.resolve(1, 0), Err(BetError::InvalidGuess)));
+        assert!(matches!(bets.resolve_numeric(1, f64::NAN), Err(BetError::InvalidBounds)));
+        bets.guess_on(1, bob, 12., 10)?;
+        bets.guess_on(1, charlie, 15., 60)?;
+        // alice and bob are both 2 away, they split the pot of 100 3 to 1, minus the 10% fee
+        let updates = bets.resolve_numeric(1, 10.)?;
+        assert_eq!(updates.len(), 2);
+        assert_eq!(bets.balance(1, alice)?, 70 + 67);
+        assert_eq!(bets.balance(1, bob)?, 90 + 22);
+        assert_eq!(bets.balance(1, charlie)?, 40);
+        // with inverse distance weighting, everyone gets something
+        bets.create_guess_bet(2, 1, alice, "How many deaths ?", GuessScoring::InverseDistance)?;
+        bets.guess_on(2, alice, 5., 20)?;
+        bets.guess_on(2, bob, 7., 20)?;
+        let updates = bets.resolve_numeric(2, 5.)?;
+        // weights of 20/1 and 20/3, so 30 and 10 of the pot of 40, minus the fee
+        assert_eq!(updates[0].diff, 27);
+        assert_eq!(updates[1].diff, 9);
+        // large stakes are weighted the same way
+        let (dave, eve) = (3, 4);
+        bets.create_account(1, dave, 4_000_000_000_000_000_000)?;
+        bets.create_account(1, eve, 4_000_000_000_000_000_000)?;
+        bets.create_guess_bet(3, 1, dave, "How many stars ?", GuessScoring::InverseDistance)?;
+        bets.guess_on(3, dave, 5., 4_000_000_000_000_000_000)?;
+        bets.guess_on(3, eve, 7., 4_000_000_000_000_000_000)?;
+        let updates = bets.resolve_numeric(3, 5.)?;
+        assert!((updates[0].diff as f64 / updates[1].diff as f64 - 3.).abs() < 1e-9);
+        let gains = utils::lrm(u64::MAX, &[u64::MAX, u64::MAX, 1]);
+        assert_eq!(gains.iter().map(|gain| *gain as u128).sum::<u128>(), u64::MAX as u128);
+        Ok(())
+    }
+
+    #[test]
+    fn ranked_bets() -> Result<(), BetError> {
+        let bets = test_bets("ranked")?;
+        let (alice, bob, charlie) = (0, 1, 2);
+        for user in [alice, bob, charlie] {
+            bets.create_account(1, user, 100)?;
+        }
+        bets.create_ranked_bet(1, 1, alice, "Race", &["Red", "Green", "Blue", "Yellow"])?;
+        bets.create_bet(2, 1, alice, "Not a race", &["Red", "Green"])?;
+        assert!(matches!(bets.resolve_ranking(2, &[0, 1]), Err(BetError::InvalidRanking)));
+        bets.bet_on_ranked(1, alice, RankedPick::Win(0), 10)?;
+        bets.bet_on_ranked(1, bob, RankedPick::Win(1), 10)?;
+        bets.bet_on_ranked(1, alice, RankedPick::Exact(vec![0, 2]), 10)?;
+        assert!(matches!(
+            bets.bet_on_ranked(1, alice, RankedPick::Exact(vec![2, 0]), 10),
+            Err(BetError::MultiOpt(_))
+        ));
+        bets.bet_on_ranked(1, bob, RankedPick::Exact(vec![0, 1]), 30)?;
+        bets.bet_on_ranked(1, charlie, RankedPick::Show(3), 20)?;
+        bets.bet_on_ranked(1, charlie, RankedPick::Exact(vec![3, 2, 1]), 5)?;
+        assert_eq!(
+            bets.events(bets.last_event()? - 2, 10)?[1].1,
+            BetEvent::RankedWagerPlaced { server: 1, bet: 1, user: charlie, pool: 3, amount: 5 }
+        );
+        assert_eq!(bets.account(1, alice)?.in_bet, 20);
+        assert!(matches!(bets.resolve(1, 0), Err(BetError::InvalidRanking)));
+        let updates = bets.resolve_ranking(1, &[0, 2, 3, 1])?;
+        // alice wins the regular pool (20 - 10%) and the exacta pool (40 - 10%),
+        // charlie gets the show pool back since he was alone in it, the trifecta pool has no winner
+        assert_eq!(bets.balance(1, alice)?, 80 + 18 + 36);
+        assert_eq!(bets.balance(1, bob)?, 60);
+        assert_eq!(bets.balance(1, charlie)?, 100);
+        assert_eq!(updates.len(), 4);
+        // the ranked pools are rolled back too
+        bets.rollback_resolution(1, ClawbackPolicy::Refuse)?;
+        assert_eq!(bets.account(1, alice)?.in_bet, 20);
+        assert_eq!(bets.account(1, charlie)?.in_bet, 25);
+        bets.abort_bet(1)?;
+        for user in [alice, bob, charlie] {
+            assert_eq!(bets.balance(1, user)?, 100);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn market() -> Result<(), BetError> {
+        let bets = test_bets("market")?;
+        let (alice, bob) = (0, 1);
+        bets.create_account(1, alice, 100)?;
+        bets.create_account(1, bob, 100)?;
+        bets.create_market(1, 1, alice, "Will it rain ?", &["Yes", "No"], 10.)?;
+        assert_eq!(bets.market_prices(1)?, vec![0.5, 0.5]);
+        // there's no parimutuel pool next to the market
+        assert!(matches!(bets.bet_on(1, 0, bob, 10), Err(BetError::MarketTrade)));
+        // 10 * ln((e + 1) / 2) = 6.2 rounded up
+        assert_eq!(bets.buy_shares(1, 0, alice, 10)?.diff, -7);
+        let prices = bets.market_prices(1)?;
+        assert!(prices[0] > 0.7 && prices[0] < 0.75);
+        assert!(matches!(bets.sell_shares(1, 1, alice, 1), Err(BetError::NotEnoughShares)));
+        assert!(matches!(bets.buy_shares(1, 0, bob, u64::MAX), Err(BetError::NotEnoughMoney)));
+        assert!(matches!(bets.sell_shares(1, 0, alice, u64::MAX), Err(BetError::NotEnoughShares)));
+        // the coins spent on shares make the pool
+        bets.set_bet_limits(1, &StakeLimits { max_pool_share: Some(0.5), ..Default::default() })?;
+        assert!(matches!(bets.buy_shares(1, 1, bob, 30), Err(BetError::StakeOutOfRange { min: 1, max: Some(7) })));
+        bets.set_bet_limits(1, &StakeLimits { max_stake: Some(15), ..Default::default() })?;
+        assert!(matches!(bets.buy_shares(1, 1, bob, 40), Err(BetError::StakeOutOfRange { .. })));
+        bets.buy_shares(1, 1, bob, 20)?;
+        bets.sell_shares(1, 0, alice, 5)?;
+        assert_eq!(
+            bets.events(bets.last_event()? - 1, 10)?[0].1,
+            BetEvent::SharesTraded { server: 1, bet: 1, user: alice, outcome: 0, shares: -5 }
+        );
+        assert_eq!(bets.market_shares(1, alice)?, vec![5, 0]);
+        // the coins spent on the shares are in bets
+        for user in [alice, bob] {
+            let account = bets.account(1, user)?;
+            assert!(account.in_bet > 0);
+            assert_eq!(account.balance + account.in_bet, 100);
+        }
+        bets.lock_bet(1)?;
+        assert!(matches!(bets.buy_shares(1, 0, bob, 1), Err(BetError::BetLocked)));
+        let bob_balance = bets.balance(1, bob)?;
+        let updates = bets.resolve(1, 1)?;
+        assert_eq!(updates.len(), 1);
+        assert_eq!(bets.balance(1, bob)?, bob_balance + 20);
+        assert_eq!(bets.accounts(1)?.iter().map(|account| account.in_bet).sum::<u64>(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn challenges() -> Result<(), BetError> {
+        use std::time::Duration;
+        let bets = test_bets("challenge")?;
+        let (alice, bob, charlie) = (0, 1, 2);
+        for user in [alice, bob, charlie] {
+            bets.create_account(1, user, 100)?;
+        }
+        let hour = Duration::from_secs(3600);
+        bets.propose_challenge(1, 1, alice, bob, "I win the next game", 50, hour)?;
+        assert_eq!(bets.account(1, alice)?.in_bet, 50);
+        assert!(matches!(bets.accept_challenge(1, charlie), Err(BetError::NotParticipant)));
+        assert!(matches!(bets.claim_challenge(1, alice, alice), Err(BetError::NotAccepted)));
+        bets.accept_challenge(1, bob)?;
+        assert_eq!(bets.balance(1, bob)?, 50);
+        assert!(matches!(bets.accept_challenge(1, bob), Err(BetError::BetLocked)));
+        assert_eq!(bets.balance(1, bob)?, 50);
+        // they disagree until alice admits defeat
+        assert!(bets.claim_challenge(1, alice, alice)?.is_none());
+        assert!(bets.claim_challenge(1, bob, bob)?.is_none());
+        let update = bets.claim_challenge(1, alice, bob)?.unwrap();
+        assert_eq!(update.user, bob);
+        assert_eq!(bets.balance(1, bob)?, 150);
+        assert!(matches!(bets.challenge(1), Err(BetError::NotFound)));
+        // a moderator can settle it
+        bets.propose_challenge(2, 1, bob, charlie, "Rematch", 0.5, hour)?;
+        bets.accept_challenge(2, charlie)?;
+        bets.resolve_challenge(2, charlie)?;
+        assert_eq!(bets.balance(1, charlie)?, 175);
+        assert_eq!(
+            bets.events(bets.last_event()? - 2, 10)?[0].1,
+            BetEvent::ChallengeSettled { server: 1, challenge: 2, winner: charlie }
+        );
+        // the expiry saturates rather than overflowing
+        bets.propose_challenge(4, 1, alice, bob, "Someday", 10, Duration::MAX)?;
+        bets.decline_challenge(4, bob)?;
+        // challenges that are not accepted in time are refunded
+        bets.propose_challenge(3, 1, charlie, alice, "Too late", 10, Duration::ZERO)?;
+        assert!(matches!(bets.accept_challenge(3, alice), Err(BetError::Expired)));
+        let updates = bets.expire_challenges()?;
+        assert_eq!(updates.len(), 1);
+        assert_eq!(bets.balance(1, charlie)?, 175);
+        Ok(())
+    }
+
+    #[test]
+    fn server_config() -> Result<(), BetError> {
+        let bets = test_bets("config")?;
+        let (alice, bob) = (0, 1);
+        assert_eq!(bets.server_config(1)?, ServerConfig::default());
+        assert!(matches!(
+            bets.set_server_config(1, &ServerConfig { fee: 1.5, ..Default::default() }),
+            Err(BetError::InvalidConfig(_))
+        ));
+        bets.set_server_config(1, &ServerConfig {
+            starting_balance: 200,
+            fee: 0.5,
+            limits: StakeLimits { min_stake: Some(5), max_stake: Some(100), ..Default::default() },
+            allow_fractions: false,
+            income: 25,
+        })?;
+        assert_eq!(bets.open_account(1, alice)?.balance, 200);
+        bets.open_account(1, bob)?;
+        // other servers keep the default config
+        assert_eq!(bets.open_account(2, alice)?.balance, 100);
+        bets.pay_income(1)?;
+        assert_eq!(bets.balance(1, alice)?, 225);
+        assert_eq!(bets.balance(2, alice)?, 100);
+        bets.create_bet(1, 1, alice, "Coin flip", &["Heads", "Tails"])?;
+        assert!(matches!(bets.bet_on(1, 0, alice, 0.5), Err(BetError::FractionsNotAllowed)));
+        assert!(matches!(
+            bets.bet_on(1, 0, alice, 4),
+            Err(BetError::StakeOutOfRange { min: 5, max: Some(100) })
+        ));
+        assert!(matches!(bets.bet_on(1, 0, alice, 101), Err(BetError::StakeOutOfRange { .. })));
+        bets.bet_on(1, 0, alice, 100)?;
+        bets.bet_on(1, 1, bob, 100)?;
+        // the 50% fee of the server is applied
+        bets.resolve(1, 0)?;
+        assert_eq!(bets.balance(1, alice)?, 225);
+        Ok(())
+    }
+
+    #[test]
+    fn stake_limits() -> Result<(), BetError> {
+        let bets = test_bets("limits")?;
+        let (alice, bob, whale) = (0, 1, 2);
+        bets.create_account(1, alice, 100)?;
+        bets.create_account(1, bob, 100)?;
+        bets.create_account(1, whale, 10000)?;
+        bets.set_server_config(1, &ServerConfig {
+            limits: StakeLimits { min_fraction: Some(0.05), max_fraction: Some(0.5), ..Default::default() },
+            ..Default::default()
+        })?;
+        bets.create_bet(1, 1, alice, "Coin flip", &["Heads", "Tails"])?;
+        assert!(matches!(
+            bets.set_bet_limits(1, &StakeLimits { max_pool_share: Some(2.), ..Default::default() }),
+            Err(BetError::InvalidConfig(_))
+        ));
+        bets.set_bet_limits(1, &StakeLimits { max_stake: Some(40), max_pool_share: Some(0.5), ..Default::default() })?;
+        // 5% to 50% of the balance, and at most 40 on this bet
+        assert_eq!(bets.stake_range(1, alice)?, Some((5, Some(40))));
+        assert!(matches!(
+            bets.bet_on(1, 0, alice, 4),
+            Err(BetError::StakeOutOfRange { min: 5, max: Some(40) })
+        ));
+        bets.bet_on(1, 0, alice, 30)?;
+        // the whale can't own more than half of the pool, which is less than their minimum stake of 500
+        assert_eq!(bets.stake_range(1, whale)?, None);
+        assert!(matches!(bets.bet_on(1, 1, whale, 500), Err(BetError::NoStakeAllowed)));
+        bets.bet_on(1, 1, bob, 20)?;
+        // alice already owns 30 out of 50 so she can't stake anymore, bob can add 10 to match her
+        assert_eq!(bets.stake_range(1, alice)?, None);
+        assert!(matches!(bets.bet_on(1, 0, alice, 5), Err(BetError::NoStakeAllowed)));
+        assert_eq!(bets.stake_range(1, bob)?, Some((4, Some(10))));
+        Ok(())
+    }
+
+    #[test]
+    fn events() -> Result<(), BetError> {
+        let bets = test_bets("events")?;
+        let (alice, bob) = (0, 1);
+        bets.create_account(1, alice, 100)?;
+        let feed = bets.subscribe(0)?;
+        bets.create_account(1, bob, 100)?;
+        bets.create_bet(1, 1, alice, "Rain tomorrow ?", &["Yes", "No"])?;
+        bets.bet_on(1, 0, alice, 10)?;
+        bets.lock_bet(1)?;
+        bets.resolve(1, 0)?;
+        let received: Vec<_> = feed.try_iter().collect();
+        // the subscription caught up with the account created before it
+        assert_eq!(received, bets.events(0, 100)?);
+        let events: Vec<BetEvent> = received.into_iter().map(|(_, event)| event).collect();
+        assert_eq!(events, vec![
+            BetEvent::BalanceChanged(AccountUpdate { server: 1, user: alice, diff: 100, balance: 100 }),
+            BetEvent::BalanceChanged(AccountUpdate { server: 1, user: bob, diff: 100, balance: 100 }),
+            BetEvent::BetCreated { server: 1, bet: 1, author: alice },
+            BetEvent::BalanceChanged(AccountUpdate { server: 1, user: alice, diff: -10, balance: 90 }),
+            BetEvent::WagerPlaced { server: 1, bet: 1, user: alice, outcome: 0, amount: 10 },
+            BetEvent::BetLocked { server: 1, bet: 1 },
+            BetEvent::BalanceChanged(AccountUpdate { server: 1, user: alice, diff: 10, balance: 100 }),
+            BetEvent::BetResolved { server: 1, bet: 1, outcome: 0 },
+        ]);
+        // a consumer restarting from the last event it handled only gets the new ones
+        let last = bets.events(0, 100)?.last().unwrap().0;
+        // changes from another process are picked up on poll
+        let other = Bets::new(&test_db_path("events"))?;
+        other.create_bet(2, 1, bob, "Snow tomorrow ?", &["Yes", "No"])?;
+        other.abort_bet(2)?;
+        assert!(feed.try_recv().is_err());
+        bets.poll_events()?;
+        let received: Vec<_> = feed.try_iter().collect();
+        assert_eq!(received, bets.events(last, 100)?);
+        assert_eq!(received.last().unwrap().1, BetEvent::BetAborted { server: 1, bet: 2 });
+        Ok(())
+    }
+
+    #[test]
+    fn outbox() -> Result<(), BetError> {
+        let bets = test_bets("outbox")?;
+        bets.create_account(1, 0, 100)?;
+        bets.create_bet(1, 1, 0, "Rain tomorrow ?", &["Yes", "No"])?;
+        bets.bet_on(1, 0, 0, 10)?;
+        let pending = bets.pending_events("bot", 100)?;
+        assert_eq!(pending.len(), 4);
+        let ids: Vec<u64> = pending.iter().map(|(id, _)| *id).collect();
+        // acknowledged out of order, the rest is still pending (even after a restart)
+        bets.acknowledge_events("bot", &[ids[0], ids[2]])?;
+        let bets = Bets::new(&test_db_path("outbox"))?;
+        assert_eq!(bets.pending_events("bot", 100)?, vec![pending[1].clone(), pending[3].clone()]);
+        // consumers are independent
+        assert_eq!(bets.pending_events("logger", 100)?, pending);
+        // acknowledging twice is harmless
+        bets.acknowledge_events("bot", &[ids[1], ids[3], ids[3]])?;
+        assert!(bets.pending_events("bot", 100)?.is_empty());
+        // an event that doesn't exist yet can't be acknowledged
+        bets.acknowledge_events("bot", &[ids[3] + 1])?;
+        bets.abort_bet(1)?;
+        let pending = bets.pending_events("bot", 100)?;
+        assert_eq!(pending.last().unwrap().1, BetEvent::BetAborted { server: 1, bet: 1 });
+        assert_eq!(pending[0].0, ids[3] + 1);
+        // events are kept until every consumer acknowledged them, and the last one is always kept
+        bets.acknowledge_events("logger", &[])?;
+        assert_eq!(bets.prune_events(u64::MAX)?, 0);
+        let last = bets.last_event()?;
+        bets.acknowledge_events("logger", &(1..=last).collect::<Vec<_>>())?;
+        assert_eq!(bets.prune_events(u64::MAX)?, 4);
+        bets.acknowledge_events("bot", &[pending[0].0, pending[1].0])?;
+        assert_eq!(bets.prune_events(u64::MAX)?, 1);
+        assert_eq!(bets.events(0, 100)?.len(), 1);
+        assert_eq!(bets.last_event()?, last);
+        Ok(())
+    }
+
+    #[test]
+    fn idempotency_keys() -> Result<(), BetError> {
+        use std::time::Duration;
+        let bets = test_bets("idempotency")?;
+        let (alice, bob) = (0, 1);
+        bets.create_account(1, alice, 100)?;
+        bets.create_account(1, bob, 100)?;
+        bets.create_bet(1, 1, alice, "Rain tomorrow ?", &["Yes", "No"])?;
+        // the retry of a timed out call is not applied twice
+        let (update, _) = bets.with_key("wager-1").bet_on(1, 0, alice, 10)?;
+        let (retry, bet) = bets.with_key("wager-1").bet_on(1, 0, alice, 10)?;
+        assert_eq!(update, retry);
+        assert_eq!(bet.outcomes[0].wagers, vec![(alice, 10)]);
+        assert_eq!(bets.balance(1, alice)?, 90);
+        assert!(matches!(bets.with_key("wager-1").abort_bet(1), Err(BetError::KeyReused)));
+        bets.with_key("wager-2").bet_on(1, 1, bob, 10)?;
+        let payouts = bets.with_key("resolve-1").resolve(1, 0)?;
+        assert_eq!(bets.with_key("resolve-1").resolve(1, 0)?, payouts);
+        assert_eq!(bets.balance(1, alice)?, 108);
+        let income = bets.with_key("income-1").income(1, 10)?;
+        assert_eq!(bets.with_key("income-1").income(1, 10)?, income);
+        assert_eq!(bets.balance(1, bob)?, 100);
+        // once expired, the key can be used again
+        bets.with_key("income-1").ttl(Duration::ZERO).income(1, 10)?;
+        assert_eq!(bets.balance(1, bob)?, 110);
+        assert_eq!(bets.expire_keys(Duration::ZERO)?, 4);
+        bets.with_key("wager-1").income(1, 10)?;
+        assert_eq!(bets.balance(1, bob)?, 120);
+        // a key that never expires
+        let reward = bets.with_key("reward-1").ttl(Duration::MAX).adjust_balance(1, bob, 5, "reward")?;
+        assert_eq!(bets.with_key("reward-1").ttl(Duration::MAX).adjust_balance(1, bob, 5, "reward")?, reward);
+        assert_eq!(bets.balance(1, bob)?, 125);
+        bets.create_numeric_bet(2, 1, alice, "Goals ?", &NumericOutcomes::OverUnder(2.5), false)?;
+        bets.bet_on(2, 1, bob, 10)?;
+        let payouts = bets.with_key("resolve-2").resolve_numeric(2, 3.)?;
+        assert_eq!(bets.with_key("resolve-2").resolve_numeric(2, 3.)?, payouts);
+        bets.create_ranked_bet(3, 1, alice, "Race", &["Red", "Blue"])?;
+        bets.bet_on_ranked(3, bob, RankedPick::Show(1), 10)?;
+        let payouts = bets.with_key("resolve-3").resolve_ranking(3, &[1, 0])?;
+        assert_eq!(bets.with_key("resolve-3").resolve_ranking(3, &[1, 0])?, payouts);
+        assert_eq!(bets.balance(1, bob)?, 125);
+        Ok(())
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn http_server() -> Result<(), BetError> {
+        use std::io::{Read, Write};
+        let bets = test_bets("server")?;
+        let http = std::sync::Arc::new(tiny_http::Server::http("127.0.0.1:0").unwrap());
+        let addr = http.server_addr().to_ip().unwrap();
+        let serving = std::thread::spawn({
+            let (bets, http) = (bets.clone(), http.clone());
+            move || server::serve(&bets, &http)
+        });
+        let keyed_request = |method: &str, url: &str, key: &str, body: &str| {
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            let key = if key.is_empty() { String::new() } else { format!("Idempotency-Key: {}\r\n", key) };
+            write!(
+                stream,
+                "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}Content-Length: {}\r\n\r\n{}",
+                method, url, key, body.len(), body
+            ).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            let (head, body) = response.split_once("\r\n\r\n").unwrap();
+            let status: u16 = head.split(' ').nth(1).unwrap().parse().unwrap();
+            (status, serde_json::from_str::<serde_json::Value>(body).unwrap())
+        };
+        let request = |method: &str, url: &str, body: &str| keyed_request(method, url, "", body);
+        assert_eq!(request("POST", "/servers/1/accounts", r#"{"user": 0}"#).0, 201);
+        assert_eq!(request("POST", "/servers/1/accounts", r#"{"user": 1, "balance": 50}"#).0, 201);
+        let (status, body) = request("POST", "/servers/1/accounts", r#"{"user": 1}"#);
+        assert_eq!((status, body["error"]["code"].as_str()), (409, Some("already_exists")));
+        let (status, body) = request(
+            "POST", "/servers/1/bets",
+            r#"{"bet": 1, "author": 0, "desc": "Rain tomorrow ?", "outcomes": ["Yes", "No"]}"#,
+        );
+        assert_eq!((status, body["outcomes"][1]["desc"].as_str()), (201, Some("No")));
+        let wager = r#"{"user": 0, "outcome": 0, "amount": "50%"}"#;
+        let (status, body) = keyed_request("POST", "/bets/1/wagers", "wager-1", wager);
+        assert_eq!((status, body["account_update"]["balance"].as_u64()), (201, Some(50)));
+        // a retry with the same idempotency key is not placed twice
+        keyed_request("POST", "/bets/1/wagers", "wager-1", wager);
+        assert_eq!(request("GET", "/bets/1", "").1["outcomes"][0]["wagers"][0]["amount"], 50);
+        let (status, body) = request("POST", "/bets/1/wagers", r#"{"user": 1, "outcome": 1, "amount": 500}"#);
+        assert_eq!((status, &body["error"]), (422, &serde_json::to_value(BetError::NotEnoughMoney).unwrap()));
+        // the details of the error come along
+        let (status, body) = request("POST", "/bets/1/wagers", r#"{"user": 0, "outcome": 1, "amount": 10}"#);
+        assert_eq!((status, body["error"]["code"].as_str()), (409, Some("multiple_options")));
+        assert_eq!(body["error"]["options"].as_array().map(Vec::len), Some(2));
+        let (status, body) = request("POST", "/bets/1/wagers", r#"{"user": 1, "outcome": 1, "amount": "150%"}"#);
+        assert_eq!((status, body["error"]["code"].as_str()), (400, Some("bad_request")));
+        request("POST", "/bets/1/wagers", r#"{"user": 1, "outcome": 1, "amount": 50}"#);
+        assert_eq!(request("POST", "/bets/1/lock", "").1["is_open"], false);
+        // an outcome that doesn't exist would take every stake
+        let (status, body) = request("POST", "/bets/1/resolve", r#"{"outcome": 2}"#);
+        assert_eq!((status, body["error"]["code"].as_str()), (400, Some("invalid_outcome")));
+        let (status, body) = request("POST", "/bets/1/resolve", r#"{"outcome": 0}"#);
+        assert_eq!((status, body[0]["balance"].as_u64()), (200, Some(140)));
+        let (status, body) = request("GET", "/servers/1/leaderboard", "");
+        assert_eq!((status, body[0]["user"].as_u64(), body[1]["balance"].as_u64()), (200, Some(0), Some(0)));
+        assert_eq!(request("GET", "/servers/1/accounts/1", "").1["balance"], 0);
+        assert_eq!(request("GET", "/bets/7", "").0, 404);
+        let (status, body) = request("DELETE", "/bets/1", "");
+        assert_eq!((status, body["error"]["code"].as_str(), body["error"]["message"].as_str()), (404, Some("no_route"), Some("no such route")));
+        http.unblock();
+        serving.join().unwrap();
+        Ok(())
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn live_feed() -> Result<(), BetError> {
+        let bets = test_bets("live")?;
+        let (alice, bob) = (0, 1);
+        bets.create_account(1, alice, 100)?;
+        bets.create_account(1, bob, 100)?;
+        bets.create_bet(1, 1, alice, "Rain tomorrow ?", &["Yes", "No"])?;
+        let http = std::sync::Arc::new(tiny_http::Server::http("127.0.0.1:0").unwrap());
+        let addr = http.server_addr().to_ip().unwrap();
+        let serving = std::thread::spawn({
+            let (bets, http) = (bets.clone(), http.clone());
+            move || server::serve(&bets, &http)
+        });
+        let connect = |path: &str| tungstenite::connect(format!("ws://{}{}", addr, path)).unwrap().0;
+        // the next JSON message, skipping the pings
+        let next = |socket: &mut tungstenite::WebSocket<_>| loop {
+            if let tungstenite::Message::Text(text) = socket.read().unwrap() {
+                break serde_json::from_str::<serde_json::Value>(&text).unwrap();
+            }
+        };
+        let mut bet_feed = connect("/bets/1/live");
+        let snapshot = next(&mut bet_feed);
+        assert_eq!((snapshot["type"].as_str(), snapshot["bet"]["outcomes"][1]["desc"].as_str()), (Some("snapshot"), Some("No")));
+        bets.bet_on(1, 0, alice, 10)?;
+        let update = next(&mut bet_feed);
+        assert_eq!(update["event"]["kind"], "wager_placed");
+        assert_eq!(update["bet"]["outcomes"][0]["wagers"][0]["amount"], 10);
+        let mut server_feed = connect("/servers/1/live");
+        let snapshot = next(&mut server_feed);
+        assert_eq!((snapshot["accounts"][0]["balance"].as_u64(), snapshot["bets"][0]["bet"].as_u64()), (Some(90), Some(1)));
+        bets.lock_bet(1)?;
+        assert_eq!(next(&mut bet_feed)["event"]["kind"], "bet_locked");
+        assert_eq!(next(&mut server_feed)["event"]["kind"], "bet_locked");
+        // changes made by another process show up too
+        Bets::new(&test_db_path("live"))?.resolve(1, 0)?;
+        let update = next(&mut server_feed);
+        assert_eq!((update["event"]["kind"].as_str(), update["event"]["balance"].as_u64()), (Some("balance_changed"), Some(100)));
+        assert_eq!(next(&mut server_feed)["event"]["kind"], "bet_resolved");
+        let update = next(&mut bet_feed);
+        assert_eq!((update["event"]["kind"].as_str(), update["bet"]["is_open"].as_bool()), (Some("bet_resolved"), Some(false)));
+        // the feed answers a close
+        bet_feed.close(None).unwrap();
+        let closed = loop {
+            if let Err(err) = bet_feed.read() {
+                break err;
+            }
+        };
+        assert!(matches!(closed, tungstenite::Error::ConnectionClosed));
+        http.unblock();
+        serving.join().unwrap();
+        Ok(())
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn live_feed_timeout() -> Result<(), BetError> {
+        let bets = test_bets("live_timeout")?;
+        bets.create_account(1, 0, 100)?;
+        let http = std::sync::Arc::new(tiny_http::Server::http("127.0.0.1:0").unwrap());
+        let addr = http.server_addr().to_ip().unwrap();
+        let serving = std::thread::spawn({
+            let (bets, http) = (bets.clone(), http.clone());
+            move || server::serve(&bets, &http)
+        });
+        let start = std::time::Instant::now();
+        let (mut feed, _) = tungstenite::connect(format!("ws://{}/servers/1/live", addr)).unwrap();
+        assert!(matches!(feed.read().unwrap(), tungstenite::Message::Text(_)));
+        // the feed subscribes right after sending the snapshot
+        while bets.subscribers.lock().unwrap().is_empty() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        // the client doesn't answer the ping sent after a second without events,
+        // its subscription is dropped on the next event after the timeout
+        std::thread::sleep(std::time::Duration::from_secs(5));
+        let mut user = 1;
+        while !bets.subscribers.lock().unwrap().is_empty() {
+            assert!(start.elapsed() < std::time::Duration::from_secs(30));
+            std::thread::sleep(std::time::Duration::from_millis(500));
+            bets.create_account(1, user, 100)?;
+            user += 1;
+        }
+        // the feed ends once the client is heard from again
+        drop(feed);
+        http.unblock();
+        serving.join().unwrap();
+        Ok(())
+    }
+
+    #[cfg(feature = "admin")]
+    #[test]
+    fn admin_tool() -> Result<(), BetError> {
+        use clap::Parser;
+        let bets = test_bets("admin")?;
+        let (alice, bob) = (0, 1);
+        bets.create_account(1, alice, 100)?;
+        bets.create_account(1, bob, 100)?;
+        bets.create_account(2, alice, 100)?;
+        bets.create_bet(1, 1, alice, "Rain tomorrow ?", &["Yes", "No"])?;
+        bets.bet_on(1, 0, alice, 30)?;
+        bets.bet_on(1, 1, bob, 10)?;
+        let db = test_db_path("admin");
+        let admin = |args: &str| {
+            let cli = admin::Cli::try_parse_from(format!("betting-admin --db {} {}", db, args).split(' ')).unwrap();
+            admin::run(&cli)
+        };
+        assert_eq!(admin("servers")?, "1\n2");
+        assert_eq!(
+            admin("bet 1")?,
+            "Rain tomorrow ? (open)\n\
+            1. Yes [########--] 75% · 30 coins · x1.33\n   user 0: 30 coins\n\
+            2. No [###-------] 25% · 10 coins · x4.00\n   user 1: 10 coins"
+        );
+        let accounts: serde_json::Value = serde_json::from_str(&admin("--json accounts 1")?).unwrap();
+        assert_eq!((accounts[0]["balance"].as_u64(), accounts[0]["in_bet"].as_u64()), (Some(70), Some(30)));
+        assert_eq!(admin("adjust 1 1 -20 --reason refund")?, "user 1: -20 -> 70 coins");
+        assert!(matches!(admin("adjust 1 1 -500 --reason oops"), Err(BetError::NotEnoughMoney)));
+        assert_eq!(admin("check")?, "No problems found");
+        assert_eq!(admin("abort 1")?, "user 0: +30 -> 100 coins\nuser 1: +10 -> 80 coins");
+        assert_eq!(admin("bets 1")?, "");
+        // a mistyped path is an error rather than a new empty database
+        let missing = test_db_path("admin_missing");
+        let _ = std::fs::remove_file(&missing);
+        let cli = admin::Cli::try_parse_from(["betting-admin", "--db", &missing, "servers"]).unwrap();
+        assert!(admin::run(&cli).is_err());
+        assert!(!std::path::Path::new(&missing).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn outcome_lookup() -> Result<(), BetError> {
+        let bets = test_bets("lookup")?;
+        let (alice, bob) = (0, 1);
+        bets.create_account(1, alice, 100)?;
+        bets.create_account(1, bob, 100)?;
+        bets.create_bet(1, 1, alice, "Who wins ?", &["Alice", "Alicia", "Bob", "Charlie"])?;
+        assert_eq!(bets.find_outcome(1, "Bob")?, 2);
+        assert_eq!(bets.find_outcome(1, "alice")?, 0);
+        assert_eq!(bets.find_outcome(1, "char")?, 3);
+        assert_eq!(bets.find_outcome(1, "Chralie")?, 3);
+        assert!(matches!(bets.find_outcome(1, "zorg"), Err(BetError::NotFound)));
+        match bets.find_outcome(1, "ali") {
+            Err(BetError::AmbiguousOutcome(candidates)) => assert_eq!(candidates, ["Alice", "Alicia"]),
+            other => panic!("expected an ambiguous outcome, got {:?}", other),
+        }
+        let (update, bet) = bets.bet_on_named(1, "bbo", bob, 30)?;
+        assert_eq!((update.balance, bet.outcomes[2].wagers.clone()), (70, vec![(bob, 30)]));
+        assert!(matches!(bets.bet_on_named(2, "Bob", bob, 30), Err(BetError::NotFound)));
+        Ok(())
+    }
+
+    #[test]
+    fn rendering() -> Result<(), BetError> {
+        use render::{Format, Style};
+        let bets = test_bets("render")?;
+        let (alice, bob, charlie) = (0, 1, 2);
+        for user in [alice, bob, charlie] {
+            bets.create_account(1, user, 100)?;
+        }
+        bets.create_bet(1, 1, alice, "Who *wins* ?", &["Alice", "Bob"])?;
+        bets.bet_on(1, 0, alice, 60)?;
+        bets.bet_on(1, 0, charlie, 10)?;
+        let (_, bet) = bets.bet_on(1, 1, bob, 30)?;
+        assert_eq!(
+            bet.to_string(),
+            "Who *wins* ? (open)\n\
+            1. Alice [#######---] 70% · 70 coins · x1.43\n   user 0: 60 coins, user 2: 10 coins\n\
+            2. Bob [###-------] 30% · 30 coins · x3.33\n   user 1: 30 coins"
+        );
+        let markdown = Style {
+            format: Format::Markdown,
+            currency: "points".to_string(),
+            emoji: Some("🪙".to_string()),
+            bar_width: 4,
+            top_bettors: 1,
+            user_name: |user| format!("<@{}>", user),
+        };
+        assert_eq!(
+            markdown.bet(&bet),
+            "**Who \\*wins\\* ?** (open)\n\
+            1. **Alice** `███░` 70% · 🪙 70 points · x1.43\n   <@0>: 🪙 60 points\n\
+            2. **Bob** `█░░░` 30% · 🪙 30 points · x3.33\n   <@1>: 🪙 30 points"
+        );
+        let ansi = Style { format: Format::Ansi, bar_width: 2, ..Style::default() };
+        assert_eq!(ansi.bar(0.5), "\x1b[32m█\x1b[0m\x1b[2m░\x1b[0m");
+        assert_eq!(bets.account(1, alice)?.to_string(), "user 0: 40 coins, 60 coins in bets");
+        assert_eq!(markdown.account(&bets.account(1, alice)?), "<@0>: 🪙 40 points, 🪙 60 points in bets");
+        let updates = bets.resolve(1, 0)?;
+        assert_eq!(
+            Style::default().payouts(&bet, 0, &updates),
+            "Who *wins* ?: Alice won, 2 winners share 89 coins\nuser 0: +77 -> 117 coins\nuser 2: +12 -> 102 coins"
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_format() -> Result<(), BetError> {
+        // snapshots of the JSON shape, changing them breaks the users' stored data and APIs
+        fn snapshot<T>(value: &T, expected: &str)
+        where T: serde::Serialize + serde::de::DeserializeOwned {
+            let json = serde_json::to_string(value).unwrap();
+            assert_eq!(json, expected);
+            let value: T = serde_json::from_str(&json).unwrap();
+            assert_eq!(serde_json::to_string(&value).unwrap(), expected);
+        }
+        let bets = test_bets("serde")?;
+        let (alice, bob) = (0, 1);
+        bets.create_account(1, alice, 100)?;
+        bets.create_account(1, bob, 100)?;
+        bets.create_bet(1, 1, alice, "Rain ?", &["Yes", "No"])?;
+        bets.bet_on(1, 0, alice, 30)?;
+        let (update, bet) = bets.bet_on(1, 1, bob, 0.5)?;
+        snapshot(
+            &bet,
+            r#"{"bet":1,"server":1,"author":0,"desc":"Rain ?","outcomes":[{"desc":"Yes","wagers":[{"user":0,"amount":30}]},{"desc":"No","wagers":[{"user":1,"amount":50}]}],"is_open":true}"#,
+        );
+        snapshot(&update, r#"{"server":1,"user":1,"diff":-50,"balance":50}"#);
+        snapshot(&bets.get_info(1)?, r#"{"desc":"Rain ?","server":1,"author":0,"is_open":true}"#);
+        snapshot(&bets.position(alice, 1)?, r#"{"outcome":0,"amount":30}"#);
+        snapshot(&bets.account(1, bob)?, r#"{"user":1,"balance":50,"in_bet":50}"#);
+        snapshot(&Amount::FLAT(100), "100");
+        snapshot(&Amount::FRACTION(0.5), r#""50%""#);
+        snapshot(&Amount::FRACTION(0.07), r#""7%""#);
+        snapshot(&Amount::FRACTION(0.125), r#""12.5%""#);
+        assert!(serde_json::from_str::<Amount>(r#""150%""#).is_err());
+        snapshot(&ClawbackPolicy::Partial, r#""partial""#);
+        snapshot(&NumericOutcomes::OverUnder(2.5), r#"{"over_under":2.5}"#);
+        snapshot(&RankedPick::Exact(vec![2, 0]), r#"{"exact":[2,0]}"#);
+        snapshot(
+            &BetEvent::WagerPlaced { server: 1, bet: 1, user: 1, outcome: 1, amount: 50 },
+            r#"{"kind":"wager_placed","server":1,"bet":1,"user":1,"outcome":1,"amount":50}"#,
+        );
+        snapshot(&BetEvent::BalanceChanged(update), r#"{"kind":"balance_changed","server":1,"user":1,"diff":-50,"balance":50}"#);
+        let config: ServerConfig = serde_json::from_str(r#"{"fee":0.05,"limits":{"max_stake":500}}"#).unwrap();
+        assert_eq!(
+            config,
+            ServerConfig { fee: 0.05, limits: StakeLimits { max_stake: Some(500), ..Default::default() }, ..Default::default() }
+        );
+        assert_eq!(
+            serde_json::to_string(&BetError::StakeOutOfRange { min: 10, max: None }).unwrap(),
+            r#"{"code":"stake_out_of_range","message":"the stake must be between 10 and anything","min":10,"max":null}"#
+        );
+        assert_eq!(serde_json::to_string(&BetError::NotFound).unwrap(), r#"{"code":"not_found","message":"not found"}"#);
+        Ok(())
+    }
+
+    #[cfg(feature = "export")]
+    #[test]
+    fn server_export() -> Result<(), BetError> {
+        use itertools::Itertools;
+        let bets = test_bets("export")?;
+        let (alice, bob) = (0, 1);
+        let config = ServerConfig { fee: 0.1, income: 10, ..Default::default() };
+        bets.set_server_config(1, &config)?;
+        bets.create_account(1, alice, 100)?;
+        bets.create_account(1, bob, 100)?;
+        bets.adjust_balance(1, bob, 20, "streak reward")?;
+        bets.create_bet(1, 1, alice, "Rain tomorrow ?", &["Yes", "No"])?;
+        bets.set_bet_limits(1, &StakeLimits { max_stake: Some(50), ..Default::default() })?;
+        bets.bet_on(1, 0, alice, 30)?;
+        bets.bet_on(1, 1, bob, 10)?;
+        bets.create_bet(2, 1, bob, "Snow tomorrow ?", &["Yes", "No"])?;
+        bets.bet_on(2, 1, alice, 5)?;
+        bets.lock_bet(2)?;
+        let summary = |bets: &Bets, server| -> Result<_, BetError> {
+            let accounts = bets
+                .accounts(server)?
+                .iter()
+                .map(|account| (account.user, account.balance, account.in_bet))
+                .sorted()
+                .collect::<Vec<_>>();
+            let bets = bets
+                .bets_of_server(server)?
+                .into_iter()
+                .map(|bet| (bet.bet, bet.is_open, bet.outcomes.into_iter().map(|outcome| outcome.wagers).collect::<Vec<_>>()))
+                .collect::<Vec<_>>();
+            Ok((accounts, bets))
+        };
+        let mut json = Vec::new();
+        bets.export_server(1, &mut json, ExportFormat::Json)?;
+        let imported = test_bets("import_json")?;
+        assert_eq!(imported.import_server(&json[..], ExportFormat::Json, None)?, 1);
+        assert_eq!(summary(&imported, 1)?, summary(&bets, 1)?);
+        assert_eq!(imported.server_config(1)?, config);
+        assert_eq!(imported.bet_limits(1)?.max_stake, Some(50));
+        assert!(matches!(imported.import_server(&json[..], ExportFormat::Json, Some(2)), Err(BetError::Transfer(_))));
+        // the exports of the previous version had no side tables, parlays, challenges or ledger
+        let mut previous: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        previous["version"] = 1.into();
+        for key in ["parlays", "challenges", "ledger"] {
+            previous.as_object_mut().unwrap().remove(key);
+        }
+        let previous = serde_json::to_vec(&previous).unwrap();
+        let imported = test_bets("import_previous")?;
+        imported.import_server(&previous[..], ExportFormat::Json, None)?;
+        assert_eq!(summary(&imported, 1)?, summary(&bets, 1)?);
+        // without a ledger, the balances come from an adjustment
+        let mut reexported = Vec::new();
+        imported.export_server(1, &mut reexported, ExportFormat::Json)?;
+        let reexported: serde_json::Value = serde_json::from_slice(&reexported).unwrap();
+        let imports = reexported["adjustments"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|adjustment| adjustment["reason"] == IMPORT_REASON)
+            .map(|adjustment| (adjustment["user"].as_u64().unwrap(), adjustment["diff"].as_i64().unwrap()))
+            .collect::<Vec<_>>();
+        let balances = imported.accounts(1)?.into_iter().map(|account| (account.user, account.balance as i64)).sorted();
+        assert_eq!(imports, balances.collect::<Vec<_>>());
+        assert!(imported.check_integrity()?.is_ok());
+        let mut csv = Vec::new();
+        bets.export_server(1, &mut csv, ExportFormat::Csv)?;
+        let imported = test_bets("import_csv")?;
+        assert_eq!(imported.import_server(&csv[..], ExportFormat::Csv, Some(7))?, 7);
+        assert_eq!(summary(&imported, 7)?, summary(&bets, 1)?);
+        // the fee came along with the config
+        assert_eq!(imported.resolve(1, 0)?, bets.resolve(1, 0)?.into_iter().map(|update| AccountUpdate { server: 7, ..update }).collect::<Vec<_>>());
+        // merging into a configured server keeps its config
+        let merged = test_bets("import_merged")?;
+        merged.set_server_config(8, &ServerConfig::default())?;
+        merged.import_server(&json[..], ExportFormat::Json, Some(8))?;
+        assert_eq!(merged.server_config(8)?, ServerConfig::default());
+        // coins can't appear out of nowhere
+        let mut tampered: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        tampered["accounts"][0]["balance"] = 1000.into();
+        let tampered = serde_json::to_vec(&tampered).unwrap();
+        assert!(matches!(test_bets("import_tampered")?.import_server(&tampered[..], ExportFormat::Json, None), Err(BetError::Transfer(_))));
+        let mut tampered: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        tampered["accounts"][0]["balance"] = u64::MAX.into();
+        let tampered = serde_json::to_vec(&tampered).unwrap();
+        assert!(matches!(test_bets("import_overflow")?.import_server(&tampered[..], ExportFormat::Json, None), Err(BetError::Transfer(_))));
+        // the coins of a wager left on a deleted bet would be lost
+        let conn = rusqlite::Connection::open(test_db_path("export"))?;
+        conn.execute("INSERT INTO ToDelete (bet) VALUES (2)", [])?;
+        assert!(matches!(bets.export_server(1, &mut Vec::new(), ExportFormat::Json), Err(BetError::Transfer(_))));
+        Ok(())
+    }
+
+    #[cfg(feature = "export")]
+    #[test]
+    fn server_export_kinds() -> Result<(), BetError> {
+        let bets = test_bets("export_kinds")?;
+        let (alice, bob, carol) = (0, 1, 2);
+        for user in [alice, bob, carol] {
+            bets.create_account(1, user, 100)?;
+        }
+        bets.create_bet(1, 1, alice, "Rain tomorrow ?", &["Yes", "No"])?;
+        bets.bet_on(1, 0, alice, 10)?;
+        bets.bet_on(1, 1, bob, 10)?;
+        bets.create_numeric_bet(2, 1, alice, "Goals ?", &NumericOutcomes::Ranges(vec![1., 3.]), true)?;
+        bets.bet_on(2, 0, bob, 5)?;
+        bets.create_guess_bet(3, 1, alice, "Temperature ?", GuessScoring::InverseDistance)?;
+        bets.guess_on(3, alice, 21.5, 5)?;
+        bets.guess_on(3, carol, 18., 5)?;
+        bets.create_ranked_bet(4, 1, alice, "Race", &["A", "B", "C", "D"])?;
+        bets.bet_on_ranked(4, bob, RankedPick::Win(0), 5)?;
+        bets.bet_on_ranked(4, bob, RankedPick::Show(2), 5)?;
+        bets.bet_on_ranked(4, carol, RankedPick::Exact(vec![1, 0]), 5)?;
+        bets.create_market(5, 1, alice, "Election", &["Red", "Blue"], 10.)?;
+        bets.buy_shares(5, 0, carol, 8)?;
+        bets.buy_shares(5, 1, bob, 3)?;
+        bets.sell_shares(5, 0, carol, 2)?;
+        bets.create_bet(6, 1, bob, "Sun tomorrow ?", &["Yes", "No"])?;
+        bets.bet_on(6, 1, alice, 10)?;
+        // the leg on the resolved bet is carried by the value of the parlay
+        bets.create_parlay(100, 1, carol, &[(6, 0), (1, 0), (2, 1)], 10)?;
+        bets.resolve(6, 0)?;
+        bets.propose_challenge(200, 1, alice, bob, "Chess", 5, std::time::Duration::from_secs(3600))?;
+        bets.accept_challenge(200, bob)?;
+        bets.claim_challenge(200, alice, alice)?;
+        bets.propose_challenge(201, 1, carol, alice, "Darts", 5, std::time::Duration::from_secs(3600))?;
+        let mut json = Vec::new();
+        bets.export_server(1, &mut json, ExportFormat::Json)?;
+        let exported: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(exported["parlays"][0]["legs"].as_array().unwrap().len(), 2);
+        let ledger = exported["ledger"].as_array().unwrap();
+        assert_eq!(ledger.last().unwrap()["balance"], serde_json::json!(bets.balance(1, carol)?));
+        // importing then exporting again gives back the same export, and the same events
+        let reexport = |imported: &Bets, server| -> Result<serde_json::Value, BetError> {
+            let mut json = Vec::new();
+            imported.export_server(server, &mut json, ExportFormat::Json)?;
+            let mut exported: serde_json::Value = serde_json::from_slice(&json).unwrap();
+            exported["server"] = 1.into();
+            Ok(exported)
+        };
+        let imported = test_bets("import_kinds_json")?;
+        imported.import_server(&json[..], ExportFormat::Json, None)?;
+        assert_eq!(reexport(&imported, 1)?, exported);
+        assert!(imported.check_integrity()?.is_ok());
+        let mut csv = Vec::new();
+        bets.export_server(1, &mut csv, ExportFormat::Csv)?;
+        let imported = test_bets("import_kinds_csv")?;
+        imported.import_server(&csv[..], ExportFormat::Csv, Some(7))?;
+        assert_eq!(reexport(&imported, 7)?, exported);
+        assert!(imported.check_integrity()?.is_ok());
+        // the imported bets play out as the original ones
+        assert_eq!(imported.market_prices(5)?, bets.market_prices(5)?);
+        assert!(matches!(imported.bet_on_ranked(4, bob, RankedPick::Show(1), 5), Err(BetError::MultiOpt(_))));
+        let on_server_7 = |updates: Vec<AccountUpdate>| updates.into_iter().map(|update| AccountUpdate { server: 7, ..update }).collect::<Vec<_>>();
+        assert_eq!(imported.resolve(1, 0)?, on_server_7(bets.resolve(1, 0)?));
+        assert_eq!(imported.resolve_numeric(2, 4.)?, on_server_7(bets.resolve_numeric(2, 4.)?));
+        assert_eq!(imported.resolve_numeric(3, 20.)?, on_server_7(bets.resolve_numeric(3, 20.)?));
+        assert_eq!(imported.resolve_ranking(4, &[1, 0, 2])?, on_server_7(bets.resolve_ranking(4, &[1, 0, 2])?));
+        assert_eq!(imported.resolve(5, 1)?, on_server_7(bets.resolve(5, 1)?));
+        assert_eq!(imported.resolve_challenge(200, bob)?, on_server_7(vec![bets.resolve_challenge(200, bob)?])[0]);
+        assert_eq!(imported.parlay(100)?.payout, bets.parlay(100)?.payout);
+        // the side tables are checked like the rest
+        let tamper = |edit: &dyn Fn(&mut serde_json::Value)| -> Result<bool, BetError> {
+            let mut tampered = exported.clone();
+            edit(&mut tampered);
+            let tampered = serde_json::to_vec(&tampered).unwrap();
+            Ok(matches!(test_bets("import_kinds_tampered")?.import_server(&tampered[..], ExportFormat::Json, None), Err(BetError::Transfer(_))))
+        };
+        assert!(tamper(&|export| export["bets"][1]["numeric"]["ranges"][1][1] = 4.into())?);
+        assert!(tamper(&|export| export["bets"][2]["guess"]["guesses"][1]["user"] = bob.into())?);
+        assert!(tamper(&|export| export["bets"][3]["ranked"][0]["pool"] = 1.into())?);
+        assert!(tamper(&|export| export["bets"][3]["ranked"][1]["pick"] = serde_json::json!([1, 1]))?);
+        assert!(tamper(&|export| export["bets"][4]["market"]["shares"][0]["outcome"] = 2.into())?);
+        assert!(tamper(&|export| export["bets"][4]["market"]["liquidity"] = 0.into())?);
+        assert!(tamper(&|export| export["parlays"][0]["legs"][0]["bet"] = 6.into())?);
+        assert!(tamper(&|export| export["parlays"][0]["legs"][1]["bet"] = 3.into())?);
+        assert!(tamper(&|export| export["challenges"][0]["opponent"] = alice.into())?);
+        assert!(tamper(&|export| export["challenges"][1]["challenger_claim"] = carol.into())?);
+        assert!(tamper(&|export| export["ledger"][3]["diff"] = 50.into())?);
+        assert!(tamper(&|export| export["ledger"].as_array_mut().unwrap().pop().map(drop).unwrap())?);
+        Ok(())
+    }
+
+    #[test]
+    fn backups() -> Result<(), BetError> {
+        let bets = test_bets("backup")?;
+        let (alice, bob) = (0, 1);
+        bets.create_account(1, alice, 100)?;
+        bets.create_bet(1, 1, alice, "Rain tomorrow ?", &["Yes", "No"])?;
+        bets.bet_on(1, 0, alice, 30)?;
+        let snapshot = test_db_path("backup_snapshot");
+        bets.backup_to(&snapshot)?;
+        let events = bets.subscribe(bets.last_event()?)?;
+        bets.create_account(1, bob, 100)?;
+        bets.resolve(1, 0)?;
+        assert!(events.try_iter().count() > 0);
+        bets.restore_from(&snapshot)?;
+        assert_eq!((bets.balance(1, alice)?, bets.bet(1)?.is_open), (70, true));
+        assert!(matches!(bets.balance(1, bob), Err(BetError::NotFound)));
+        let conn = rusqlite::Connection::open(test_db_path("backup"))?;
+        assert_eq!(conn.query_row("PRAGMA journal_mode", [], |row| row.get::<usize, String>(0))?, "wal");
+        // the events after the snapshot happen again with the same ids
+        bets.create_account(1, bob, 50)?;
+        assert!(matches!(events.try_recv(), Ok((_, BetEvent::BalanceChanged(AccountUpdate { diff: 50, .. })))));
+        // only snapshots of the current schema are restored
+        let old = test_bets("backup_old")?;
+        rusqlite::Connection::open(test_db_path("backup_old"))?.pragma_update(None, "user_version", 0)?;
+        assert!(matches!(bets.restore_from(test_db_path("backup_old")), Err(BetError::Backup(_))));
+        drop(old);
+        assert!(matches!(bets.restore_from(test_db_path("backup_missing")), Err(BetError::Backup(_))));
+        let dir = std::env::temp_dir().join("betting_snapshots");
+        let _ = std::fs::remove_dir_all(&dir);
+        for _ in 0..3 {
+            bets.snapshot(&dir, 2)?;
+        }
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+        let snapshots = bets.schedule_snapshots(&dir, std::time::Duration::from_millis(10), 2);
+        let taken = snapshots.results().iter().take(3).collect::<Result<Vec<_>, _>>()?;
+        drop(snapshots);
+        assert!(taken[2].exists() && !taken[0].exists());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn integrity() -> Result<(), BetError> {
+        let bets = test_bets("integrity")?;
+        let (alice, bob, charlie) = (0, 1, 2);
+        for user in [alice, bob, charlie] {
+            bets.create_account(1, user, 100)?;
+        }
+        bets.create_bet(1, 1, alice, "Rain tomorrow ?", &["Yes", "No"])?;
+        bets.bet_on(1, 0, alice, 30)?;
+        bets.bet_on(1, 1, bob, 20)?;
+        bets.bet_on(1, 1, charlie, 10)?;
+        assert!(bets.check_integrity()?.is_ok());
+        // changes made behind the library's back, by a tool that doesn't enforce foreign keys
+        let conn = rusqlite::Connection::open(test_db_path("integrity"))?;
+        conn.pragma_update(None, "foreign_keys", false)?;
+        conn.execute("UPDATE Account SET balance = balance + 1000 WHERE user = ?1", [alice])?;
+        conn.execute("UPDATE Wager SET outcome = 7 WHERE user = ?1", [bob])?;
+        conn.execute("DELETE FROM Account WHERE user = ?1", [charlie])?;
+        let report = bets.check_integrity()?;
+        assert_eq!(report.violations, [
+            Violation::UnrecordedChange { server: 1, user: alice, balance: 1070, recorded: 70 },
+            Violation::WagerWithoutAccount { bet: 1, server: 1, user: charlie, amount: 10 },
+            Violation::WagerOnMissingOutcome { bet: 1, outcome: 7, server: 1, user: bob, amount: 20 },
+        ]);
+        assert_eq!(report.violations[0].to_string(), "account 1/0 has 1070 coins but its last recorded change left it with 70");
+        // only bob's wager can be refunded, charlie has no account to refund it to
+        let report = bets.repair_integrity()?;
+        assert_eq!(report.repaired, [Violation::WagerOnMissingOutcome { bet: 1, outcome: 7, server: 1, user: bob, amount: 20 }]);
+        assert_eq!(report.violations.len(), 2);
+        assert_eq!(bets.balance(1, bob)?, 100);
+        // once recorded, the coins created earlier show up as a gap in the ledger
+        bets.income(1, 10)?;
+        assert!(matches!(
+            bets.check_integrity()?.violations[..],
+            [Violation::BrokenLedger { previous: 70, diff: 10, balance: 1080, .. }, Violation::WagerWithoutAccount { .. }]
+        ));
+        // the outcomes and wagers of a bet row deleted by hand are left behind
+        let dave = 3;
+        bets.create_account(2, dave, 100)?;
+        bets.create_bet(2, 2, dave, "Snow tomorrow ?", &["Yes", "No"])?;
+        bets.bet_on(2, 0, dave, 40)?;
+        conn.execute("DELETE FROM Bet WHERE uuid = 2", [])?;
+        let report = bets.repair_integrity()?;
+        assert_eq!(report.repaired, [Violation::WagerOnMissingBet { bet: 2, server: 2, user: dave, amount: 40 }]);
+        assert_eq!(bets.balance(2, dave)?, 100);
+        // and so are the side tables of the other kinds of bets
+        let erin = 4;
+        bets.create_account(3, erin, 100)?;
+        bets.create_ranked_bet(4, 3, erin, "Race", &["A", "B", "C"])?;
+        bets.bet_on_ranked(4, erin, RankedPick::Show(0), 20)?;
+        bets.create_market(5, 3, erin, "Sunny tomorrow ?", &["Yes", "No"], 10.)?;
+        let spent = -bets.buy_shares(5, 0, erin, 5)?.diff;
+        bets.create_bet(6, 3, erin, "Leg 1", &["Yes", "No"])?;
+        bets.create_bet(7, 3, erin, "Leg 2", &["Yes", "No"])?;
+        bets.create_parlay(1, 3, erin, &[(6, 0), (7, 0)], 10)?;
+        conn.execute("DELETE FROM Bet WHERE uuid IN (4, 5, 6)", [])?;
+        let report = bets.check_integrity()?;
+        assert_eq!(report.violations.len(), 5);
+        assert_eq!(report.violations[4].to_string(), "parlay 1 of user 4 has a leg on bet 6, which doesn't exist");
+        let report = bets.repair_integrity()?;
+        assert!(matches!(
+            report.repaired[..],
+            [
+                Violation::RankedWagerOnMissingBet { bet: 4, server: 3, user: 4, amount: 20, .. },
+                Violation::SharesOnMissingBet { bet: 5, server: 3, user: 4, cost },
+                Violation::ParlayLegOnMissingBet { parlay: 1, bet: 6, server: 3, user: 4 },
+            ] if cost == spent
+        ));
+        assert_eq!(bets.balance(3, erin)?, 90);
+        // the parlay rides on the leg that's left
+        bets.abort_bet(7)?;
+        assert_eq!(bets.balance(3, erin)?, 100);
+        // resetting a server deletes the rows of its bets, including the outcomes
+        bets.create_bet(3, 2, dave, "Hail tomorrow ?", &["Yes", "No"])?;
+        bets.bet_on(3, 1, dave, 40)?;
+        bets.reset(2, 50)?;
+        let rows = |table: &str| conn.query_row(&format!("SELECT COUNT(*) FROM {} WHERE bet = 3", table), [], |row| row.get::<usize, u64>(0));
+        assert!(matches!(bets.bet(3), Err(BetError::NotFound)));
+        assert_eq!((rows("Outcome")?, rows("Wager")?), (0, 0));
+        assert_eq!(bets.balance(2, dave)?, 50);
+        Ok(())
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn metrics() -> Result<(), BetError> {
+        let bets = test_bets("metrics")?;
+        let (alice, bob) = (0, 1);
+        bets.set_server_config(1, &ServerConfig { fee: 0.1, ..Default::default() })?;
+        bets.create_account(1, alice, 100)?;
+        bets.create_account(1, bob, 100)?;
+        bets.create_bet(1, 1, alice, "Rain tomorrow ?", &["Yes", "No"])?;
+        bets.bet_on(1, 0, alice, 30)?;
+        bets.bet_on(1, 1, bob, 10)?;
+        assert!(matches!(bets.bet_on(1, 1, bob, 500), Err(BetError::NotEnoughMoney)));
+        bets.lock_bet(1)?;
+        bets.resolve(1, 0)?;
+        bets.create_bet(2, 1, alice, "Snow tomorrow ?", &["Yes", "No"])?;
+        bets.abort_bet(2)?;
+        // clones share the metrics
+        let metrics = bets.clone().metrics();
+        for line in [
+            "betting_bets_created_total 2",
+            "betting_bets_locked_total 1",
+            "betting_bets_resolved_total 1",
+            "betting_bets_aborted_total 1",
+            "betting_wagers_placed_total 2",
+            "betting_coins_wagered_total 40",
+            "betting_coins_paid_total 36",
+            "betting_coins_raked_total 4",
+            "betting_errors_total{operation=\"bet_on\",error=\"not_enough_money\"} 1",
+            "betting_operation_duration_seconds_bucket{operation=\"bet_on\",le=\"+Inf\"} 3",
+            "betting_operation_duration_seconds_count{operation=\"resolve\"} 1",
+        ] {
+            assert!(metrics.lines().any(|metric| metric == line), "{} is missing from\n{}", line, metrics);
+        }
+        #[cfg(feature = "server")]
+        {
+            use std::io::{Read, Write};
+            let http = std::sync::Arc::new(tiny_http::Server::http("127.0.0.1:0").unwrap());
+            let serving = std::thread::spawn({
+                let (bets, http) = (bets.clone(), http.clone());
+                move || server::serve(&bets, &http)
+            });
+            let mut stream = std::net::TcpStream::connect(http.server_addr().to_ip().unwrap()).unwrap();
+            write!(stream, "GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 200") && response.contains("betting_bets_created_total 2"));
+            http.unblock();
+            serving.join().unwrap();
+        }
+        // parlays, market purchases and challenge stakes are wagers too
+        bets.create_bet(3, 1, alice, "Wind tomorrow ?", &["Yes", "No"])?;
+        bets.create_bet(4, 1, alice, "Hail tomorrow ?", &["Yes", "No"])?;
+        bets.create_parlay(1, 1, alice, &[(3, 0), (4, 0)], 10)?;
+        bets.create_market(5, 1, alice, "Fog tomorrow ?", &["Yes", "No"], 100.)?;
+        let price = -bets.buy_shares(5, 0, bob, 10)?.diff as u64;
+        bets.propose_challenge(1, 1, alice, bob, "Chess", 5, std::time::Duration::from_secs(60))?;
+        bets.accept_challenge(1, bob)?;
+        bets.create_numeric_bet(6, 1, alice, "Goals ?", &NumericOutcomes::OverUnder(2.5), false)?;
+        bets.bet_on(6, 1, bob, 5)?;
+        bets.resolve_numeric(6, 3.)?;
+        bets.create_guess_bet(7, 1, alice, "Score ?", GuessScoring::Closest)?;
+        bets.guess_on(7, bob, 1., 5)?;
+        bets.resolve_numeric(7, 2.)?;
+        bets.create_ranked_bet(8, 1, alice, "Race", &["Red", "Blue"])?;
+        bets.bet_on_ranked(8, bob, RankedPick::Show(1), 5)?;
+        bets.bet_on_ranked(8, alice, RankedPick::Show(0), 5)?;
+        // both show picks win 5 coins of the side pool, minus the fee
+        bets.resolve_ranking(8, &[1, 0])?;
+        let metrics = bets.metrics();
+        for line in [
+            "betting_wagers_placed_total 10".to_string(),
+            format!("betting_coins_wagered_total {}", 80 + price),
+            "betting_coins_raked_total 6".to_string(),
+            "betting_operation_duration_seconds_count{operation=\"resolve_numeric\"} 1".to_string(),
+            "betting_operation_duration_seconds_count{operation=\"resolve_guess\"} 1".to_string(),
+            "betting_operation_duration_seconds_count{operation=\"resolve_ranking\"} 1".to_string(),
+        ] {
+            assert!(metrics.lines().any(|metric| metric == line), "{} is missing from\n{}", line, metrics);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn wager_validation() -> Result<(), BetError> {
+        let bets = test_bets("wager_validation")?;
+        let (alice, bob) = (0, 1);
+        bets.create_account(1, alice, 100)?;
+        bets.create_account(1, bob, 100)?;
+        bets.create_bet(1, 1, alice, "Heads or tails ?", &["Heads", "Tails"])?;
+        assert!(matches!(bets.bet_on(1, 2, alice, 10), Err(BetError::NotFound)));
+        bets.bet_on(1, 0, alice, 10)?;
+        // switching sides is refused before any coin is taken
+        assert!(matches!(
+            bets.bet_on(1, 1, alice, 10),
+            Err(BetError::MultiOpt(options)) if options == ["Heads", "Tails"]
+        ));
+        bets.bet_on(1, 0, alice, 10)?;
+        let account = bets.account(1, alice)?;
+        assert_eq!((account.balance, account.in_bet), (80, 20));
+        bets.bet_on(1, 1, bob, 10)?;
+        bets.abort_bet(1)?;
+        assert_eq!(bets.balance(1, alice)?, 100);
+        Ok(())
+    }
+
+    #[test]
+    fn chat_commands() -> Result<(), BetError> {
+        use chat::{parse, respond, ChatCommand, ChatMessage};
+        let bets = test_bets("chat")?;
+        assert_eq!(parse("gg"), None);
+        assert_eq!(parse("!dance"), None);
+        assert_eq!(
+            parse("!bet 50% Alice"),
+            Some(Ok(ChatCommand::Bet { amount: Amount::FRACTION(0.5), outcome: "Alice".to_string() }))
+        );
+        assert_eq!(parse("!bet all 2"), Some(Ok(ChatCommand::Bet { amount: Amount::FRACTION(1.), outcome: "2".to_string() })));
+        assert_eq!(parse("!bet 150% Alice"), Some(Err("!bet <amount> <outcome>")));
+        assert_eq!(parse("!predict Who wins ? | Alice"), Some(Err("!predict <question> | <outcome> | <outcome>...")));
+        let say = |user: u64, name: &str, is_mod: bool, text: &str| {
+            respond(&bets, &ChatMessage { server: 1, user, id: 7, name, is_mod, text }).unwrap()
+        };
+        assert_eq!(say(1, "Bob", false, "!bet 10 1"), "Bob, there's no prediction running");
+        assert_eq!(say(1, "Bob", false, "!predict Who wins ? | Alice | Bob"), "Bob, only moderators can start predictions");
+        assert_eq!(
+            say(0, "Mod", true, "!predict Who wins ? | Alice | Bob"),
+            "Prediction: Who wins ? 1. Alice | 2. Bob - bet with !bet <amount> <outcome>"
+        );
+        assert_eq!(say(1, "Bob", false, "!bet 100 1"), "Bob bet 100 on Alice, 0 coins left");
+        assert_eq!(say(2, "Carol", false, "!bet 50% bob"), "Carol bet 50 on Bob, 50 coins left");
+        assert_eq!(say(2, "Carol", false, "!bet 10 Dave"), "Carol, the outcomes are 1. Alice | 2. Bob");
+        assert_eq!(say(3, "Dave", false, "!bet 10 aliec"), "Dave bet 10 on Alice, 90 coins left");
+        assert_eq!(say(2, "Carol", false, "!bet 10 Alice"), "Carol, you already bet on Bob");
+        assert_eq!(say(1, "Bob", false, "!bet 10 1"), "Bob, you don't have enough coins");
+        assert_eq!(say(2, "Carol", false, "!balance"), "Carol has 50 coins, 50 in bets");
+        assert_eq!(say(2, "Carol", false, "!lock"), "Carol, only moderators can settle predictions");
+        assert_eq!(say(0, "Mod", true, "!lock"), "Bets are locked: Who wins ?");
+        assert_eq!(say(2, "Carol", false, "!bet 10 2"), "Carol, bets are locked");
+        assert_eq!(say(0, "Mod", true, "!resolve"), "Mod, usage: !resolve <outcome>");
+        assert_eq!(say(0, "Mod", true, "!resolve alice"), "Alice won! 2 users got 143 coins");
+        assert_eq!(bets.balance(1, 1)?, 130);
+        // the current bet is the one created last, whatever its uuid
+        bets.create_bet(9, 2, 0, "Rain tomorrow ?", &["Yes", "No"])?;
+        bets.create_bet(3, 2, 0, "Snow tomorrow ?", &["Yes", "No"])?;
+        let message = ChatMessage { server: 2, user: 0, id: 8, name: "Mod", is_mod: true, text: "!lock" };
+        assert_eq!(respond(&bets, &message).unwrap(), "Bets are locked: Snow tomorrow ?");
+        assert!(bets.bet(9)?.is_open);
+        Ok(())
+    }
+
+    #[cfg(feature = "twitch")]
+    #[test]
+    fn twitch_chat() -> Result<(), Box<dyn std::error::Error>> {
+        use std::io::{BufRead, BufReader, Write};
+        let bets = test_bets("twitch")?;
+        // a fake IRC server, playing Twitch's side of the conversation
+        let irc = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let addr = irc.local_addr()?;
+        let bot = {
+            let bets = bets.clone();
+            std::thread::spawn(move || {
+                let mut twitch = chat::twitch::Twitch::connect(addr, "BetBot", "oauth:secret", &["#Streamer"])?;
+                twitch.run(&bets)
+            })
+        };
+        let (mut stream, _) = irc.accept()?;
+        let mut lines = BufReader::new(stream.try_clone()?).lines();
+        for expected in [
+            "CAP REQ :twitch.tv/tags twitch.tv/commands",
+            "PASS oauth:secret",
+            "NICK betbot",
+            "JOIN #streamer",
+        ] {
+            assert_eq!(lines.next().unwrap()?, expected);
+        }
+        let mut exchange = |sent: &str| -> std::io::Result<String> {
+            write!(stream, "{}\r\n", sent)?;
+            lines.next().unwrap()
+        };
+        assert_eq!(exchange("PING :tmi.twitch.tv")?, "PONG :tmi.twitch.tv");
+        assert_eq!(
+            exchange(
+                "@badges=broadcaster/1;display-name=The\\sStreamer;id=b34ccfc7-4977-403a-8a94-33c6bac34fb8;mod=0;room-id=42;tmi-sent-ts=1700000000000;user-id=42 \
+                :streamer!streamer@streamer.tmi.twitch.tv PRIVMSG #streamer :!predict Win ? | Yes | No"
+            )?,
+            "PRIVMSG #streamer :Prediction: Win ? 1. Yes | 2. No - bet with !bet <amount> <outcome>"
+        );
+        assert_eq!(
+            exchange(
+                "@badges=;display-name=Alice;id=0f2a6a8e-8c2b-4f5e-9d3a-52e1d1a5c0b7;mod=0;room-id=42;tmi-sent-ts=1700000001000;user-id=7 \
+                :alice!alice@alice.tmi.twitch.tv PRIVMSG #streamer :!bet 30 yes"
+            )?,
+            "PRIVMSG #streamer :Alice bet 30 on Yes, 70 coins left"
+        );
+        assert_eq!(
+            exchange(
+                "@badges=;display-name=Alice;id=7c1d5e3a-2f4b-4a6c-8e9d-0b1a2c3d4e5f;mod=0;room-id=42;tmi-sent-ts=1700000002000;user-id=7 \
+                :alice!alice@alice.tmi.twitch.tv PRIVMSG #streamer :!lock"
+            )?,
+            "PRIVMSG #streamer :Alice, only moderators can settle predictions"
+        );
+        // the bet uuid comes from the id of the message
+        assert_eq!(bets.bet(0x0a94_33c6_bac3_4fb8)?.author, 42);
+        // a message without an id is skipped, the next one is still answered,
+        // and a line break in a name can't start another IRC command
+        assert_eq!(
+            exchange(
+                "@badges=;display-name=Alice;mod=0;room-id=42;user-id=7 \
+                :alice!alice@alice.tmi.twitch.tv PRIVMSG #streamer :!balance\r\n\
+                @badges=;display-name=Eve\\r\\nJOIN\\s#evil;id=5d2c1e0f-3a4b-4c5d-8e6f-7a8b9c0d1e2f;mod=0;room-id=42;user-id=9 \
+                :eve!eve@eve.tmi.twitch.tv PRIVMSG #streamer :!balance"
+            )?,
+            "PRIVMSG #streamer :Eve  JOIN #evil has 100 coins, 0 in bets"
+        );
+        drop((stream, lines));
+        assert!(bot.join().unwrap().is_ok());
+        Ok(())
+    }
+
+    #[cfg(feature = "discord")]
+    #[test]
+    fn discord_interactions() -> Result<(), BetError> {
+        use chat::discord::{self, Action, OutcomeChoice};
+        use serde_json::{json, Value};
+        let bets = test_bets("discord")?;
+        let payload = |json: &str| serde_json::from_str::<Value>(json).unwrap();
+        // recorded interactions, trimmed to the fields that matter
+        let predict = payload(r#"{
+            "type": 2, "id": "1180000000000000001", "guild_id": "900000000000000042",
+            "member": {"permissions": "2147483647", "user": {"id": "100", "username": "streamer"}},
+            "data": {"name": "predict", "type": 1, "options": [
+                {"name": "question", "type": 3, "value": "Who wins ?"},
+                {"name": "outcomes", "type": 3, "value": "Alice | Bob"}
+            ]}
+        }"#);
+        let click = payload(r#"{
+            "type": 3, "id": "1180000000000000002", "guild_id": "900000000000000042",
+            "member": {"permissions": "1024", "user": {"id": "200", "username": "alice"}},
+            "data": {"component_type": 2, "custom_id": "outcome:1180000000000000001:1"}
+        }"#);
+        let modal = payload(r#"{
+            "type": 5, "id": "1180000000000000003", "guild_id": "900000000000000042",
+            "member": {"permissions": "1024", "user": {"id": "200", "username": "alice"}},
+            "data": {"custom_id": "wager:1180000000000000001:1", "components": [
+                {"type": 1, "components": [{"type": 4, "custom_id": "amount", "value": "40%"}]}
+            ]}
+        }"#);
+        let command = |user: u64, permissions: &str, name: &str, options: Value| {
+            json!({
+                "type": 2, "id": "1180000000000000009", "guild_id": "900000000000000042",
+                "member": { "permissions": permissions, "user": { "id": user.to_string() } },
+                "data": { "name": name, "options": options },
+            })
+        };
+        assert_eq!(discord::respond(&bets, &json!({ "type": 1 })), json!({ "type": 1 }));
+        let interaction = discord::parse_interaction(&modal).unwrap();
+        assert_eq!((interaction.server, interaction.user, interaction.is_mod), (900000000000000042, 200, false));
+        assert_eq!(
+            interaction.action,
+            Action::Bet { bet: Some(1180000000000000001), outcome: OutcomeChoice::Index(1), amount: Amount::FRACTION(0.4) }
+        );
+        let response = discord::respond(&bets, &predict);
+        assert_eq!(response["type"], 4);
+        let embed = &response["data"]["embeds"][0];
+        assert_eq!((embed["title"].as_str(), embed["fields"][1]["name"].as_str()), (Some("Who wins ?"), Some("2. Bob")));
+        assert_eq!(response["data"]["components"][0]["components"][1]["custom_id"], "outcome:1180000000000000001:1");
+        let response = discord::respond(&bets, &click);
+        assert_eq!((response["type"].as_u64(), response["data"]["custom_id"].as_str()), (Some(9), Some("wager:1180000000000000001:1")));
+        let response = discord::respond(&bets, &modal);
+        assert_eq!(response["data"]["content"], "<@200> bet 40 on Bob, 60 coins left");
+        assert_eq!(response["data"]["embeds"][0]["fields"][1]["value"], "`██████████` 100% · 40 coins · 1 bettors");
+        let response = discord::respond(&bets, &command(300, "0", "bet", json!([
+            { "name": "amount", "value": "120" }, { "name": "outcome", "value": "alice" }
+        ])));
+        assert_eq!((response["data"]["content"].as_str(), response["data"]["flags"].as_u64()), (Some("You don't have enough coins"), Some(64)));
+        discord::respond(&bets, &command(300, "0", "bet", json!([{ "name": "amount", "value": "all" }, { "name": "outcome", "value": "1" }])));
+        let response = discord::respond(&bets, &command(300, "0", "balance", json!([])));
+        assert_eq!(response["data"]["content"], "You have 0 coins, 100 in bets");
+        let response = discord::respond(&bets, &command(300, "0", "lock", json!([])));
+        assert_eq!(response["data"]["content"], "Only the author of the bet and server managers can do that");
+        let response = discord::respond(&bets, &command(100, "32", "lock", json!([])));
+        assert_eq!(response["data"]["components"][0]["components"][0]["disabled"], true);
+        let response = discord::respond(&bets, &command(100, "32", "resolve", json!([{ "name": "outcome", "value": "Bob" }])));
+        assert_eq!(response["data"]["embeds"][0]["title"], "Who wins ?: Bob won");
+        assert_eq!(response["data"]["embeds"][0]["description"], "<@200> +126 → 186 coins");
+        // Discord shows at most 25 outcomes, a bet made elsewhere can have more
+        let outcomes = (1..=30).map(|i| format!("Horse {}", i)).collect::<Vec<_>>();
+        bets.create_bet(2, 900000000000000042, 100, "Race", &outcomes)?;
+        let message = discord::bet_message(&bets.bet(2)?);
+        assert_eq!(message["embeds"][0]["fields"].as_array().map(Vec::len), Some(25));
+        assert_eq!(message["components"].as_array().map(Vec::len), Some(5));
+        let predict = command(100, "32", "predict", json!([
+            { "name": "question", "value": "Race" }, { "name": "outcomes", "value": outcomes[..26].join(" | ") }
+        ]));
+        let response = discord::respond(&bets, &predict);
+        assert_eq!((response["data"]["content"].as_str(), response["data"]["flags"].as_u64()), (Some("a bet can have at most 25 outcomes"), Some(64)));
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn async_bets() -> Result<(), BetError> {
+        test_bets("async")?;
+        let bets = AsyncBets::new(&test_db_path("async")).await?;
+        let (alice, bob) = (0, 1);
+        bets.create_account(1, alice, 100).await?;
+        bets.create_account(1, bob, 100).await?;
+        bets.create_bet(1, 1, alice, "Coin flip", &["Heads", "Tails"]).await?;
+        let (update, bet) = bets.bet_on(1, 0, alice, 0.5).await?;
+        assert_eq!(update.balance, 50);
+        assert_eq!(bet.outcomes[0].wagers, vec![(alice, 50)]);
+        bets.bet_on(1, 1, bob, 50).await?;
+        assert!(matches!(bets.bet_on(1, 1, bob, 1000).await, Err(BetError::NotEnoughMoney)));
+        bets.lock_bet(1).await?;
+        assert!(matches!(bets.bet_on(1, 1, bob, 10).await, Err(BetError::BetLocked)));
+        assert_eq!(bets.latest_bet(1).await?.bet, 1);
+        assert_eq!(bets.servers().await?, vec![1]);
+        let resolve = || bets.with_key("resolve-1".to_string(), |bets| bets.resolve(1, 0));
+        assert_eq!(resolve().await?, resolve().await?);
+        assert_eq!(bets.balance(1, alice).await?, 140);
+        let update = bets.adjust_balance(1, bob, 10, "refund").await?;
+        assert_eq!(bets.events(bets.last_event().await? - 1, 1).await?[0].1, BetEvent::BalanceChanged(update));
         Ok(())
     }
 }
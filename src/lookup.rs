@@ -0,0 +1,82 @@
+use crate::{amount::Amount, AccountUpdate, Bet, BetError, Bets};
+
+// How similar a query must be to an outcome to match it, between 0 and 1
+const FUZZY_THRESHOLD: f64 = 0.6;
+// How much better than the runner-up the best fuzzy match must be to win
+const FUZZY_MARGIN: f64 = 0.1;
+
+// The outcomes passing a test, an error if there's more than one
+fn unique(outcomes: &[&str], test: impl Fn(&str) -> bool) -> Result<Option<usize>, BetError> {
+    let matches = (0..outcomes.len()).filter(|i| test(outcomes[*i])).collect::<Vec<_>>();
+    match matches[..] {
+        [] => Ok(None),
+        [i] => Ok(Some(i)),
+        _ => Err(BetError::AmbiguousOutcome(matches.iter().map(|i| outcomes[*i].to_string()).collect())),
+    }
+}
+
+// Matches a query against the descriptions of outcomes, by decreasing strictness
+pub(crate) fn match_outcome(outcomes: &[&str], query: &str) -> Result<usize, BetError> {
+    let query = query.trim();
+    if let Some(i) = outcomes.iter().position(|outcome| *outcome == query) {
+        return Ok(i);
+    }
+    let query = query.to_lowercase();
+    if let Some(i) = unique(outcomes, |outcome| outcome.to_lowercase() == query)? {
+        return Ok(i);
+    }
+    if let Some(i) = unique(outcomes, |outcome| !query.is_empty() && outcome.to_lowercase().starts_with(&query))? {
+        return Ok(i);
+    }
+    let mut scores = outcomes
+        .iter()
+        .map(|outcome| strsim::normalized_damerau_levenshtein(&outcome.to_lowercase(), &query))
+        .enumerate()
+        .filter(|(_, score)| *score >= FUZZY_THRESHOLD)
+        .collect::<Vec<_>>();
+    scores.sort_by(|(_, score1), (_, score2)| score2.partial_cmp(score1).unwrap());
+    match scores[..] {
+        [] => Err(BetError::NotFound),
+        [(i, _)] => Ok(i),
+        [(i, best), (_, second), ..] if best - second >= FUZZY_MARGIN => Ok(i),
+        [(_, best), ..] => Err(BetError::AmbiguousOutcome(
+            scores
+                .iter()
+                .filter(|(_, score)| best - score < FUZZY_MARGIN)
+                .map(|(i, _)| outcomes[*i].to_string())
+                .collect(),
+        )),
+    }
+}
+
+impl Bet {
+    /// The index of the outcome matching a query, see `Bets::find_outcome`.
+    pub fn find_outcome(&self, query: &str) -> Result<usize, BetError> {
+        let outcomes = self.outcomes.iter().map(|outcome| outcome.desc.as_str()).collect::<Vec<_>>();
+        match_outcome(&outcomes, query)
+    }
+}
+
+impl Bets {
+    /// The index of the outcome of a bet matching a query, trying in order an exact match,
+    /// a case-insensitive match, a case-insensitive prefix and a close spelling.
+    ///
+    /// Fails with `BetError::AmbiguousOutcome` listing the candidates if several outcomes match
+    /// equally well, and `BetError::NotFound` if none does.
+    pub fn find_outcome(&self, bet: u64, query: &str) -> Result<usize, BetError> {
+        self.bet(bet)?.find_outcome(query)
+    }
+
+    /// `bet_on` with the outcome given by name, see `find_outcome`.
+    pub fn bet_on_named<A>(
+        &self,
+        bet: u64,
+        outcome: &str,
+        user: u64,
+        amount: A,
+    ) -> Result<(AccountUpdate, Bet), BetError>
+    where A: Into<Amount> {
+        let outcome = self.find_outcome(bet, outcome)?;
+        self.bet_on(bet, outcome, user, amount)
+    }
+}
@@ -0,0 +1,260 @@
+use rusqlite::{Connection, OptionalExtension, Transaction, params};
+use crate::{metrics::Counter, AccountUpdate, BetError, BetEvent, Bets, bet_connection::BetConnection, bet_transaction::BetTransaction};
+
+// The LMSR cost function b * ln(sum(exp(q_i / b))), computed without overflowing
+fn cost(shares: &[f64], liquidity: f64) -> f64 {
+    let max = shares.iter().fold(f64::MIN, |max, q| max.max(q / liquidity));
+    liquidity * (max + shares.iter().map(|q| (q / liquidity - max).exp()).sum::<f64>().ln())
+}
+
+// The price of each outcome, which sum up to 1
+fn prices(shares: &[f64], liquidity: f64) -> Vec<f64> {
+    let max = shares.iter().fold(f64::MIN, |max, q| max.max(q / liquidity));
+    let weights: Vec<f64> = shares.iter().map(|q| (q / liquidity - max).exp()).collect();
+    let norm = weights.iter().sum::<f64>();
+    weights.into_iter().map(|weight| weight / norm).collect()
+}
+
+impl Bets {
+    /// Creates a prediction market where users buy and sell shares of the outcomes
+    /// at a price that follows the demand, according to a logarithmic market scoring rule.
+    ///
+    /// Each winning share pays 1 coin at resolution. The server subsidizes the market,
+    /// which can lose at most `liquidity * ln(number of outcomes)` coins;
+    /// a higher liquidity means that prices move slower.
+    pub fn create_market<S1, S2>(
+        &self,
+        bet_uuid: u64,
+        server: u64,
+        author: u64,
+        desc: S1,
+        outcomes: &[S2],
+        liquidity: f64,
+    ) -> Result<(), BetError>
+    where S1: ToString, S2: ToString {
+        if !(liquidity.is_finite() && liquidity > 0.) {
+            return Err(BetError::InvalidLiquidity);
+        }
+        let mut conn = Connection::open(&self.db_path)?;
+        let tx = conn.transaction()?;
+        Bets::insert_bet(&tx, bet_uuid, server, author, desc, outcomes)?;
+        tx.execute(
+            "INSERT
+            INTO Market (bet, liquidity)
+            VALUES (?1, ?2)",
+            params![bet_uuid, liquidity],
+        )?;
+        tx.commit()?;
+        self.count(Counter::BetsCreated, 1);
+        self.publish();
+        Ok(())
+    }
+
+    // (liquidity, outstanding shares of each outcome)
+    fn market_state(conn: &Connection, bet: u64) -> Result<(f64, Vec<f64>), BetError> {
+        let liquidity = conn
+            .prepare(
+                "SELECT liquidity
+                FROM Market
+                WHERE bet = ?1",
+            )
+            .unwrap()
+            .query_row([bet], |row| row.get::<usize, f64>(0))?;
+        let mut shares = vec![0.; conn.outcomes_of_bet(bet)?.len()];
+        let mut stmt = conn
+            .prepare(
+                "SELECT outcome, SUM(shares)
+                FROM MarketShares
+                WHERE bet = ?1 AND payout IS NULL
+                GROUP BY outcome",
+            )
+            .unwrap();
+        let mut rows = stmt.query([bet])?;
+        while let Some(row) = rows.next()? {
+            if let Some(outcome_shares) = shares.get_mut(row.get::<usize, usize>(0)?) {
+                *outcome_shares = row.get::<usize, f64>(1)?;
+            }
+        }
+        Ok((liquidity, shares))
+    }
+
+    /// The current price of a share of each outcome, between 0 and 1 coin
+    pub fn market_prices(&self, bet: u64) -> Result<Vec<f64>, BetError> {
+        let conn = Connection::open(&self.db_path)?;
+        let (liquidity, shares) = Bets::market_state(&conn, bet)?;
+        Ok(prices(&shares, liquidity))
+    }
+
+    /// The shares a user holds in a market, for each outcome
+    pub fn market_shares(&self, bet: u64, user: u64) -> Result<Vec<u64>, BetError> {
+        let conn = Connection::open(&self.db_path)?;
+        let mut shares = vec![0; conn.outcomes_of_bet(bet)?.len()];
+        let mut stmt = conn
+            .prepare(
+                "SELECT outcome, shares
+                FROM MarketShares
+                WHERE bet = ?1 AND user = ?2 AND payout IS NULL",
+            )
+            .unwrap();
+        let mut rows = stmt.query([bet, user])?;
+        while let Some(row) = rows.next()? {
+            if let Some(outcome_shares) = shares.get_mut(row.get::<usize, usize>(0)?) {
+                *outcome_shares = row.get::<usize, u64>(1)?;
+            }
+        }
+        Ok(shares)
+    }
+
+    /// Buys shares of an outcome, the cost is rounded up to the next coin.
+    /// The cost is a stake, it must follow the stake limits of the server and the market,
+    /// the coins spent on the market's shares count towards `max_pool_share`.
+    pub fn buy_shares(
+        &self,
+        bet: u64,
+        outcome: usize,
+        user: u64,
+        shares: u64,
+    ) -> Result<AccountUpdate, BetError> {
+        let shares = i64::try_from(shares).map_err(|_| BetError::NotEnoughMoney)?;
+        self.trade_shares(bet, outcome, user, shares)
+    }
+
+    /// Sells shares of an outcome back to the market, the refund is rounded down to the previous coin
+    pub fn sell_shares(
+        &self,
+        bet: u64,
+        outcome: usize,
+        user: u64,
+        shares: u64,
+    ) -> Result<AccountUpdate, BetError> {
+        let shares = i64::try_from(shares).map_err(|_| BetError::NotEnoughShares)?;
+        self.trade_shares(bet, outcome, user, -shares)
+    }
+
+    fn trade_shares(
+        &self,
+        bet: u64,
+        outcome: usize,
+        user: u64,
+        shares: i64,
+    ) -> Result<AccountUpdate, BetError> {
+        let mut conn = Connection::open(&self.db_path)?;
+        // the price and balance are read in the transaction, so that concurrent trades can't use a stale state
+        let tx = conn.transaction()?;
+        let bet_info = tx.bet_info(bet)?;
+        if !bet_info.is_open {
+            return Err(BetError::BetLocked);
+        }
+        tx.assert_bet_not_deleted(bet)?;
+        let (liquidity, mut outstanding) = Bets::market_state(&tx, bet)?;
+        let held = tx
+            .prepare(
+                "SELECT shares
+                FROM MarketShares
+                WHERE bet = ?1 AND outcome = ?2 AND user = ?3",
+            )
+            .unwrap()
+            .query_row(params![bet, outcome, user], |row| row.get::<usize, i64>(0))
+            .optional()?
+            .unwrap_or(0);
+        match held.checked_add(shares) {
+            None => return Err(BetError::NotEnoughMoney),
+            Some(total) if total < 0 => return Err(BetError::NotEnoughShares),
+            Some(_) => {}
+        }
+        let before = cost(&outstanding, liquidity);
+        *outstanding.get_mut(outcome).ok_or(BetError::NotFound)? += shares as f64;
+        let after = cost(&outstanding, liquidity);
+        // rounded up so that the market keeps the rounding, buying or selling
+        let price = (after - before).ceil() as i64;
+        if price > tx.balance(bet_info.server, user)? as i64 {
+            return Err(BetError::NotEnoughMoney);
+        }
+        if price > 0 {
+            let (min, max) = tx.stake_range(bet_info.server, user, Some(bet))?.ok_or(BetError::NoStakeAllowed)?;
+            if (price as u64) < min || max.is_some_and(|max| price as u64 > max) {
+                return Err(BetError::StakeOutOfRange { min, max });
+            }
+        }
+        let acc_update = tx.change_balance(bet_info.server, user, -price)?;
+        tx.execute(
+            "INSERT or ignore
+            INTO MarketShares (bet, outcome, server, user, shares, cost)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![bet, outcome, bet_info.server, user, 0, 0],
+        )?;
+        tx.execute(
+            "UPDATE MarketShares
+            SET shares = shares + ?1, cost = cost + ?2
+            WHERE bet = ?3 AND outcome = ?4 AND user = ?5",
+            params![shares, price, bet, outcome, user],
+        )?;
+        tx.emit(BetEvent::SharesTraded { server: bet_info.server, bet, user, outcome, shares })?;
+        tx.commit()?;
+        // a purchase is a wager on the outcome, a sale takes coins out of the market
+        if price > 0 {
+            self.count(Counter::WagersPlaced, 1);
+            self.count(Counter::CoinsWagered, price as u64);
+        }
+        self.publish();
+        Ok(acc_update)
+    }
+
+    // Pays 1 coin per winning share, the payouts are kept so that they can be rolled back
+    pub(crate) fn pay_market_shares(
+        tx: &Transaction,
+        bet: u64,
+        winning_outcome: usize,
+    ) -> Result<Vec<AccountUpdate>, BetError> {
+        let holders = tx
+            .prepare(
+                "SELECT server, user, shares
+                FROM MarketShares
+                WHERE bet = ?1 AND outcome = ?2 AND payout IS NULL AND shares > 0",
+            )
+            .unwrap()
+            .query_map(params![bet, winning_outcome], |row| Ok((
+                row.get::<usize, u64>(0)?,
+                row.get::<usize, u64>(1)?,
+                row.get::<usize, i64>(2)?,
+            )))?
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut account_updates = Vec::new();
+        for (server, user, shares) in holders {
+            account_updates.push(tx.change_balance(server, user, shares)?);
+        }
+        tx.execute(
+            "UPDATE MarketShares
+            SET payout = CASE WHEN outcome = ?1 THEN shares ELSE 0 END
+            WHERE bet = ?2 AND payout IS NULL",
+            params![winning_outcome, bet],
+        )?;
+        Ok(account_updates)
+    }
+
+    // Refunds what each user spent on an aborted market, those who sold at a profit keep it
+    pub(crate) fn refund_market_shares(tx: &Transaction, bet: u64) -> Result<Vec<AccountUpdate>, BetError> {
+        let costs = tx
+            .prepare(
+                "SELECT server, user, SUM(cost)
+                FROM MarketShares
+                WHERE bet = ?1 AND payout IS NULL
+                GROUP BY server, user",
+            )
+            .unwrap()
+            .query_map([bet], |row| Ok((
+                row.get::<usize, u64>(0)?,
+                row.get::<usize, u64>(1)?,
+                row.get::<usize, i64>(2)?,
+            )))?
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut account_updates = Vec::new();
+        for (server, user, cost) in costs {
+            if cost > 0 {
+                account_updates.push(tx.change_balance(server, user, cost)?);
+            }
+        }
+        tx.execute("DELETE FROM MarketShares WHERE bet = ?1", [bet])?;
+        Ok(account_updates)
+    }
+}
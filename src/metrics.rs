@@ -0,0 +1,133 @@
+//! Counters and latencies of the operations of a `Bets`, shared by its clones,
+//! rendered in the Prometheus text format by `Bets::metrics` with the `metrics` feature.
+//! Without it the hooks do nothing.
+#[cfg(feature = "metrics")]
+use std::{collections::BTreeMap, fmt::Write, time::Instant};
+use crate::{AccountUpdate, BetError, Bets};
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Counter {
+    BetsCreated,
+    BetsLocked,
+    BetsResolved,
+    BetsAborted,
+    WagersPlaced,
+    CoinsWagered,
+    CoinsPaid,
+    CoinsRaked,
+}
+
+// (name, help) of each counter, in the order of `Counter`
+#[cfg(feature = "metrics")]
+const COUNTERS: [(&str, &str); 8] = [
+    ("betting_bets_created_total", "Bets created"),
+    ("betting_bets_locked_total", "Bets locked"),
+    ("betting_bets_resolved_total", "Bets resolved"),
+    ("betting_bets_aborted_total", "Bets aborted"),
+    ("betting_wagers_placed_total", "Wagers placed, with parlays, market purchases and challenge stakes"),
+    ("betting_coins_wagered_total", "Coins put in wagers, parlays, market purchases and challenges"),
+    ("betting_coins_paid_total", "Coins paid out when resolving bets"),
+    ("betting_coins_raked_total", "Coins taken as fees when resolving bets"),
+];
+
+// The upper bounds of the latency buckets, in seconds
+#[cfg(feature = "metrics")]
+const LATENCY_BUCKETS: [f64; 11] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2.5];
+
+#[cfg(feature = "metrics")]
+#[derive(Debug, Default)]
+struct Histogram {
+    // cumulative, like Prometheus buckets
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+#[cfg(feature = "metrics")]
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    counters: [u64; COUNTERS.len()],
+    // (operation, error code) -> count
+    errors: BTreeMap<(&'static str, &'static str), u64>,
+    latencies: BTreeMap<&'static str, Histogram>,
+}
+
+#[cfg(feature = "metrics")]
+impl Metrics {
+    fn render(&self) -> String {
+        let mut text = String::new();
+        for ((name, help), value) in COUNTERS.iter().zip(self.counters) {
+            writeln!(text, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, value).unwrap();
+        }
+        text += "# HELP betting_errors_total Failed operations, by operation and error\n";
+        text += "# TYPE betting_errors_total counter\n";
+        for ((operation, error), count) in &self.errors {
+            writeln!(text, "betting_errors_total{{operation=\"{}\",error=\"{}\"}} {}", operation, error, count).unwrap();
+        }
+        text += "# HELP betting_operation_duration_seconds How long the operations took, failed ones included\n";
+        text += "# TYPE betting_operation_duration_seconds histogram\n";
+        for (operation, histogram) in &self.latencies {
+            let name = "betting_operation_duration_seconds";
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                writeln!(text, "{}_bucket{{operation=\"{}\",le=\"{}\"}} {}", name, operation, bound, count).unwrap();
+            }
+            writeln!(text, "{}_bucket{{operation=\"{}\",le=\"+Inf\"}} {}", name, operation, histogram.count).unwrap();
+            writeln!(text, "{}_sum{{operation=\"{}\"}} {}", name, operation, histogram.sum).unwrap();
+            writeln!(text, "{}_count{{operation=\"{}\"}} {}", name, operation, histogram.count).unwrap();
+        }
+        text
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl Bets {
+    /// The metrics of this `Bets` and its clones since it was created, in the Prometheus text format.
+    ///
+    /// Counters of bets and coins only count what was committed, error counts are labelled
+    /// with `BetError::code`, and latencies are measured for the main operations.
+    pub fn metrics(&self) -> String {
+        self.metrics.lock().unwrap().render()
+    }
+
+    // Runs an operation, recording how long it took and how it failed
+    pub(crate) fn measure<T>(&self, operation: &'static str, f: impl FnOnce() -> Result<T, BetError>) -> Result<T, BetError> {
+        let start = Instant::now();
+        let result = f();
+        let seconds = start.elapsed().as_secs_f64();
+        let mut metrics = self.metrics.lock().unwrap();
+        if let Err(err) = &result {
+            *metrics.errors.entry((operation, err.code())).or_default() += 1;
+        }
+        let histogram = metrics.latencies.entry(operation).or_default();
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter_mut()) {
+            if seconds <= *bound {
+                *count += 1;
+            }
+        }
+        histogram.count += 1;
+        histogram.sum += seconds;
+        result
+    }
+
+    pub(crate) fn count(&self, counter: Counter, value: u64) {
+        self.metrics.lock().unwrap().counters[counter as usize] += value;
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+impl Bets {
+    pub(crate) fn measure<T>(&self, _operation: &'static str, f: impl FnOnce() -> Result<T, BetError>) -> Result<T, BetError> {
+        f()
+    }
+
+    pub(crate) fn count(&self, _counter: Counter, _value: u64) {}
+}
+
+impl Bets {
+    // Counts a committed resolution, `raked` is what the fee took from the winnings
+    pub(crate) fn count_resolution(&self, account_updates: &[AccountUpdate], raked: u64) {
+        self.count(Counter::BetsResolved, 1);
+        self.count(Counter::CoinsPaid, account_updates.iter().map(|update| update.diff.max(0) as u64).sum());
+        self.count(Counter::CoinsRaked, raked);
+    }
+}
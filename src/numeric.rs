@@ -0,0 +1,83 @@
+use crate::BetError;
+
+// (low, high) bounds of a range of values, None meaning unbounded
+pub(crate) type Range = (Option<f64>, Option<f64>);
+
+/// The outcomes of a bet on a number, each covering the values `low <= value < high`
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum NumericOutcomes {
+    /// 2 outcomes: under the line and over the line (included)
+    OverUnder(f64),
+    /// n+1 outcomes delimited by n strictly increasing bounds
+    Ranges(Vec<f64>),
+}
+
+impl NumericOutcomes {
+    pub(crate) fn ranges(&self) -> Result<Vec<Range>, BetError> {
+        let bounds = match self {
+            NumericOutcomes::OverUnder(line) => vec![*line],
+            NumericOutcomes::Ranges(bounds) => bounds.clone(),
+        };
+        if bounds.is_empty()
+            || bounds.iter().any(|bound| !bound.is_finite())
+            || bounds.windows(2).any(|pair| pair[0] >= pair[1])
+        {
+            return Err(BetError::InvalidBounds);
+        }
+        let mut ranges = vec![(None, Some(bounds[0]))];
+        for pair in bounds.windows(2) {
+            ranges.push((Some(pair[0]), Some(pair[1])));
+        }
+        ranges.push((bounds.last().copied(), None));
+        Ok(ranges)
+    }
+
+    pub(crate) fn label(&self, range: &Range) -> String {
+        match (self, range) {
+            (NumericOutcomes::OverUnder(line), (None, _)) => format!("Under {}", line),
+            (NumericOutcomes::OverUnder(line), _) => format!("Over {}", line),
+            (_, (None, Some(high))) => format!("< {}", high),
+            (_, (Some(low), None)) => format!(">= {}", low),
+            (_, (Some(low), Some(high))) => format!("{} to {}", low, high),
+            (_, (None, None)) => "Any".to_string(),
+        }
+    }
+}
+
+/// How the pot of a guess bet is split once the actual value is known
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum GuessScoring {
+    /// The closest guesses share the pot, proportionally to their stakes
+    Closest,
+    /// Everyone gets a share of the pot weighted by `stake / (1 + distance)`
+    InverseDistance,
+}
+
+impl From<u32> for GuessScoring {
+    fn from(val: u32) -> Self {
+        match val {
+            0 => GuessScoring::Closest,
+            _ => GuessScoring::InverseDistance,
+        }
+    }
+}
+
+impl From<GuessScoring> for u32 {
+    fn from(scoring: GuessScoring) -> Self {
+        match scoring {
+            GuessScoring::Closest => 0,
+            GuessScoring::InverseDistance => 1,
+        }
+    }
+}
+
+// How far a value is from a range, 0 if it's inside
+pub(crate) fn distance(range: &Range, value: f64) -> f64 {
+    match range {
+        (Some(low), _) if value < *low => low - value,
+        (_, Some(high)) if value >= *high => value - high,
+        _ => 0.,
+    }
+}
@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use itertools::Itertools;
+use rusqlite::{Connection, OptionalExtension, Transaction, params};
+use crate::{utils, amount::Amount, metrics::Counter, AccountUpdate, BetError, BetEvent, Bets, bet_connection::BetConnection, bet_transaction::BetTransaction};
+
+// (server, user, pick, amount)
+type RankedWager = (u64, u64, Vec<usize>, u64);
+
+/// A prediction on the final ranking of the outcomes of a ranked bet
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum RankedPick {
+    /// The outcome finishes 1st, this is the regular pool of the bet
+    Win(usize),
+    /// The outcome finishes in the top 3
+    Show(usize),
+    /// The exact order of the first outcomes, 2 of them for an exacta, 3 for a trifecta
+    Exact(Vec<usize>),
+}
+
+impl RankedPick {
+    // Each kind of pick has its own pool: 0 for show, n for the exact order of the first n
+    fn pool(&self) -> usize {
+        match self {
+            RankedPick::Win(_) => 1,
+            RankedPick::Show(_) => 0,
+            RankedPick::Exact(order) => order.len(),
+        }
+    }
+
+    fn outcomes(&self) -> Vec<usize> {
+        match self {
+            RankedPick::Win(outcome) | RankedPick::Show(outcome) => vec![*outcome],
+            RankedPick::Exact(order) => order.clone(),
+        }
+    }
+}
+
+fn encode(outcomes: &[usize]) -> String {
+    outcomes.iter().join(",")
+}
+
+fn decode(pick: &str) -> Vec<usize> {
+    pick.split(',').filter_map(|outcome| outcome.parse().ok()).collect()
+}
+
+fn is_winning(pool: usize, pick: &[usize], ranking: &[usize]) -> bool {
+    match pool {
+        0 => ranking.iter().take(3).contains(&pick[0]),
+        n => ranking.len() >= n && ranking[..n] == *pick,
+    }
+}
+
+// Checks that the outcomes are distinct outcomes of the bet
+fn check_outcomes(conn: &Connection, bet: u64, outcomes: &[usize]) -> Result<(), BetError> {
+    let bet_outcomes = conn.outcomes_of_bet(bet)?;
+    if outcomes.is_empty()
+        || !outcomes.iter().all_unique()
+        || !outcomes.iter().all(|outcome| bet_outcomes.contains(&(*outcome as u64)))
+    {
+        return Err(BetError::InvalidRanking);
+    }
+    Ok(())
+}
+
+impl Bets {
+    /// Creates a bet whose result is a ranking of the outcomes, like a race,
+    /// on which users can also bet on a top 3 finish or on the exact order of the first outcomes.
+    ///
+    /// Such a bet must be resolved with `resolve_ranking`.
+    pub fn create_ranked_bet<S1, S2>(
+        &self,
+        bet_uuid: u64,
+        server: u64,
+        author: u64,
+        desc: S1,
+        outcomes: &[S2],
+    ) -> Result<(), BetError>
+    where S1: ToString, S2: ToString {
+        let mut conn = Connection::open(&self.db_path)?;
+        let tx = conn.transaction()?;
+        Bets::insert_bet(&tx, bet_uuid, server, author, desc, outcomes)?;
+        tx.execute(
+            "INSERT
+            INTO RankedBet (bet)
+            VALUES (?1)",
+            [bet_uuid],
+        )?;
+        tx.commit()?;
+        self.count(Counter::BetsCreated, 1);
+        self.publish();
+        Ok(())
+    }
+
+    /// Bets on a pick of a ranked bet, each kind of pick has its own pool.
+    /// Betting again in a pool is only allowed with the same pick.
+    pub fn bet_on_ranked<A>(
+        &self,
+        bet: u64,
+        user: u64,
+        pick: RankedPick,
+        amount: A,
+    ) -> Result<AccountUpdate, BetError>
+    where A: Into<Amount> {
+        let amount: Amount = amount.into();
+        if let RankedPick::Win(outcome) = pick {
+            return Ok(self.bet_on(bet, outcome, user, amount)?.0);
+        }
+        let mut conn = Connection::open(&self.db_path)?;
+        // the bet and balance are read in the transaction, so that a concurrent lock or resolution can't be missed
+        let tx = conn.transaction()?;
+        tx.prepare(
+            "SELECT bet
+            FROM RankedBet
+            WHERE bet = ?1",
+        )
+        .unwrap()
+        .query_row([bet], |row| row.get::<usize, u64>(0))?;
+        let bet_info = tx.bet_info(bet)?;
+        if !bet_info.is_open {
+            return Err(BetError::BetLocked);
+        }
+        tx.assert_bet_not_deleted(bet)?;
+        let outcomes = pick.outcomes();
+        check_outcomes(&tx, bet, &outcomes)?;
+        if outcomes.len() == 1 && pick.pool() != 0 {
+            // an exact order of a single outcome is a win
+            return Err(BetError::InvalidRanking);
+        }
+        let pool = pick.pool();
+        let pick = encode(&outcomes);
+        if let Some(previous) = tx
+            .prepare(
+                "SELECT pick
+                FROM RankedWager
+                WHERE bet = ?1 AND user = ?2 AND pool = ?3",
+            )
+            .unwrap()
+            .query_row(params![bet, user, pool], |row| row.get::<usize, String>(0))
+            .optional()?
+        {
+            if previous != pick {
+                return Err(BetError::MultiOpt(vec![previous, pick]));
+            }
+        }
+        let amount = tx.stake(bet_info.server, user, Some(bet), amount)?;
+        let acc_update = tx.change_balance(bet_info.server, user, -(amount as i64))?;
+        tx.execute(
+            "INSERT or ignore
+            INTO RankedWager (bet, server, user, pool, pick, amount)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![bet, bet_info.server, user, pool, pick, 0],
+        )?;
+        tx.execute(
+            "UPDATE RankedWager
+            SET amount = amount + ?1
+            WHERE bet = ?2 AND user = ?3 AND pool = ?4",
+            params![amount, bet, user, pool],
+        )?;
+        tx.emit(BetEvent::RankedWagerPlaced { server: bet_info.server, bet, user, pool, amount })?;
+        tx.commit()?;
+        self.count(Counter::WagersPlaced, 1);
+        self.count(Counter::CoinsWagered, amount);
+        self.publish();
+        Ok(acc_update)
+    }
+
+    /// Resolves a ranked bet with the final ranking of (some of) its outcomes, first to last.
+    ///
+    /// The regular pool goes to the first outcome, and every other pool is split among its winning picks,
+    /// a pool without any winning pick is refunded.
+    pub fn resolve_ranking(
+        &self,
+        bet: u64,
+        ranking: &[usize],
+    ) -> Result<Vec<AccountUpdate>, BetError> {
+        self.measure("resolve_ranking", || self.resolve_ranking_keyed(bet, ranking, None))
+    }
+
+    pub(crate) fn resolve_ranking_keyed(
+        &self,
+        bet: u64,
+        ranking: &[usize],
+        key: Option<&str>,
+    ) -> Result<Vec<AccountUpdate>, BetError> {
+        let conn = Connection::open(&self.db_path)?;
+        if !conn
+            .prepare(
+                "SELECT *
+                FROM RankedBet
+                WHERE bet = ?1",
+            )
+            .unwrap()
+            .exists([bet])?
+        {
+            return Err(BetError::InvalidRanking);
+        }
+        check_outcomes(&conn, bet, ranking)?;
+        let fee = conn.server_config(conn.bet_info(bet)?.server)?.fee;
+        self.resolve_with(bet, ranking[0], key, |tx| Bets::pay_ranked_pools(tx, bet, ranking, fee))
+    }
+
+    // Pays the show and exact order pools, returns the account updates and what the fee took
+    fn pay_ranked_pools(tx: &Transaction, bet: u64, ranking: &[usize], fee: f64) -> Result<(Vec<AccountUpdate>, u64), BetError> {
+        // Map <pool, [wager]>
+        let mut pools: HashMap<usize, Vec<RankedWager>> = HashMap::new();
+        let mut stmt = tx.prepare(
+            "SELECT pool, server, user, pick, amount
+            FROM RankedWager
+            WHERE bet = ?1 AND payout IS NULL
+            ORDER BY user",
+        ).unwrap();
+        let mut rows = stmt.query([bet])?;
+        while let Some(row) = rows.next()? {
+            pools.entry(row.get::<usize, usize>(0)?).or_default().push((
+                row.get::<usize, u64>(1)?,
+                row.get::<usize, u64>(2)?,
+                decode(&row.get::<usize, String>(3)?),
+                row.get::<usize, u64>(4)?,
+            ));
+        }
+        let mut account_updates = Vec::new();
+        let mut raked = 0;
+        for (pool, wagers) in pools.into_iter().sorted_by_key(|(pool, _)| *pool) {
+            let total = wagers.iter().map(|(_, _, _, amount)| amount).sum::<u64>();
+            let weights: Vec<u64> = wagers
+                .iter()
+                .map(|(_, _, pick, amount)| if is_winning(pool, pick, ranking) { *amount } else { 0 })
+                .collect();
+            let payouts: Vec<i64> = if weights.iter().all(|weight| *weight == 0) {
+                // nobody won, everyone is refunded
+                wagers.iter().map(|(_, _, _, amount)| *amount as i64).collect()
+            } else {
+                let fee = if wagers.iter().map(|(_, _, pick, _)| pick).unique().count() > 1 { fee } else { 0. };
+                utils::lrm(total, &weights)
+                    .into_iter()
+                    .map(|gain| {
+                        let net_gain = Bets::net_gain(gain, fee);
+                        raked += gain - net_gain as u64;
+                        net_gain
+                    })
+                    .collect()
+            };
+            for ((server, user, _, _), payout) in wagers.iter().zip(payouts) {
+                if payout > 0 {
+                    account_updates.push(tx.change_balance(*server, *user, payout)?);
+                }
+                tx.execute(
+                    "UPDATE RankedWager
+                    SET payout = ?1
+                    WHERE bet = ?2 AND user = ?3 AND pool = ?4",
+                    params![payout, bet, user, pool],
+                )?;
+            }
+        }
+        Ok((account_updates, raked))
+    }
+}
@@ -0,0 +1,189 @@
+//! Renders bets and accounts as plain text, Markdown or ANSI colored text for terminals.
+//!
+//! ```
+//! use betting::render::{Format, Style};
+//! let style = Style { format: Format::Markdown, currency: "points".to_string(), ..Style::default() };
+//! ```
+use std::fmt::{self, Display};
+use itertools::Itertools;
+use crate::{AccountStatus, AccountUpdate, Bet, Outcome};
+
+const BOLD: &str = "\x1b[1m";
+const DIM: &str = "\x1b[2m";
+const GREEN: &str = "\x1b[32m";
+const RED: &str = "\x1b[31m";
+const RESET: &str = "\x1b[0m";
+
+/// The markup of the rendered strings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Markdown,
+    Ansi,
+}
+
+/// How to render bets and accounts
+#[derive(Debug, Clone)]
+pub struct Style {
+    pub format: Format,
+    /// The name of the currency, written after amounts
+    pub currency: String,
+    /// Written before amounts, like "🪙"
+    pub emoji: Option<String>,
+    /// The width of the pool bars, in characters
+    pub bar_width: usize,
+    /// How many of the largest wagers are listed under each outcome
+    pub top_bettors: usize,
+    /// How users are named, like `<@{user}>` for Discord mentions, written as is in every format
+    pub user_name: fn(u64) -> String,
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Style {
+            format: Format::Text,
+            currency: "coins".to_string(),
+            emoji: None,
+            bar_width: 10,
+            top_bettors: 3,
+            user_name: |user| format!("user {}", user),
+        }
+    }
+}
+
+fn pool(outcome: &Outcome) -> u64 {
+    outcome.wagers.iter().map(|(_, amount)| amount).sum()
+}
+
+impl Style {
+    fn escape(&self, text: &str) -> String {
+        match self.format {
+            Format::Markdown => text.chars().fold(String::new(), |mut escaped, c| {
+                if "\\*_~`|>#[]".contains(c) {
+                    escaped.push('\\');
+                }
+                escaped.push(c);
+                escaped
+            }),
+            _ => text.to_string(),
+        }
+    }
+
+    fn bold(&self, text: &str) -> String {
+        match self.format {
+            Format::Text => text.to_string(),
+            Format::Markdown => format!("**{}**", self.escape(text)),
+            Format::Ansi => format!("{}{}{}", BOLD, text, RESET),
+        }
+    }
+
+    /// An amount in the currency, like "🪙 120 coins"
+    pub fn amount(&self, amount: u64) -> String {
+        match &self.emoji {
+            Some(emoji) => format!("{} {} {}", emoji, amount, self.currency),
+            None => format!("{} {}", amount, self.currency),
+        }
+    }
+
+    // A change of balance, colored in the terminal
+    fn diff(&self, diff: i64) -> String {
+        match self.format {
+            Format::Ansi if diff > 0 => format!("{}{:+}{}", GREEN, diff, RESET),
+            Format::Ansi if diff < 0 => format!("{}{:+}{}", RED, diff, RESET),
+            _ => format!("{:+}", diff),
+        }
+    }
+
+    /// A bar filled with the share of a pool, between 0 and 1
+    pub fn bar(&self, share: f64) -> String {
+        let full = ((share * self.bar_width as f64).round() as usize).min(self.bar_width);
+        let empty = self.bar_width - full;
+        match self.format {
+            Format::Text => format!("[{}{}]", "#".repeat(full), "-".repeat(empty)),
+            Format::Markdown => format!("`{}{}`", "█".repeat(full), "░".repeat(empty)),
+            Format::Ansi => format!("{}{}{}{}{}{}", GREEN, "█".repeat(full), RESET, DIM, "░".repeat(empty), RESET),
+        }
+    }
+
+    /// A bet, with for each outcome its pool, its multiplier before fees and its largest wagers
+    pub fn bet(&self, bet: &Bet) -> String {
+        let total = bet.outcomes.iter().map(pool).sum::<u64>();
+        let mut text = format!("{} ({})", self.bold(&bet.desc), if bet.is_open { "open" } else { "locked" });
+        for (i, outcome) in bet.outcomes.iter().enumerate() {
+            let pool = pool(outcome);
+            let share = if total > 0 { pool as f64 / total as f64 } else { 0. };
+            let multiplier = if pool > 0 { format!("x{:.2}", total as f64 / pool as f64) } else { "x-".to_string() };
+            text += &format!(
+                "\n{}. {} {} {:.0}% · {} · {}",
+                i + 1, self.bold(&outcome.desc), self.bar(share), share * 100., self.amount(pool), multiplier
+            );
+            let top = outcome
+                .wagers
+                .iter()
+                .sorted_by_key(|(user, amount)| (std::cmp::Reverse(*amount), *user))
+                .take(self.top_bettors)
+                .map(|(user, amount)| format!("{}: {}", (self.user_name)(*user), self.amount(*amount)))
+                .join(", ");
+            if !top.is_empty() {
+                text += &format!("\n   {}", top);
+            }
+        }
+        text
+    }
+
+    /// An account, with the coins it has in bets
+    pub fn account(&self, account: &AccountStatus) -> String {
+        format!(
+            "{}: {}, {} in bets",
+            (self.user_name)(account.user), self.amount(account.balance), self.amount(account.in_bet)
+        )
+    }
+
+    /// A change of balance, like "user 1: +20 -> 120 coins"
+    pub fn account_update(&self, update: &AccountUpdate) -> String {
+        format!(
+            "{}: {} -> {}",
+            (self.user_name)(update.user), self.diff(update.diff), self.amount(update.balance)
+        )
+    }
+
+    /// The summary of a resolution, `bet` is the bet as it was before being resolved
+    /// and `updates` what `Bets::resolve` returned
+    pub fn payouts(&self, bet: &Bet, winning_outcome: usize, updates: &[AccountUpdate]) -> String {
+        let winner = bet.outcomes.get(winning_outcome).map_or("?", |outcome| outcome.desc.as_str());
+        let paid = updates.iter().filter(|update| update.diff > 0).sorted_by_key(|update| (std::cmp::Reverse(update.diff), update.user));
+        let total = updates.iter().map(|update| update.diff.max(0) as u64).sum::<u64>();
+        let mut text = format!(
+            "{}: {} won, {} winners share {}",
+            self.escape(&bet.desc), self.bold(winner), paid.len(), self.amount(total)
+        );
+        for update in paid {
+            text += &format!("\n{}", self.account_update(update));
+        }
+        text
+    }
+}
+
+impl Display for Bet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Style::default().bet(self))
+    }
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}, {} wagers", self.desc, Style::default().amount(pool(self)), self.wagers.len())
+    }
+}
+
+impl Display for AccountStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Style::default().account(self))
+    }
+}
+
+impl Display for AccountUpdate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Style::default().account_update(self))
+    }
+}
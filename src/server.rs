@@ -0,0 +1,249 @@
+//! A REST/JSON API over `Bets`, served by the `betting-server` binary.
+//!
+//! | Route | Body | Result |
+//! |---|---|---|
+//! | `POST /servers/{server}/accounts` | `{"user", "balance"?}` | the account update, the balance defaults to the server's starting balance |
+//! | `GET /servers/{server}/accounts` | | the accounts of the server |
+//! | `GET /servers/{server}/accounts/{user}` | | an account |
+//! | `GET /servers/{server}/leaderboard` | | the accounts, richest first (counting the coins in bets) |
+//! | `POST /servers/{server}/bets` | `{"bet", "author", "desc", "outcomes"}` | the bet |
+//! | `GET /bets/{bet}` | | the bet, with the wagers on each outcome |
+//! | `POST /bets/{bet}/wagers` | `{"user", "outcome", "amount"}` | the account update and the bet, `amount` is a number of coins or a string like `"50%"` |
+//! | `POST /bets/{bet}/lock` | | the bet |
+//! | `POST /bets/{bet}/resolve` | `{"outcome"}` | the account updates |
+//! | `POST /bets/{bet}/abort` | | the account updates |
+//! | `GET /bets/{bet}/live` | | a WebSocket of the live updates of the bet |
+//! | `GET /servers/{server}/live` | | a WebSocket of the live updates of the server |
+//! | `GET /metrics` | | the metrics in the Prometheus text format, with the `metrics` feature |
+//!
+//! A live feed first sends a snapshot: `{"type": "snapshot", "bet"}` for a bet,
+//! `{"type": "snapshot", "accounts", "bets"}` for a server. Then every event of the bet or server is sent
+//! as `{"type": "event", "id", "event"}`, along with the updated `"bet"` when its pools or state changed.
+//! Balance changes are only sent to server feeds, since they're not tied to a bet.
+//! The feed pings the client every second when nothing happened and waits for the pong before sending
+//! more, so clients must keep reading the socket (browsers answer the pings on their own).
+//! The feed of a client that doesn't answer within 5 seconds stops, and ends once the client is heard from
+//! again or its connection is dropped.
+//!
+//! Wagers, resolutions and aborts accept an `Idempotency-Key` header, see `Bets::with_key`.
+//! Errors are returned as `{"error": {"code", "message"}}` with a matching HTTP status.
+use serde_json::{json, Map, Value};
+use crate::{AccountUpdate, Amount, BetError, Bets};
+
+mod live;
+
+enum ApiError {
+    Bet(BetError),
+    BadRequest(String),
+    NoRoute,
+}
+
+impl From<BetError> for ApiError {
+    fn from(err: BetError) -> Self {
+        ApiError::Bet(err)
+    }
+}
+
+/// The HTTP status and error code of a `BetError`
+pub fn error_status(err: &BetError) -> (u16, &'static str) {
+    let status = match err {
+        BetError::NotFound => 404,
+        BetError::AlreadyExists
+        | BetError::BetLocked
+        | BetError::MultiOpt(_)
+        | BetError::KeyReused
+        | BetError::ParlayLost => 409,
+        BetError::NotEnoughMoney
+        | BetError::NotEnoughShares
+        | BetError::StakeOutOfRange { .. }
+        | BetError::NoStakeAllowed
+        | BetError::AmbiguousOutcome(_)
+        | BetError::FractionsNotAllowed
+        | BetError::Expired
+        | BetError::NotAccepted => 422,
+        BetError::NotParticipant => 403,
+        BetError::InvalidParlay
+        | BetError::InvalidOutcome
+        | BetError::InvalidBounds
+        | BetError::InvalidRanking
+        | BetError::InvalidGuess
+        | BetError::MarketTrade
+        | BetError::InvalidFraction
+        | BetError::InvalidLiquidity
+        | BetError::InvalidConfig(_)
+        | BetError::Transfer(_) => 400,
+        BetError::Backup(_) | BetError::InternalError(_) => 500,
+    };
+    (status, err.code())
+}
+
+impl ApiError {
+    // The body is `{ "error": { "code", "message", ... } }`, with the details of a `BetError` as it serializes
+    fn response(self) -> (u16, Value) {
+        match self {
+            ApiError::Bet(err) => (error_status(&err).0, json!({ "error": err })),
+            ApiError::BadRequest(message) => (400, json!({ "error": { "code": "bad_request", "message": message } })),
+            ApiError::NoRoute => (404, json!({ "error": { "code": "no_route", "message": "no such route" } })),
+        }
+    }
+}
+
+fn field<'a>(body: &'a Map<String, Value>, name: &str) -> Result<&'a Value, ApiError> {
+    body.get(name).ok_or_else(|| ApiError::BadRequest(format!("missing field `{}`", name)))
+}
+
+fn u64_field(body: &Map<String, Value>, name: &str) -> Result<u64, ApiError> {
+    field(body, name)?
+        .as_u64()
+        .ok_or_else(|| ApiError::BadRequest(format!("`{}` must be a positive integer", name)))
+}
+
+fn str_field<'a>(body: &'a Map<String, Value>, name: &str) -> Result<&'a str, ApiError> {
+    field(body, name)?
+        .as_str()
+        .ok_or_else(|| ApiError::BadRequest(format!("`{}` must be a string", name)))
+}
+
+fn amount_field(body: &Map<String, Value>) -> Result<Amount, ApiError> {
+    let invalid = || ApiError::BadRequest("`amount` must be a number of coins or a percentage".to_string());
+    let amount = match field(body, "amount")? {
+        Value::Number(coins) => Amount::FLAT(coins.as_u64().ok_or_else(invalid)?),
+        Value::String(amount) => amount.parse::<Amount>().map_err(|_| invalid())?,
+        _ => return Err(invalid()),
+    };
+    match amount {
+        Amount::FRACTION(part) if !(0. ..=1.).contains(&part) => Err(invalid()),
+        amount => Ok(amount),
+    }
+}
+
+fn id(segment: &str) -> Result<u64, ApiError> {
+    segment.parse().map_err(|_| ApiError::NoRoute)
+}
+
+fn route(bets: &Bets, method: &str, path: &[&str], key: Option<&str>, body: &Map<String, Value>) -> Result<(u16, Value), ApiError> {
+    Ok(match (method, path) {
+        ("POST", ["servers", server, "accounts"]) => {
+            let (server, user) = (id(server)?, u64_field(body, "user")?);
+            let update = match body.get("balance") {
+                Some(_) => {
+                    let balance = u64_field(body, "balance")?;
+                    bets.create_account(server, user, balance)?;
+                    AccountUpdate { server, user, diff: balance as i64, balance }
+                }
+                None => bets.open_account(server, user)?,
+            };
+            (201, json!(update))
+        }
+        ("GET", ["servers", server, "accounts"]) => {
+            let mut accounts = bets.accounts(id(server)?)?;
+            accounts.sort_by_key(|account| account.user);
+            (200, json!(accounts))
+        }
+        ("GET", ["servers", server, "accounts", user]) => (200, json!(bets.account(id(server)?, id(user)?)?)),
+        ("GET", ["servers", server, "leaderboard"]) => {
+            let mut accounts = bets.accounts(id(server)?)?;
+            accounts.sort_by_key(|account| (std::cmp::Reverse(account.balance + account.in_bet), account.user));
+            (200, json!(accounts))
+        }
+        ("POST", ["servers", server, "bets"]) => {
+            let bet = u64_field(body, "bet")?;
+            let outcomes = field(body, "outcomes")?
+                .as_array()
+                .and_then(|outcomes| outcomes.iter().map(Value::as_str).collect::<Option<Vec<_>>>())
+                .filter(|outcomes| !outcomes.is_empty())
+                .ok_or_else(|| ApiError::BadRequest("`outcomes` must be a non-empty array of strings".to_string()))?;
+            bets.create_bet(bet, id(server)?, u64_field(body, "author")?, str_field(body, "desc")?, &outcomes)?;
+            (201, json!(bets.bet(bet)?))
+        }
+        ("GET", ["bets", bet]) => (200, json!(bets.bet(id(bet)?)?)),
+        ("POST", ["bets", bet, "wagers"]) => {
+            let (bet, outcome, user) = (id(bet)?, u64_field(body, "outcome")? as usize, u64_field(body, "user")?);
+            let amount = amount_field(body)?;
+            let (update, bet) = match key {
+                Some(key) => bets.with_key(key).bet_on(bet, outcome, user, amount)?,
+                None => bets.bet_on(bet, outcome, user, amount)?,
+            };
+            (201, json!({ "account_update": update, "bet": bet }))
+        }
+        ("POST", ["bets", bet, "lock"]) => {
+            let bet = id(bet)?;
+            bets.lock_bet(bet)?;
+            (200, json!(bets.bet(bet)?))
+        }
+        ("POST", ["bets", bet, "resolve"]) => {
+            let (bet, outcome) = (id(bet)?, u64_field(body, "outcome")? as usize);
+            let updates = match key {
+                Some(key) => bets.with_key(key).resolve(bet, outcome)?,
+                None => bets.resolve(bet, outcome)?,
+            };
+            (200, json!(updates))
+        }
+        ("POST", ["bets", bet, "abort"]) => {
+            let bet = id(bet)?;
+            let updates = match key {
+                Some(key) => bets.with_key(key).abort_bet(bet)?,
+                None => bets.abort_bet(bet)?,
+            };
+            (200, json!(updates))
+        }
+        _ => return Err(ApiError::NoRoute),
+    })
+}
+
+fn path_of(url: &str) -> Vec<&str> {
+    url.split('?')
+        .next()
+        .unwrap_or_default()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect()
+}
+
+/// Handles a request, returns the HTTP status and the JSON body of the response.
+pub fn handle(bets: &Bets, method: &str, url: &str, key: Option<&str>, body: &str) -> (u16, Value) {
+    let path = path_of(url);
+    let body = match body.trim() {
+        "" => Ok(Map::new()),
+        body => match serde_json::from_str::<Value>(body) {
+            Ok(Value::Object(body)) => Ok(body),
+            _ => Err(ApiError::BadRequest("the body must be a JSON object".to_string())),
+        },
+    };
+    body.and_then(|body| route(bets, method, &path, key, &body))
+        .unwrap_or_else(ApiError::response)
+}
+
+/// Serves requests until the server is unblocked or dropped, live feeds run on their own threads.
+pub fn serve(bets: &Bets, server: &tiny_http::Server) {
+    for mut request in server.incoming_requests() {
+        #[cfg(feature = "metrics")]
+        if request.method() == &tiny_http::Method::Get && path_of(request.url()) == ["metrics"] {
+            let response = tiny_http::Response::from_string(bets.metrics())
+                .with_header(tiny_http::Header::from_bytes("Content-Type", "text/plain; version=0.0.4").unwrap());
+            let _ = request.respond(response);
+            continue;
+        }
+        if let (tiny_http::Method::Get, Some(topic)) = (request.method(), live::Topic::of_path(&path_of(request.url()))) {
+            live::accept(bets, request, topic);
+            continue;
+        }
+        let mut body = String::new();
+        let (status, response) = match request.as_reader().read_to_string(&mut body) {
+            Ok(_) => {
+                let key = request
+                    .headers()
+                    .iter()
+                    .find(|header| header.field.equiv("Idempotency-Key"))
+                    .map(|header| header.value.to_string());
+                handle(bets, request.method().as_str(), request.url(), key.as_deref(), &body)
+            }
+            Err(_) => ApiError::BadRequest("the body must be UTF-8".to_string()).response(),
+        };
+        let response = tiny_http::Response::from_string(response.to_string())
+            .with_status_code(status)
+            .with_header(tiny_http::Header::from_bytes("Content-Type", "application/json").unwrap());
+        // the client may be gone already, there's nobody left to tell
+        let _ = request.respond(response);
+    }
+}
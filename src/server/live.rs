@@ -0,0 +1,166 @@
+// Live updates over WebSocket, see the server docs for the messages
+use std::{
+    sync::{mpsc::{channel, Receiver, RecvTimeoutError, Sender}, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+use serde_json::{json, Value};
+use tungstenite::{handshake::derive_accept_key, protocol::Role, Message, WebSocket};
+use crate::{BetError, BetEvent, Bets};
+use super::ApiError;
+
+// How often the changes made by other processes are looked for, the client is pinged as often
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+// How long the client has to answer a ping before its feed is dropped
+const PONG_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Topic {
+    Bet(u64),
+    Server(u64),
+}
+
+impl Topic {
+    pub(crate) fn of_path(path: &[&str]) -> Option<Topic> {
+        match path {
+            ["bets", bet, "live"] => bet.parse().ok().map(Topic::Bet),
+            ["servers", server, "live"] => server.parse().ok().map(Topic::Server),
+            _ => None,
+        }
+    }
+
+    fn matches(&self, event: &BetEvent) -> bool {
+        match self {
+            Topic::Bet(bet) => event.bet() == Some(*bet),
+            Topic::Server(server) => event.server() == *server,
+        }
+    }
+
+    fn snapshot(&self, bets: &Bets) -> Result<Value, BetError> {
+        Ok(match self {
+            Topic::Bet(bet) => json!({ "type": "snapshot", "bet": bets.bet(*bet)? }),
+            Topic::Server(server) => {
+                let mut accounts = bets.accounts(*server)?;
+                accounts.sort_by_key(|account| account.user);
+                json!({ "type": "snapshot", "accounts": accounts, "bets": bets.bets_of_server(*server)? })
+            }
+        })
+    }
+}
+
+fn message(bets: &Bets, id: u64, event: &BetEvent) -> Value {
+    let mut message = json!({ "type": "event", "id": id, "event": event });
+    // the bet may be gone if it was cleaned up since
+    if let Some(Ok(bet)) = event.bet().map(|bet| bets.bet(bet)) {
+        message["bet"] = json!(bet);
+    }
+    message
+}
+
+/// Upgrades the request to a WebSocket and feeds it from another thread.
+pub(crate) fn accept(bets: &Bets, request: tiny_http::Request, topic: Topic) {
+    let Some(key) = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Sec-WebSocket-Key"))
+        .map(|header| derive_accept_key(header.value.as_bytes()))
+    else {
+        let (status, body) = ApiError::BadRequest("expected a WebSocket handshake".to_string()).response();
+        let _ = request.respond(tiny_http::Response::from_string(body.to_string()).with_status_code(status));
+        return;
+    };
+    let response = tiny_http::Response::empty(101)
+        .with_header(tiny_http::Header::from_bytes("Sec-WebSocket-Accept", key).unwrap());
+    let stream = request.upgrade("websocket", response);
+    let bets = bets.clone();
+    thread::spawn(move || feed(&bets, WebSocket::from_raw_socket(stream, Role::Server, None), topic));
+}
+
+// Sends the snapshot and then the events, until the client is gone.
+// The stream of an upgraded request can't be split or given a timeout, so the client is read from this
+// thread too: after waiting for the events the client is pinged, and its frames are read up to the pong,
+// which answers its pings and closes. The events come through a watchdog, which drops the subscription
+// when the pong is late, since a read on a silent client only returns once the OS gives up on it.
+fn feed<S: std::io::Read + std::io::Write>(bets: &Bets, mut socket: WebSocket<S>, topic: Topic) {
+    // events committed between the two are sent even though they're in the snapshot, which is harmless
+    let Ok(after) = bets.last_event() else {
+        return;
+    };
+    let snapshot = topic.snapshot(bets).unwrap_or_else(|err| ApiError::Bet(err).response().1);
+    if socket.send(Message::Text(snapshot.to_string())).is_err() {
+        return;
+    }
+    let Ok(subscription) = bets.subscribe(after) else {
+        return;
+    };
+    let pinged = Arc::new(Mutex::new(None));
+    let (sender, events) = channel();
+    thread::spawn({
+        let (bets, pinged) = (bets.clone(), pinged.clone());
+        move || watch(&bets, subscription, sender, &pinged)
+    });
+    loop {
+        let connected = match events.recv_timeout(POLL_INTERVAL) {
+            Ok((id, event)) if topic.matches(&event) => {
+                socket.send(Message::Text(message(bets, id, &event).to_string())).is_ok()
+            }
+            Ok(_) => true,
+            Err(RecvTimeoutError::Timeout) => {
+                *pinged.lock().unwrap() = Some(Instant::now());
+                let answered = socket.send(Message::Ping(Vec::new())).is_ok() && read_until_pong(&mut socket);
+                *pinged.lock().unwrap() = None;
+                answered
+            }
+            // the watchdog gave up on the client
+            Err(RecvTimeoutError::Disconnected) => false,
+        };
+        if !connected {
+            return;
+        }
+    }
+}
+
+// Forwards the events of the subscription to the feed and looks for the changes made by other processes,
+// until the feed is gone or its client took more than PONG_TIMEOUT to answer a ping.
+// Dropping the sender ends the feed as soon as its read returns.
+fn watch(
+    bets: &Bets,
+    subscription: Receiver<(u64, BetEvent)>,
+    sender: Sender<(u64, BetEvent)>,
+    pinged: &Arc<Mutex<Option<Instant>>>,
+) {
+    loop {
+        match subscription.recv_timeout(POLL_INTERVAL) {
+            Ok(event) => {
+                if sender.send(event).is_err() {
+                    return;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                let _ = bets.poll_events();
+            }
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        let late = pinged.lock().unwrap().is_some_and(|at| at.elapsed() > PONG_TIMEOUT);
+        // the feed holds the other reference until it returns
+        if late || Arc::strong_count(pinged) == 1 {
+            return;
+        }
+    }
+}
+
+// Reads the frames of the client up to the pong, the pings are answered by tungstenite as they're read.
+// A close is answered too, false if the client is gone.
+fn read_until_pong<S: std::io::Read + std::io::Write>(socket: &mut WebSocket<S>) -> bool {
+    loop {
+        match socket.read() {
+            Ok(Message::Pong(_)) => return true,
+            Ok(Message::Close(_)) => {
+                let _ = socket.flush();
+                return false;
+            }
+            Ok(_) => {}
+            Err(_) => return false,
+        }
+    }
+}
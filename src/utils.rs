@@ -1,31 +1,37 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
 // Distribute integer quantities of a total according to un-normalized parts,
 // minimizing the error incurred by rounding
 // https://en.wikipedia.org/wiki/Largest_remainder_method
-pub fn lrm(total: u64, parts: &Vec<u64>) -> Vec<u64> {
-    let norm = parts.iter().fold(0, |i, a| i + *a);
+pub fn lrm(total: u64, parts: &[u64]) -> Vec<u64> {
+    // the products and the sum of the parts don't fit in a u64 but they do in a u128
+    let norm = parts.iter().map(|part| *part as u128).sum::<u128>();
     if norm == 0 {
         return vec![0; parts.len()];
     }
-    let parts: Vec<_> = parts
-        .into_iter()
-        .map(|part| *part as f32 / norm as f32)
+    // compute the ideal gains as a quotient and a remainder over norm
+    let fgains: Vec<(u128, u128)> = parts
+        .iter()
+        .map(|part| {
+            let share = total as u128 * *part as u128;
+            (share / norm, share % norm)
+        })
         .collect();
-    // compute the ideal gains (real number)
-    let fgains: Vec<f32> = parts.iter().map(|part| total as f32 * part).collect();
     // attribute the rounded down gains to everyone
-    let mut gains: Vec<u64> = fgains.iter().map(|fgain| fgain.floor() as u64).collect();
+    let mut gains: Vec<u64> = fgains.iter().map(|(gain, _)| *gain as u64).collect();
     // compute the remaining quantity to distribute (guaranteed to be less than gains.len())
-    let total = total - gains.iter().fold(0, |init, gain| init + gain);
+    let total = total - gains.iter().sum::<u64>();
     // give +1 to the largest remainders to distribute the remaining quantity
     let mut fgains_idx = fgains.iter().enumerate().collect::<Vec<_>>();
-    fgains_idx.sort_unstable_by(|(_i1, fgain1), (_i2, fgain2)| {
-        (**fgain1 - fgain1.floor())
-            .partial_cmp(&(**fgain2 - fgain2.floor()))
-            .unwrap()
-    });
-    fgains_idx.reverse();
+    // (the sort is stable so the earliest parts win the ties)
+    fgains_idx.sort_by(|(_i1, (_, rem1)), (_i2, (_, rem2))| rem2.cmp(rem1));
     for (i, _) in fgains_idx.into_iter().take(total as usize) {
         gains[i] += 1;
     }
     gains
 }
+
+// The current unix timestamp in seconds
+pub(crate) fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}